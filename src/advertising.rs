@@ -0,0 +1,134 @@
+//! Broadcasting advertisements from the local adapter. Advertisements are
+//! started with `EasyBluezHandle::advertise`.
+
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration as OldDuration;
+
+use uuid::Uuid;
+
+use Duration;
+use errors::*;
+
+#[derive(Clone, Debug)]
+pub struct Advertisement {
+    pub(crate) connectable: bool,
+    pub(crate) local_name: Option<String>,
+    pub(crate) service_uuids: Vec<Uuid>,
+    pub(crate) manufacturer_data: Vec<(u16, Vec<u8>)>,
+    pub(crate) service_data: Vec<(Uuid, Vec<u8>)>,
+    pub(crate) appearance: Option<u16>,
+    pub(crate) include_tx_power: bool,
+    pub(crate) interval: Option<(Duration, Duration)>,
+}
+
+impl Default for Advertisement {
+    fn default() -> Self {
+        Advertisement {
+            connectable: true,
+            local_name: None,
+            service_uuids: vec![],
+            manufacturer_data: vec![],
+            service_data: vec![],
+            appearance: None,
+            include_tx_power: false,
+            interval: None,
+        }
+    }
+}
+
+impl Advertisement {
+    /// Create a new, empty, connectable advertisement
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether remote devices may connect in response to this advertisement
+    pub fn connectable(mut self, connectable: bool) -> Self {
+        self.connectable = connectable;
+        self
+    }
+
+    pub fn local_name(mut self, name: &str) -> Self {
+        self.local_name = Some(name.to_string());
+        self
+    }
+
+    pub fn service_uuid(mut self, uuid: Uuid) -> Self {
+        self.service_uuids.push(uuid);
+        self
+    }
+
+    /// Add manufacturer specific data, keyed by Company Identifier
+    pub fn manufacturer_data(mut self, company_id: u16, data: &[u8]) -> Self {
+        self.manufacturer_data.push((company_id, data.to_vec()));
+        self
+    }
+
+    pub fn service_data(mut self, uuid: Uuid, data: &[u8]) -> Self {
+        self.service_data.push((uuid, data.to_vec()));
+        self
+    }
+
+    /// GAP appearance value
+    pub fn appearance(mut self, appearance: u16) -> Self {
+        self.appearance = Some(appearance);
+        self
+    }
+
+    /// Include the adapter's TX power level
+    pub fn include_tx_power(mut self, include: bool) -> Self {
+        self.include_tx_power = include;
+        self
+    }
+
+    /// Advertising interval range. Only honored by BlueZ versions that
+    /// support the (experimental) interval properties
+    pub fn interval(mut self, min: Duration, max: Duration) -> Self {
+        self.interval = Some((min, max));
+        self
+    }
+}
+
+pub(crate) enum AdvCommand {
+    Start,
+    Stop,
+    Update(Advertisement),
+}
+
+/// A registered advertisement. Dropping this handle stops advertising.
+pub struct AdvertisementHandle {
+    pub(crate) cmd_sender: Sender<(AdvCommand, Sender<Result<()>>)>,
+    /// How long to wait for the advertiser to carry out a command
+    pub(crate) timeout: OldDuration,
+    pub(crate) _advertiser: thread::JoinHandle<()>,
+}
+
+impl AdvertisementHandle {
+    /// Resume advertising after a call to `stop`
+    pub fn start(&self) -> Result<()> {
+        self.command(AdvCommand::Start)
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.command(AdvCommand::Stop)
+    }
+
+    /// Replace the advertised data. If currently advertising, the new data
+    /// is broadcast immediately
+    pub fn update(&self, adv: Advertisement) -> Result<()> {
+        self.command(AdvCommand::Update(adv))
+    }
+
+    fn command(&self, cmd: AdvCommand) -> Result<()> {
+        let (tx, rx) = channel();
+
+        self.cmd_sender.send((cmd, tx)).chain_err(|| "")?;
+
+        match rx.recv_timeout(self.timeout) {
+            Ok(done) => done,
+            Err(RecvTimeoutError::Timeout) => bail!("timed out waiting for the advertiser"),
+            Err(RecvTimeoutError::Disconnected) => bail!("advertiser exited unexpectedly"),
+        }
+    }
+}
@@ -1,18 +1,47 @@
 use basic_scheduler::{BasicEvent, Duration, Scheduler};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use BtMacAddress;
 use std::collections::{HashMap, HashSet};
 use errors::*;
+use std::fs::File;
+use std::path::Path;
 use std::str::FromStr;
 use uuid::Uuid;
 use std::thread;
+use std::time::{Duration as OldDuration, Instant};
+use std::sync::{Arc, RwLock};
 
-use bt_manager::SomethingItem;
+use bt_manager::{NotifyRequest, ReadRequest, SomethingItem, WriteRequest};
 use bt_manager::discovery::{discovery_task, DiscoveryData};
-use bt_manager::connection::{connect_task, ConnectionDb};
+use bt_manager::connection::{connect_task, ConnectionDb, SharedStats};
 use bt_manager::endpoints::{endpoints_task, EndpointsDb};
 use bt_manager::data_poll::{data_poll_task, DataDb};
 use bt_manager::data_write::{data_write_task, DataWDb};
+use bt_manager::gatt_server::GattServerData;
+use bt_manager::signals::{self, BusEvent, BusSubscription, Dispatcher, ObjectCache, Resolved, SharedCache, DEVICE_IFACE};
+use bt_manager::bus::{system_call, system_call_with_timeout};
+use bt_manager::data_write::write_with_options;
+use bt_manager::scanner::{self, Scanner};
+use bt_manager::demand::Demand;
+use bt_manager::socket::{connect_l2cap, connect_rfcomm, spawn_stream};
+use bt_manager::advertising::AdvertiserData;
+use gatt_server::{GattApplication, GattServerHandle};
+use advertising::{Advertisement, AdvertisementHandle};
+use beacon::BeaconEvent;
+#[cfg(feature = "dfu")]
+use dfu::{DfuOptions, DfuPackage, DfuProgress, DfuSession};
+use connection::{ConnectMode, ConnectionEvent, DeviceStatus, Priority, ReconnectPolicy};
+use services::*;
+use uart::{UartStream, NUS_RX, NUS_SERVICE, NUS_TX};
+use writes::{WriteKind, WriteOptions, DEFAULT_MTU};
+use devices::{ScannedDevice, ServiceInfo};
+use metrics::{Metrics, MetricsExporter, MetricsSnapshot};
+use recording::{read_recording, Recorder, Replayer};
+use capture::{Capture, CaptureFile, CaptureFormat};
+use presence::{DevicePresence, PresenceEvent, PresenceTracker, SharedPresence, Smoothing};
+
+/// Data to send, and data received, over a connection-oriented channel
+pub type ByteStream = (Sender<Box<[u8]>>, Receiver<Box<[u8]>>);
 
 pub struct EasyBluez {
     scan_interval: Duration,
@@ -21,16 +50,45 @@ pub struct EasyBluez {
     endpoint_interval: Duration,
     poll_interval: Duration,
     write_interval: Duration,
+    read_timeout: Duration,
+    continuous_scan: bool,
+    missing_threshold: Duration,
+    reconnect_policy: ReconnectPolicy,
+    connect_mode: ConnectMode,
+    max_connections: Option<usize>,
+    rotation_interval: Duration,
+    metrics: bool,
+    recording: Option<File>,
+    capture: Option<CaptureFile>,
+    presence_timeout: Duration,
+    rssi_smoothing: Smoothing,
 }
 
 pub struct EasyBluezHandle {
     mac_sender: Sender<BtMacAddress>,
+    classic_sender: Sender<BtMacAddress>,
     poll_sender: Sender<(SomethingItem, Sender<Box<[u8]>>)>,
-    write_sender: Sender<(SomethingItem, Receiver<Box<[u8]>>)>,
-    _scheduler: thread::JoinHandle<()>,
-    _data_scheduler: thread::JoinHandle<()>,
-
-    _rx: Receiver<(String, bool)>,
+    write_sender: Sender<WriteRequest>,
+    read_sender: Sender<ReadRequest>,
+    beacon_sender: Sender<Sender<BeaconEvent>>,
+    notify_sender: Sender<NotifyRequest>,
+    policy_sender: Sender<(BtMacAddress, ReconnectPolicy)>,
+    conn_event_sender: Sender<Sender<ConnectionEvent>>,
+    priority_sender: Sender<(BtMacAddress, Priority)>,
+    bus_sub_sender: Sender<BusSubscription>,
+    presence_sender: Sender<Sender<PresenceEvent>>,
+    cache: SharedCache,
+    stats: SharedStats,
+    presence: SharedPresence,
+    reconnect_policy: ReconnectPolicy,
+    /// Reconnect policies set for single devices
+    policies: Arc<RwLock<HashMap<BtMacAddress, ReconnectPolicy>>>,
+    demand: Demand,
+    read_timeout: Duration,
+    metrics: Metrics,
+    /// Playing back a recording rather than talking to BlueZ
+    replaying: bool,
+    _threads: Vec<thread::JoinHandle<()>>,
 }
 
 impl EasyBluezHandle {
@@ -39,6 +97,18 @@ impl EasyBluezHandle {
         mac_s: &str,
         svc_s: &str,
         chrc_s: &str,
+    ) -> Result<Sender<Box<[u8]>>> {
+        self.writeable_with(mac_s, svc_s, chrc_s, WriteOptions::default())
+    }
+
+    /// Like `writeable`, choosing how payloads are written, such as split
+    /// into MTU sized chunks
+    pub fn writeable_with(
+        &self,
+        mac_s: &str,
+        svc_s: &str,
+        chrc_s: &str,
+        options: WriteOptions,
     ) -> Result<Sender<Box<[u8]>>> {
         let (tx, rx) = channel();
 
@@ -54,7 +124,7 @@ impl EasyBluezHandle {
             chrc: chrc,
         };
 
-        self.write_sender.send((si, rx)).chain_err(|| "")?;
+        self.write_sender.send((si, rx, options)).chain_err(|| "")?;
 
         Ok(tx)
     }
@@ -83,6 +153,562 @@ impl EasyBluezHandle {
 
         Ok(rx)
     }
+
+    /// Subscribe to notifications from a characteristic. Notifications are
+    /// re-enabled automatically whenever the device reconnects
+    pub fn notify(
+        &self,
+        mac_s: &str,
+        svc_s: &str,
+        chrc_s: &str,
+    ) -> Result<Receiver<Box<[u8]>>> {
+        self.subscribe(mac_s, svc_s, chrc_s, false)
+    }
+
+    /// Like `notify`, but notifications are read from a socket acquired with
+    /// BlueZ's `AcquireNotify` rather than D-Bus signals, for high rate
+    /// streams. Falls back to `notify` when the characteristic doesn't
+    /// support it
+    pub fn notify_stream(
+        &self,
+        mac_s: &str,
+        svc_s: &str,
+        chrc_s: &str,
+    ) -> Result<Receiver<Box<[u8]>>> {
+        self.subscribe(mac_s, svc_s, chrc_s, true)
+    }
+
+    /// Receive iBeacon and Eddystone frames seen while scanning. Any nearby
+    /// beacon is reported, whether or not it is on the whitelist
+    pub fn beacons(&self) -> Result<Receiver<BeaconEvent>> {
+        let (tx, rx) = channel();
+
+        self.beacon_sender.send(tx).chain_err(|| "")?;
+
+        Ok(rx)
+    }
+
+    /// Whether BlueZ can be reached. While it can't, managed devices are
+    /// reported `Disconnected`, and calls waiting on devices fail
+    pub fn bluez_status(&self) -> Result<()> {
+        match self.cache.read().unwrap().unavailable() {
+            Some(reason) => bail!("BlueZ is unavailable, {}", reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Receive connection state changes of all managed devices
+    pub fn connection_events(&self) -> Result<Receiver<ConnectionEvent>> {
+        let (tx, rx) = channel();
+
+        self.conn_event_sender.send(tx).chain_err(|| "")?;
+
+        Ok(rx)
+    }
+
+    /// Receive `Appeared` and `Lost` events for any device seen while
+    /// scanning, whether or not it is on the whitelist
+    pub fn presence_events(&self) -> Result<Receiver<PresenceEvent>> {
+        let (tx, rx) = channel();
+
+        self.presence_sender.send(tx).chain_err(|| "")?;
+
+        Ok(rx)
+    }
+
+    /// Every device seen while scanning, with when it was last seen and its
+    /// signal strength. Devices are only seen while something is scanning,
+    /// such as a whitelisted device, or a beacon or presence listener
+    pub fn devices(&self) -> Vec<DevicePresence> {
+        self.presence.read().unwrap().devices()
+    }
+
+    /// Override the default reconnect policy for one device
+    pub fn set_reconnect_policy(&self, mac_s: &str, policy: ReconnectPolicy) -> Result<()> {
+        let mac = BtMacAddress::from_str(mac_s)?;
+
+        self.policies.write().unwrap().insert(mac.clone(), policy.clone());
+        self.policy_sender.send((mac, policy)).chain_err(|| "")?;
+
+        Ok(())
+    }
+
+    /// Set how a device competes for connection slots, when the number of
+    /// connections is limited
+    pub fn set_priority(&self, mac_s: &str, priority: Priority) -> Result<()> {
+        let mac = BtMacAddress::from_str(mac_s)?;
+
+        self.priority_sender.send((mac, priority)).chain_err(|| "")?;
+
+        Ok(())
+    }
+
+    /// Current connection state, link quality and uptime of a managed device
+    pub fn device_status(&self, mac_s: &str) -> Result<DeviceStatus> {
+        self.live()?;
+        let mac = BtMacAddress::from_str(mac_s)?;
+
+        let stats = match self.stats.read().unwrap().get(&mac) {
+            Some(stats) => stats.clone(),
+            None => bail!("not a managed device"),
+        };
+
+        let cache = self.cache.read().unwrap();
+        let dev_path = cache.device_path(&mac);
+        let dev_path = dev_path.as_deref().unwrap_or_default();
+
+        let connected_for = stats.connected_since.map(|since| since.elapsed());
+        let uptime = stats.uptime + connected_for.unwrap_or_default();
+        let to_duration = |d| Duration::from_std(d).chain_err(|| "bad duration");
+
+        Ok(DeviceStatus {
+            connected: cache.is_connected(dev_path),
+            mtu: cache.mtu(dev_path),
+            rssi: cache.rssi(dev_path),
+            connected_for: connected_for.map(to_duration).transpose()?,
+            uptime: to_duration(uptime)?,
+            connections: stats.connections,
+        })
+    }
+
+    /// Read the Device Information service of a device. Blocks until the
+    /// device has been connected and its services resolved, or until the
+    /// read timeout expires
+    pub fn device_info(&self, mac_s: &str) -> Result<DeviceInfo> {
+        let mac = BtMacAddress::from_str(mac_s)?;
+        let svc = uuid_from_u16(DEVICE_INFORMATION);
+
+        // Issue all reads up front, so they are resolved together
+        let pending = [
+            MANUFACTURER_NAME,
+            MODEL_NUMBER,
+            SERIAL_NUMBER,
+            FIRMWARE_REVISION,
+            HARDWARE_REVISION,
+            SOFTWARE_REVISION,
+            SYSTEM_ID,
+            PNP_ID,
+        ].iter()
+            .map(|chrc| self.request_read(&mac, svc, uuid_from_u16(*chrc)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut vals = vec![];
+        for rx in pending {
+            vals.push(self.wait_read(rx)?);
+        }
+
+        let string = |v: &Option<Box<[u8]>>| v.as_ref().and_then(|raw| parse_string(raw));
+
+        Ok(DeviceInfo {
+            manufacturer: string(&vals[0]),
+            model: string(&vals[1]),
+            serial: string(&vals[2]),
+            firmware_rev: string(&vals[3]),
+            hardware_rev: string(&vals[4]),
+            software_rev: string(&vals[5]),
+            system_id: vals[6].as_ref().and_then(|raw| parse_system_id(raw)),
+            pnp_id: vals[7].as_ref().and_then(|raw| parse_pnp_id(raw)),
+        })
+    }
+
+    /// Read the current battery level of a device, in percent. Blocks until
+    /// the device has been connected and its services resolved, or until the
+    /// read timeout expires
+    pub fn battery_level(&self, mac_s: &str) -> Result<u8> {
+        let mac = BtMacAddress::from_str(mac_s)?;
+        let rx = self.request_read(&mac, uuid_from_u16(BATTERY), uuid_from_u16(BATTERY_LEVEL))?;
+
+        match self.wait_read(rx)? {
+            Some(raw) => parse_battery_level(&raw).ok_or_else(|| "invalid battery level".into()),
+            None => bail!("device has no battery level"),
+        }
+    }
+
+    /// Open a Bluetooth Classic RFCOMM channel to a device, such as a Serial
+    /// Port Profile device. The device is whitelisted like any other, with
+    /// scanning widened to find Classic devices, and the channel is reopened
+    /// following the device's reconnect policy until both ends of the
+    /// returned stream are dropped
+    pub fn rfcomm(&self, mac_s: &str, channel: u8) -> Result<ByteStream> {
+        self.live()?;
+        let mac = BtMacAddress::from_str(mac_s)?;
+
+        self.classic_sender.send(mac.clone()).chain_err(|| "")?;
+        self.mac_sender.send(mac.clone()).chain_err(|| "")?;
+        let policy = self.policy(&mac);
+
+        Ok(spawn_stream(
+            format!("RFCOMM {} channel {}", mac_s, channel),
+            move || connect_rfcomm(&mac, channel),
+            || {},
+            policy,
+        ))
+    }
+
+    /// Open an LE L2CAP connection-oriented channel to a PSM on a device.
+    /// The channel is opened once the connection manager has connected the
+    /// device, keeps an on-demand device connected while open, and is
+    /// reopened following the device's reconnect policy until both ends of
+    /// the returned stream are dropped
+    pub fn l2cap(&self, mac_s: &str, psm: u16) -> Result<ByteStream> {
+        self.live()?;
+        let mac = BtMacAddress::from_str(mac_s)?;
+
+        self.mac_sender.send(mac.clone()).chain_err(|| "")?;
+        let policy = self.policy(&mac);
+
+        let cache = self.cache.clone();
+        let keep_cache = self.cache.clone();
+        let demand = self.demand.clone();
+        let demand_mac = mac.clone();
+
+        Ok(spawn_stream(
+            format!("L2CAP {} PSM {}", mac_s, psm),
+            move || {
+                let cache = cache.read().unwrap();
+                let dev_path = match cache.device_path(&mac) {
+                    Some(p) => p,
+                    None => bail!("device not found yet"),
+                };
+                if !cache.is_connected(&dev_path) {
+                    bail!("device not connected yet");
+                }
+
+                connect_l2cap(&mac, psm, cache.has_random_address(&dev_path))
+            },
+            move || {
+                if let Some(dev_path) = keep_cache.read().unwrap().device_path(&demand_mac) {
+                    demand.want(&dev_path);
+                }
+            },
+            policy,
+        ))
+    }
+
+    /// Open a device's Nordic UART Service as a byte stream. Data notified
+    /// on TX is read from the stream, and writes go to RX a full packet at
+    /// a time, with anything shorter sent on `flush`
+    pub fn uart(&self, mac_s: &str) -> Result<UartStream> {
+        let rx = self.notify(mac_s, NUS_SERVICE, NUS_TX)?;
+        let tx = self.writeable_with(mac_s, NUS_SERVICE, NUS_RX, WriteOptions::new().chunked())?;
+
+        Ok(UartStream {
+            tx,
+            rx,
+            pending: vec![],
+            unsent: vec![],
+            read_timeout: None,
+            mac: BtMacAddress::from_str(mac_s)?,
+            cache: self.cache.clone(),
+        })
+    }
+
+    /// Update the firmware of a Nordic device over Secure DFU, blocking until
+    /// the update finishes. Progress is reported on `progress` as it runs
+    #[cfg(feature = "dfu")]
+    pub fn dfu(
+        &self,
+        mac_s: &str,
+        package: &DfuPackage,
+        options: &DfuOptions,
+        progress: &Sender<DfuProgress>,
+    ) -> Result<()> {
+        DfuSession::new(self, options, progress).run(mac_s, package)
+    }
+
+    /// Scan for nearby devices for `duration`, returning every device heard
+    /// in that time. Devices are not added to the whitelist
+    pub fn scan(&self, duration: Duration) -> Result<Vec<ScannedDevice>> {
+        self.live()?;
+        let rx = signals::subscribe(&self.bus_sub_sender)?;
+
+        let scanner = Scanner::start(rx, scanner::LE);
+        let deadline = Instant::now() + duration.to_std().chain_err(|| "bad scan duration")?;
+        let mut seen = vec![];
+
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match scanner.updates.recv_timeout(left) {
+                Ok(ev) => {
+                    let is_device = match ev {
+                        BusEvent::Removed { .. } => false,
+                        _ => ev.involves(DEVICE_IFACE),
+                    };
+                    if is_device && !seen.iter().any(|p: &String| p == ev.path()) {
+                        seen.push(ev.path().to_string());
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        drop(scanner);
+
+        let cache = self.cache.read().unwrap();
+        Ok(seen.iter().filter_map(|path| cache.scanned_device(path)).collect())
+    }
+
+    /// Every device BlueZ currently knows about, without scanning. This
+    /// includes devices remembered from earlier sessions, which may be long
+    /// gone
+    pub fn known_devices(&self) -> Vec<ScannedDevice> {
+        self.cache.read().unwrap().devices()
+    }
+
+    /// Connect to a device, blocking until it is connected or the read
+    /// timeout expires. The device stays managed afterwards
+    pub fn connect(&self, mac_s: &str) -> Result<()> {
+        self.wait_for(mac_s, |cache, dev_path| cache.is_connected(dev_path))?;
+        Ok(())
+    }
+
+    /// Disconnect a device. A managed device is reconnected by the
+    /// connection manager as usual
+    pub fn disconnect(&self, mac_s: &str) -> Result<()> {
+        let dev_path = self.known_device(mac_s)?;
+
+        system_call(&dev_path, DEVICE_IFACE, "Disconnect", &[])?;
+        Ok(())
+    }
+
+    /// Pair with a device, blocking until pairing finishes. Pairing that
+    /// needs user input relies on an agent registered with BlueZ
+    pub fn pair(&self, mac_s: &str) -> Result<()> {
+        let dev_path = self.known_device(mac_s)?;
+        let timeout = self.read_timeout.num_milliseconds() as i32;
+
+        system_call_with_timeout(&dev_path, DEVICE_IFACE, "Pair", &[], timeout)?;
+        Ok(())
+    }
+
+    /// The services and characteristics of a device. Blocks until the device
+    /// has been connected and its services resolved, or until the read
+    /// timeout expires
+    pub fn services(&self, mac_s: &str) -> Result<Vec<ServiceInfo>> {
+        let dev_path = self.wait_for(mac_s, |cache, dev_path| cache.services_resolved(dev_path))?;
+        Ok(self.cache.read().unwrap().services(&dev_path))
+    }
+
+    /// Read a characteristic once. Blocks like `device_info`
+    pub fn read(&self, mac_s: &str, svc_s: &str, chrc_s: &str) -> Result<Box<[u8]>> {
+        let mac = BtMacAddress::from_str(mac_s)?;
+        let svc = Uuid::from_str(svc_s).chain_err(|| "not a UUID!")?;
+        let chrc = Uuid::from_str(chrc_s).chain_err(|| "not a UUID!")?;
+
+        let rx = self.request_read(&mac, svc, chrc)?;
+        match self.wait_read(rx)? {
+            Some(data) => Ok(data),
+            None => bail!("failed to read characteristic"),
+        }
+    }
+
+    /// Write a characteristic once, with a write request, blocking until the
+    /// device has acknowledged it or the read timeout expires. Payloads
+    /// larger than the MTU are rejected by the device
+    pub fn write(&self, mac_s: &str, svc_s: &str, chrc_s: &str, data: &[u8]) -> Result<()> {
+        let svc = Uuid::from_str(svc_s).chain_err(|| "not a UUID!")?;
+        let chrc = Uuid::from_str(chrc_s).chain_err(|| "not a UUID!")?;
+
+        let dev_path = self.wait_for(mac_s, |cache, dev_path| cache.services_resolved(dev_path))?;
+
+        let (path, mtu) = {
+            let cache = self.cache.read().unwrap();
+            match cache.find_characteristic(&dev_path, &svc, &chrc) {
+                Resolved::Found(path) => (path, cache.mtu(&dev_path).unwrap_or(DEFAULT_MTU)),
+                _ => bail!("device has no such characteristic"),
+            }
+        };
+
+        write_with_options(&path, &WriteOptions::new().kind(WriteKind::Request), data, mtu)
+    }
+
+    /// Register a local GATT application with BlueZ, so remote devices can
+    /// connect to this adapter as a peripheral. Blocks until BlueZ has
+    /// accepted or rejected the application, or the read timeout expires
+    pub fn serve(&self, app: GattApplication) -> Result<GattServerHandle> {
+        self.live()?;
+        let (tx_writes, rx_writes) = channel();
+        let (tx_notifiers, rx_notifiers) = channel();
+        let (tx_ready, rx_ready) = channel();
+
+        let endpoints = app.services
+            .iter()
+            .flat_map(|s| s.characteristics.iter().map(move |c| (s.uuid, c.uuid)))
+            .collect();
+
+        let data = GattServerData::new(app, rx_writes, rx_notifiers);
+        let server = thread::spawn(move || data.run(tx_ready));
+
+        let timeout = self.read_timeout.to_std().chain_err(|| "bad read timeout")?;
+        match rx_ready.recv_timeout(timeout) {
+            Ok(registered) => registered?,
+            Err(RecvTimeoutError::Timeout) => bail!("timed out waiting for BlueZ to register the application"),
+            Err(RecvTimeoutError::Disconnected) => bail!("GATT server exited unexpectedly"),
+        }
+
+        Ok(GattServerHandle {
+            endpoints,
+            write_sender: tx_writes,
+            notify_sender: tx_notifiers,
+            _server: server,
+        })
+    }
+
+    /// Start broadcasting an advertisement from the local adapter. Blocks
+    /// until BlueZ has accepted or rejected the advertisement, or the read
+    /// timeout expires
+    pub fn advertise(&self, adv: Advertisement) -> Result<AdvertisementHandle> {
+        self.live()?;
+        let (tx_cmds, rx_cmds) = channel();
+        let timeout = self.read_timeout.to_std().chain_err(|| "bad read timeout")?;
+
+        let data = AdvertiserData::new(adv, timeout, rx_cmds);
+        let hdl = AdvertisementHandle {
+            cmd_sender: tx_cmds,
+            timeout,
+            _advertiser: thread::spawn(move || data.run()),
+        };
+
+        hdl.start()?;
+
+        Ok(hdl)
+    }
+
+    fn subscribe(
+        &self,
+        mac_s: &str,
+        svc_s: &str,
+        chrc_s: &str,
+        streaming: bool,
+    ) -> Result<Receiver<Box<[u8]>>> {
+        let (tx, rx) = channel();
+
+        let mac = BtMacAddress::from_str(mac_s)?;
+        let svc = Uuid::from_str(svc_s).chain_err(|| "not a UUID!")?;
+        let chrc = Uuid::from_str(chrc_s).chain_err(|| "not a UUID!")?;
+
+        self.mac_sender.send(mac.clone()).chain_err(|| "")?;
+
+        let si = SomethingItem { mac, svc, chrc };
+
+        self.notify_sender.send((si, tx, streaming)).chain_err(|| "")?;
+
+        Ok(rx)
+    }
+
+    fn request_read(
+        &self,
+        mac: &BtMacAddress,
+        svc: Uuid,
+        chrc: Uuid,
+    ) -> Result<Receiver<Option<Box<[u8]>>>> {
+        let (tx, rx) = channel();
+
+        self.mac_sender.send(mac.clone()).chain_err(|| "")?;
+
+        let si = SomethingItem {
+            mac: mac.clone(),
+            svc,
+            chrc,
+        };
+
+        self.read_sender.send((si, tx)).chain_err(|| "")?;
+
+        Ok(rx)
+    }
+
+    /// Reconnect policy of a device, as set by `set_reconnect_policy`
+    fn policy(&self, mac: &BtMacAddress) -> ReconnectPolicy {
+        match self.policies.read().unwrap().get(mac) {
+            Some(policy) => policy.clone(),
+            None => self.reconnect_policy.clone(),
+        }
+    }
+
+    /// Object path of a device BlueZ already knows, such as one found by a scan
+    fn known_device(&self, mac_s: &str) -> Result<String> {
+        self.live()?;
+        let mac = BtMacAddress::from_str(mac_s)?;
+
+        match self.cache.read().unwrap().device_path(&mac) {
+            Some(path) => Ok(path),
+            None => bail!("unknown device, scan for it first"),
+        }
+    }
+
+    /// Manage a device, and wait until `done` holds for it, returning its
+    /// object path
+    fn wait_for<F>(&self, mac_s: &str, done: F) -> Result<String>
+    where
+        F: Fn(&ObjectCache, &str) -> bool,
+    {
+        self.live()?;
+        let mac = BtMacAddress::from_str(mac_s)?;
+        self.mac_sender.send(mac.clone()).chain_err(|| "")?;
+
+        let deadline = Instant::now() + self.read_timeout.to_std().chain_err(|| "bad read timeout")?;
+
+        while Instant::now() < deadline {
+            {
+                let cache = self.cache.read().unwrap();
+                if let Some(reason) = cache.unavailable() {
+                    bail!("BlueZ is unavailable, {}", reason);
+                }
+                if let Some(dev_path) = cache.device_path(&mac) {
+                    self.demand.want(&dev_path);
+                    if done(&cache, &dev_path) {
+                        return Ok(dev_path);
+                    }
+                }
+            }
+            thread::sleep(OldDuration::from_millis(100));
+        }
+
+        bail!("timed out waiting for device")
+    }
+
+    /// Current counters, with endpoints named `<mac>/<service>/<chrc>`.
+    /// Fails unless metrics were enabled with `EasyBluez::metrics`
+    pub fn metrics(&self) -> Result<MetricsSnapshot> {
+        match self.metrics.snapshot() {
+            Some(s) => Ok(name_endpoints(s, &self.cache)),
+            None => bail!("metrics are not enabled"),
+        }
+    }
+
+    /// Hand the metrics to an exporter every `interval`, for as long as the
+    /// process runs
+    pub fn export_metrics<E>(&self, mut exporter: E, interval: Duration) -> Result<()>
+    where
+        E: MetricsExporter + 'static,
+    {
+        self.metrics()?;
+        let interval = interval.to_std().chain_err(|| "bad interval")?;
+        let metrics = self.metrics.clone();
+        let cache = self.cache.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+
+            let snapshot = name_endpoints(metrics.snapshot().unwrap_or_default(), &cache);
+            if let Err(e) = exporter.export(&snapshot) {
+                error!("Failed to export metrics, {:?}", e);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Fail calls that need BlueZ, when replaying a recording
+    fn live(&self) -> Result<()> {
+        if self.replaying {
+            bail!("not available in replay");
+        }
+        Ok(())
+    }
+
+    fn wait_read(&self, rx: Receiver<Option<Box<[u8]>>>) -> Result<Option<Box<[u8]>>> {
+        let timeout = self.read_timeout.to_std().chain_err(|| "bad read timeout")?;
+        rx.recv_timeout(timeout).chain_err(|| "timed out waiting for read")
+    }
 }
 
 impl EasyBluez {
@@ -94,6 +720,18 @@ impl EasyBluez {
             endpoint_interval: Duration::seconds(3),
             poll_interval: Duration::milliseconds(1000),
             write_interval: Duration::milliseconds(100),
+            read_timeout: Duration::seconds(30),
+            continuous_scan: false,
+            missing_threshold: Duration::seconds(30),
+            reconnect_policy: ReconnectPolicy::default(),
+            connect_mode: ConnectMode::default(),
+            max_connections: None,
+            rotation_interval: Duration::seconds(30),
+            metrics: false,
+            recording: None,
+            capture: None,
+            presence_timeout: Duration::seconds(30),
+            rssi_smoothing: Smoothing::default(),
         }
     }
 
@@ -113,12 +751,65 @@ impl EasyBluez {
         self
     }
 
+    /// Keep discovery running continuously, rather than scanning for
+    /// `scan_duration` every `scan_interval`. Devices are picked up as BlueZ
+    /// reports them, and `scan_interval` only sets how often they are
+    /// processed. Discovery stops when the handle is dropped, or while
+    /// nothing is waiting on a device or beacon.
+    pub fn continuous_scan(mut self, continuous: bool) -> Self {
+        self.continuous_scan = continuous;
+        self
+    }
+
     /// How often to attempt to connect to discovered devices
     pub fn connect_interval(mut self, interval: Duration) -> Self {
         self.connect_interval = interval;
         self
     }
 
+    /// How devices are reconnected, unless overridden with
+    /// `EasyBluezHandle::set_reconnect_policy`
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// How long a device may go unconnected before it is reported missing
+    pub fn missing_threshold(mut self, threshold: Duration) -> Self {
+        self.missing_threshold = threshold;
+        self
+    }
+
+    /// Whether devices are kept connected, or only connected while needed.
+    /// An `OnDemand` idle timeout should be longer than the endpoint and
+    /// poll intervals
+    pub fn connect_mode(mut self, mode: ConnectMode) -> Self {
+        self.connect_mode = mode;
+        self
+    }
+
+    /// Limit how many devices are connected at once. Devices that are not
+    /// `Priority::Pinned` take turns, holding a slot for `rotation` while
+    /// others are waiting
+    pub fn max_connections(mut self, max: usize, rotation: Duration) -> Self {
+        self.max_connections = Some(max);
+        self.rotation_interval = rotation;
+        self
+    }
+
+    /// How long a device may go unseen while scanning before it is
+    /// reported `Lost`
+    pub fn presence_timeout(mut self, timeout: Duration) -> Self {
+        self.presence_timeout = timeout;
+        self
+    }
+
+    /// How the RSSI reported by `EasyBluezHandle::devices` is smoothed
+    pub fn rssi_smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.rssi_smoothing = smoothing;
+        self
+    }
+
     /// How often to find services/characteristics for connected devices
     pub fn endpoint_interval(mut self, interval: Duration) -> Self {
         self.endpoint_interval = interval;
@@ -131,6 +822,33 @@ impl EasyBluez {
         self
     }
 
+    /// How long one-shot reads, such as `device_info`, wait for a device
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Count connections, reads, writes, notifications, errors and task
+    /// tick times, for `EasyBluezHandle::metrics`
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled;
+        self
+    }
+
+    /// Record polled values, writes, notifications and connection events
+    /// to a file, appending if it exists. See the `recording` module
+    pub fn record<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
+        self.recording = Some(Recorder::open(path.as_ref())?);
+        Ok(self)
+    }
+
+    /// Capture every read, write and notification as ATT packets, to a new
+    /// file that Wireshark can open. See the `capture` module
+    pub fn capture<P: AsRef<Path>>(mut self, path: P, format: CaptureFormat) -> Result<Self> {
+        self.capture = Some(CaptureFile::create(path.as_ref(), format)?);
+        Ok(self)
+    }
+
     ///////////////////////////////////////////////////////
     // Run time
     ///////////////////////////////////////////////////////
@@ -138,12 +856,137 @@ impl EasyBluez {
         self.spawn_events()
     }
 
+    /// Play back a recording instead of talking to BlueZ, `speed` times
+    /// faster than it was recorded. Polls, notifications and connection
+    /// events subscribed to before a record is due receive it, one-shot
+    /// reads are answered with the latest value replayed, and writes are
+    /// accepted and dropped. Channels close once the recording ends.
+    ///
+    /// Nothing else is replayed. Calls that need BlueZ, such as `connect`,
+    /// `services`, `write`, `scan`, `device_status`, `rfcomm` and `serve`,
+    /// fail with "not available in replay", `known_devices` and `devices`
+    /// are empty, and beacon and presence listeners receive nothing
+    pub fn replay<P: AsRef<Path>>(&mut self, path: P, speed: f64) -> Result<EasyBluezHandle> {
+        if speed <= 0.0 {
+            bail!("replay speed must be positive");
+        }
+        let records = read_recording(path)?;
+
+        let (tx_macs, rx_macs) = channel();
+        let (tx_poll, rx_poll) = channel();
+        let (tx_write, rx_write) = channel();
+        let (tx_read, rx_read) = channel();
+        let (tx_notify, rx_notify) = channel();
+        let (tx_conn_evs, rx_conn_evs) = channel();
+        // Nothing is replayed to these, but sending to them still succeeds
+        let (tx_classic, rx_classic) = channel();
+        let (tx_beacons, rx_beacons) = channel();
+        let (tx_policies, rx_policies) = channel();
+        let (tx_priorities, rx_priorities) = channel();
+        let (tx_bus_subs, rx_bus_subs) = channel();
+        let (tx_presence, rx_presence) = channel();
+
+        let replayer = Replayer {
+            records,
+            speed,
+            rx_macs,
+            rx_polls: rx_poll,
+            rx_notifies: rx_notify,
+            rx_reads: rx_read,
+            rx_writes: rx_write,
+            rx_event_subs: rx_conn_evs,
+            polls: vec![],
+            notifies: vec![],
+            writes: vec![],
+            event_subs: vec![],
+            latest: vec![],
+        };
+
+        Ok(EasyBluezHandle {
+            _threads: vec![thread::spawn(move || {
+                let _unused = (
+                    rx_classic,
+                    rx_beacons,
+                    rx_policies,
+                    rx_priorities,
+                    rx_bus_subs,
+                    rx_presence,
+                );
+                replayer.run();
+            })],
+            mac_sender: tx_macs,
+            classic_sender: tx_classic,
+            poll_sender: tx_poll,
+            write_sender: tx_write,
+            read_sender: tx_read,
+            beacon_sender: tx_beacons,
+            notify_sender: tx_notify,
+            policy_sender: tx_policies,
+            conn_event_sender: tx_conn_evs,
+            priority_sender: tx_priorities,
+            bus_sub_sender: tx_bus_subs,
+            presence_sender: tx_presence,
+            cache: SharedCache::default(),
+            stats: SharedStats::default(),
+            presence: Arc::new(RwLock::new(PresenceTracker::new(
+                self.presence_timeout,
+                self.rssi_smoothing,
+            ))),
+            reconnect_policy: self.reconnect_policy.clone(),
+            policies: Arc::default(),
+            demand: Demand::default(),
+            read_timeout: self.read_timeout,
+            metrics: Metrics::default(),
+            replaying: true,
+        })
+    }
+
     fn spawn_events(&mut self) -> EasyBluezHandle {
         let (tx_macs, rx_macs) = channel();
+        let (tx_classic, rx_classic) = channel();
         let (tx_devs, rx_devs) = channel();
-        let (tx_conn_evs, _rx_conn_evs) = channel();
+        let (tx_conn_evs, rx_conn_evs) = channel();
+        let (tx_policies, rx_policies) = channel();
+        let (tx_dropped, rx_dropped) = channel();
+        let (tx_priorities, rx_priorities) = channel();
         let (tx_poll, rx_poll) = channel();
         let (tx_write, rx_write) = channel();
+        let (tx_read, rx_read) = channel();
+        let (tx_beacons, rx_beacons) = channel();
+        let (tx_notify, rx_notify) = channel();
+        let (tx_bus_subs, rx_bus_subs) = channel();
+        let (tx_notify_routes, rx_notify_routes) = channel();
+        let (tx_presence, rx_presence) = channel();
+
+        // Shared view of BlueZ's objects, kept up to date by the dispatcher
+        let cache = Arc::new(RwLock::new(ObjectCache::default()));
+        let demand = Demand::default();
+        let stats = SharedStats::default();
+        let presence = Arc::new(RwLock::new(PresenceTracker::new(
+            self.presence_timeout,
+            self.rssi_smoothing,
+        )));
+        let metrics = if self.metrics {
+            Metrics::enabled()
+        } else {
+            Metrics::default()
+        };
+        let recorder = match self.recording.take() {
+            Some(file) => Recorder::new(file, cache.clone()),
+            None => Recorder::default(),
+        };
+        let capture = match self.capture.take() {
+            Some(file) => Capture::new(file, cache.clone()),
+            None => Capture::default(),
+        };
+        let dispatcher = Dispatcher::new(
+            cache.clone(),
+            rx_bus_subs,
+            rx_notify_routes,
+            metrics.clone(),
+            recorder.clone(),
+            capture.clone(),
+        );
         let (tx_poll_characs, rx_poll_characs) = channel();
         let (tx_write_characs, rx_write_characs) = channel();
         let (tx_edpts, rx_edpts) = channel();
@@ -154,10 +997,23 @@ impl EasyBluez {
                 db: HashSet::new(),
                 wl: HashSet::new(),
                 receiver: rx_macs,
+                rx_classic,
+                classic: HashSet::new(),
                 sender_connect: tx_devs,
                 sender_endpoints: tx_edpts,
                 scan_interval: self.scan_interval.clone(),
                 scan_duration: self.scan_duration.clone(),
+                rx_beacon_subs: rx_beacons,
+                beacon_subs: Vec::new(),
+                continuous: self.continuous_scan,
+                scanner: None,
+                tx_bus_subs: tx_bus_subs.clone(),
+                rx_dropped,
+                metrics: metrics.clone(),
+                presence: presence.clone(),
+                rx_presence_subs: rx_presence,
+                presence_subs: Vec::new(),
+                cache: cache.clone(),
             },
         };
 
@@ -167,7 +1023,23 @@ impl EasyBluez {
                 connect_interval: self.connect_interval,
                 db: vec![],
                 incoming: rx_devs,
-                outgoing: tx_conn_evs,
+                cache: cache.clone(),
+                missing_threshold: self.missing_threshold,
+                default_policy: self.reconnect_policy.clone(),
+                rx_policies,
+                policies: HashMap::new(),
+                rx_event_subs: rx_conn_evs,
+                event_subs: Vec::new(),
+                mode: self.connect_mode.clone(),
+                demand: demand.clone(),
+                max_connections: self.max_connections,
+                rotation_interval: self.rotation_interval,
+                rx_priorities,
+                priorities: HashMap::new(),
+                stats: stats.clone(),
+                tx_dropped,
+                metrics: metrics.clone(),
+                recorder: recorder.clone(),
             },
         };
 
@@ -182,11 +1054,23 @@ impl EasyBluez {
                 pending_poll: Vec::new(),
                 pending_write: Vec::new(),
 
+                rx_reads: rx_read,
+                pending_read: Vec::new(),
+
                 tx_poll_characs: tx_poll_characs,
                 tx_write_characs: tx_write_characs,
 
+                rx_notifies: rx_notify,
+                notifies: Vec::new(),
+                tx_notify_routes,
+
                 rx_devs: rx_edpts,
                 devices: HashMap::new(),
+                cache: cache.clone(),
+                demand: demand.clone(),
+                metrics: metrics.clone(),
+                recorder: recorder.clone(),
+                capture: capture.clone(),
             },
         };
 
@@ -194,8 +1078,14 @@ impl EasyBluez {
             task: |s: &mut DataDb| data_poll_task(s),
             state: DataDb {
                 poll_interval: self.poll_interval,
+                retry_interval: self.connect_interval,
                 polls: Vec::new(),
                 poll_rx: rx_poll_characs,
+                cache: cache.clone(),
+                demand: demand.clone(),
+                metrics: metrics.clone(),
+                recorder: recorder.clone(),
+                capture: capture.clone(),
             },
         };
 
@@ -205,6 +1095,11 @@ impl EasyBluez {
                 write_interval: self.write_interval,
                 writes: Vec::new(),
                 write_rx: rx_write_characs,
+                cache: cache.clone(),
+                demand: demand.clone(),
+                metrics: metrics.clone(),
+                recorder,
+                capture,
             },
         };
 
@@ -221,16 +1116,92 @@ impl EasyBluez {
         d_hdl.send(Box::new(write_event)).unwrap();
 
         EasyBluezHandle {
-            _scheduler: thread::spawn(move || {
-                scheduler.run();
-            }),
-            _data_scheduler: thread::spawn(move || {
-                data_scheduler.run();
-            }),
+            _threads: vec![
+                thread::spawn(move || {
+                    scheduler.run();
+                }),
+                thread::spawn(move || {
+                    data_scheduler.run();
+                }),
+                thread::spawn(move || {
+                    dispatcher.run();
+                }),
+            ],
             mac_sender: tx_macs,
+            classic_sender: tx_classic,
             poll_sender: tx_poll,
             write_sender: tx_write,
-            _rx: _rx_conn_evs,
+            read_sender: tx_read,
+            beacon_sender: tx_beacons,
+            notify_sender: tx_notify,
+            policy_sender: tx_policies,
+            conn_event_sender: tx_conn_evs,
+            priority_sender: tx_priorities,
+            bus_sub_sender: tx_bus_subs,
+            presence_sender: tx_presence,
+            cache,
+            stats,
+            presence,
+            reconnect_policy: self.reconnect_policy.clone(),
+            policies: Arc::default(),
+            demand,
+            read_timeout: self.read_timeout,
+            metrics,
+            replaying: false,
+        }
+    }
+}
+
+/// Name endpoints by device and UUIDs, rather than BlueZ object path
+fn name_endpoints(mut snapshot: MetricsSnapshot, cache: &SharedCache) -> MetricsSnapshot {
+    let cache = cache.read().unwrap();
+    snapshot.endpoints = snapshot.endpoints
+        .into_iter()
+        .map(|(path, m)| (cache.endpoint_name(&path).unwrap_or(path), m))
+        .collect();
+    snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    const MAC: &str = "CF:75:CE:86:6D:02";
+
+    #[test]
+    fn replay_fails_calls_that_need_bluez() {
+        let path = env::temp_dir().join(format!("easy-bluez-replay-{}.jsonl", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, r#"{{"at":0.05,"kind":"connection","event":{{"Connected":"{}"}}}}"#, MAC).unwrap();
+        drop(file);
+
+        let hdl = EasyBluez::new().replay(&path, 1.0).unwrap();
+        let events = hdl.connection_events().unwrap();
+
+        let started = Instant::now();
+        for result in [
+            hdl.connect(MAC),
+            hdl.services(MAC).map(|_| ()),
+            hdl.disconnect(MAC),
+            hdl.device_status(MAC).map(|_| ()),
+            hdl.scan(Duration::seconds(1)).map(|_| ()),
+        ] {
+            assert_eq!(result.unwrap_err().to_string(), "not available in replay");
         }
+        // Well within the read timeout
+        assert!(started.elapsed() < OldDuration::from_secs(5));
+        assert!(hdl.known_devices().is_empty());
+
+        // Connection events are still replayed
+        let mac = BtMacAddress::from_str(MAC).unwrap();
+        assert_eq!(
+            events.recv_timeout(OldDuration::from_secs(5)).unwrap(),
+            ConnectionEvent::Connected(mac)
+        );
+
+        fs::remove_file(&path).unwrap();
     }
 }
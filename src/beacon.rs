@@ -0,0 +1,357 @@
+//! Recognizing iBeacon and Eddystone frames in scanned advertisements.
+//! Beacons seen during discovery are delivered by `EasyBluezHandle::beacons`.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use uuid::Uuid;
+
+use BtMacAddress;
+use services::uuid_from_u16;
+
+/// Apple's Bluetooth SIG Company Identifier, used by iBeacon
+pub const APPLE_COMPANY_ID: u16 = 0x004C;
+/// 16 bit service UUID carrying Eddystone frames
+pub const EDDYSTONE_SERVICE: u16 = 0xFEAA;
+
+/// Path loss exponent used for distance estimation. 2.0 is free space,
+/// indoor environments are typically somewhere between 2 and 4
+const PATH_LOSS_EXPONENT: f64 = 2.0;
+
+/// Eddystone reports TX power at 0m, while distance estimation uses
+/// the power measured at 1m
+const EDDYSTONE_1M_LOSS: i8 = 41;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Beacon {
+    IBeacon {
+        uuid: Uuid,
+        major: u16,
+        minor: u16,
+        /// RSSI at 1m, in dBm
+        measured_power: i8,
+    },
+    EddystoneUid {
+        /// TX power at 0m, in dBm
+        tx_power: i8,
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+    EddystoneUrl {
+        /// TX power at 0m, in dBm
+        tx_power: i8,
+        url: String,
+    },
+    EddystoneTlm {
+        version: u8,
+        /// Battery voltage in mV, 0 if not supported
+        battery_mv: u16,
+        /// Beacon temperature in degrees C, if supported
+        temperature: Option<f32>,
+        adv_count: u32,
+        /// Time since power-on, in units of 0.1s
+        uptime_deciseconds: u32,
+    },
+    EddystoneEid {
+        /// TX power at 0m, in dBm
+        tx_power: i8,
+        eid: [u8; 8],
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct BeaconEvent {
+    pub mac: BtMacAddress,
+    pub rssi: Option<i16>,
+    pub beacon: Beacon,
+    /// Estimated distance in meters, when both RSSI and a calibrated
+    /// power level are available
+    pub distance: Option<f64>,
+}
+
+impl Beacon {
+    /// Calibrated RSSI at 1m, if the frame carries one
+    pub fn measured_power(&self) -> Option<i8> {
+        match *self {
+            Beacon::IBeacon { measured_power, .. } => Some(measured_power),
+            Beacon::EddystoneUid { tx_power, .. }
+            | Beacon::EddystoneUrl { tx_power, .. }
+            | Beacon::EddystoneEid { tx_power, .. } => Some(tx_power.saturating_sub(EDDYSTONE_1M_LOSS)),
+            Beacon::EddystoneTlm { .. } => None,
+        }
+    }
+}
+
+/// Estimate distance in meters from the RSSI and the calibrated power at 1m,
+/// using the log-distance path loss model
+pub fn estimate_distance(measured_power: i8, rssi: i16) -> f64 {
+    let exponent = (measured_power as f64 - rssi as f64) / (10.0 * PATH_LOSS_EXPONENT);
+    10f64.powf(exponent)
+}
+
+/// Find all beacon frames in the advertised manufacturer and service data
+pub fn parse_beacons(
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+    service_data: &HashMap<String, Vec<u8>>,
+) -> Vec<Beacon> {
+    let mut beacons = vec![];
+
+    if let Some(data) = manufacturer_data.get(&APPLE_COMPANY_ID) {
+        beacons.extend(parse_ibeacon(data));
+    }
+
+    let eddystone = uuid_from_u16(EDDYSTONE_SERVICE);
+    for (uuid_s, data) in service_data.iter() {
+        if Uuid::from_str(uuid_s).ok() == Some(eddystone) {
+            beacons.extend(parse_eddystone(data));
+        }
+    }
+
+    beacons
+}
+
+/// Parse Apple manufacturer data as an iBeacon frame
+pub fn parse_ibeacon(data: &[u8]) -> Option<Beacon> {
+    if data.len() < 23 || data[0] != 0x02 || data[1] != 0x15 {
+        return None;
+    }
+
+    Some(Beacon::IBeacon {
+        uuid: Uuid::from_bytes(&data[2..18]).ok()?,
+        major: be_u16(&data[18..20]),
+        minor: be_u16(&data[20..22]),
+        measured_power: data[22] as i8,
+    })
+}
+
+/// Parse Eddystone service data
+pub fn parse_eddystone(data: &[u8]) -> Option<Beacon> {
+    match *data.first()? {
+        0x00 if data.len() >= 18 => {
+            let mut namespace = [0u8; 10];
+            let mut instance = [0u8; 6];
+            namespace.copy_from_slice(&data[2..12]);
+            instance.copy_from_slice(&data[12..18]);
+
+            Some(Beacon::EddystoneUid {
+                tx_power: data[1] as i8,
+                namespace,
+                instance,
+            })
+        }
+        0x10 if data.len() >= 3 => Some(Beacon::EddystoneUrl {
+            tx_power: data[1] as i8,
+            url: decode_eddystone_url(data[2], &data[3..])?,
+        }),
+        0x20 if data.len() >= 14 => {
+            let raw_temp = be_u16(&data[4..6]);
+            let temperature = if raw_temp == 0x8000 {
+                None
+            } else {
+                // Signed 8.8 fixed point
+                Some(raw_temp as i16 as f32 / 256.0)
+            };
+
+            Some(Beacon::EddystoneTlm {
+                version: data[1],
+                battery_mv: be_u16(&data[2..4]),
+                temperature,
+                adv_count: be_u32(&data[6..10]),
+                uptime_deciseconds: be_u32(&data[10..14]),
+            })
+        }
+        0x30 if data.len() >= 10 => {
+            let mut eid = [0u8; 8];
+            eid.copy_from_slice(&data[2..10]);
+
+            Some(Beacon::EddystoneEid {
+                tx_power: data[1] as i8,
+                eid,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn decode_eddystone_url(scheme: u8, encoded: &[u8]) -> Option<String> {
+    const SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+    const EXPANSIONS: [&str; 14] = [
+        ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/",
+        ".com", ".org", ".edu", ".net", ".info", ".biz", ".gov",
+    ];
+
+    let mut url = SCHEMES.get(scheme as usize)?.to_string();
+
+    for b in encoded {
+        match EXPANSIONS.get(*b as usize) {
+            Some(exp) => url.push_str(exp),
+            None if *b > 0x20 && *b < 0x7F => url.push(*b as char),
+            None => return None,
+        }
+    }
+
+    Some(url)
+}
+
+fn be_u16(raw: &[u8]) -> u16 {
+    ((raw[0] as u16) << 8) | (raw[1] as u16)
+}
+
+fn be_u32(raw: &[u8]) -> u32 {
+    raw[..4].iter().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// iBeacon with UUID e2c56db5-dffb-48d2-b060-d0f5a71096e0, major 1,
+    /// minor 2, measured power -59 dBm
+    const IBEACON: [u8; 23] = [
+        0x02, 0x15, 0xe2, 0xc5, 0x6d, 0xb5, 0xdf, 0xfb, 0x48, 0xd2, 0xb0, 0x60, 0xd0, 0xf5,
+        0xa7, 0x10, 0x96, 0xe0, 0x00, 0x01, 0x00, 0x02, 0xc5,
+    ];
+
+    #[test]
+    fn ibeacon() {
+        assert_eq!(
+            parse_ibeacon(&IBEACON),
+            Some(Beacon::IBeacon {
+                uuid: Uuid::from_str("e2c56db5-dffb-48d2-b060-d0f5a71096e0").unwrap(),
+                major: 1,
+                minor: 2,
+                measured_power: -59,
+            })
+        );
+    }
+
+    #[test]
+    fn ibeacon_rejects_other_frames() {
+        assert_eq!(parse_ibeacon(&IBEACON[..22]), None);
+
+        let mut other = IBEACON;
+        other[1] = 0x16;
+        assert_eq!(parse_ibeacon(&other), None);
+    }
+
+    #[test]
+    fn eddystone_uid() {
+        let frame = [
+            0x00, 0xe7, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0, 0,
+        ];
+
+        assert_eq!(
+            parse_eddystone(&frame),
+            Some(Beacon::EddystoneUid {
+                tx_power: -25,
+                namespace: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+                instance: [0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5],
+            })
+        );
+        assert_eq!(parse_eddystone(&frame[..17]), None);
+    }
+
+    #[test]
+    fn eddystone_url() {
+        // https://www.google.com/
+        let frame = [0x10, 0xeb, 0x01, b'g', b'o', b'o', b'g', b'l', b'e', 0x00];
+
+        assert_eq!(
+            parse_eddystone(&frame),
+            Some(Beacon::EddystoneUrl {
+                tx_power: -21,
+                url: "https://www.google.com/".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn eddystone_url_decoding() {
+        assert_eq!(decode_eddystone_url(0x02, b"example\x08"), Some("http://example.org".to_string()));
+        assert_eq!(decode_eddystone_url(0x03, b"a\x07b"), Some("https://a.comb".to_string()));
+        assert_eq!(decode_eddystone_url(0x00, b""), Some("http://www.".to_string()));
+        assert_eq!(decode_eddystone_url(0x04, b"example"), None);
+        assert_eq!(decode_eddystone_url(0x00, b"a b"), None);
+        assert_eq!(decode_eddystone_url(0x00, &[0x0e]), None);
+    }
+
+    #[test]
+    fn eddystone_tlm() {
+        let frame = [
+            0x20, 0x00, 0x0b, 0xb8, 0x19, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x27, 0x10,
+        ];
+
+        assert_eq!(
+            parse_eddystone(&frame),
+            Some(Beacon::EddystoneTlm {
+                version: 0,
+                battery_mv: 3000,
+                temperature: Some(25.5),
+                adv_count: 256,
+                uptime_deciseconds: 10000,
+            })
+        );
+    }
+
+    #[test]
+    fn eddystone_tlm_without_temperature() {
+        let frame = [0x20, 0x00, 0x00, 0x00, 0x80, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        match parse_eddystone(&frame) {
+            Some(Beacon::EddystoneTlm { temperature, battery_mv, .. }) => {
+                assert_eq!(temperature, None);
+                assert_eq!(battery_mv, 0);
+            }
+            other => panic!("expected TLM, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn eddystone_eid() {
+        let frame = [0x30, 0xf6, 1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert_eq!(
+            parse_eddystone(&frame),
+            Some(Beacon::EddystoneEid {
+                tx_power: -10,
+                eid: [1, 2, 3, 4, 5, 6, 7, 8],
+            })
+        );
+    }
+
+    #[test]
+    fn eddystone_rejects_unknown_frames() {
+        assert_eq!(parse_eddystone(&[]), None);
+        assert_eq!(parse_eddystone(&[0x40, 0, 0]), None);
+    }
+
+    #[test]
+    fn measured_power_at_one_metre() {
+        let url = Beacon::EddystoneUrl {
+            tx_power: -21,
+            url: String::new(),
+        };
+        assert_eq!(url.measured_power(), Some(-62));
+    }
+
+    #[test]
+    fn distance_from_rssi() {
+        assert!((estimate_distance(-59, -59) - 1.0).abs() < 1e-9);
+        assert!((estimate_distance(-59, -79) - 10.0).abs() < 1e-9);
+        assert!((estimate_distance(-59, -39) - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn beacons_found_in_advertised_data() {
+        let mut mfr = HashMap::new();
+        mfr.insert(APPLE_COMPANY_ID, IBEACON.to_vec());
+        let mut svc = HashMap::new();
+        svc.insert(
+            "0000feaa-0000-1000-8000-00805f9b34fb".to_string(),
+            vec![0x30, 0xf6, 1, 2, 3, 4, 5, 6, 7, 8],
+        );
+
+        assert_eq!(parse_beacons(&mfr, &svc).len(), 2);
+        assert!(parse_beacons(&HashMap::new(), &HashMap::new()).is_empty());
+    }
+}
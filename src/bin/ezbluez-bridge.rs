@@ -0,0 +1,377 @@
+//! A bridge for programs not written in Rust. Commands are read as JSON, one
+//! per line, from stdin or from clients of a Unix socket, and replies, data
+//! and events are written back the same way.
+//!
+//! ```text
+//! {"id": 1, "cmd": "subscribe", "mac": "CF:75:CE:86:6D:02", "service": "...", "characteristic": "..."}
+//! {"type": "ok", "id": 1}
+//! {"type": "data", "id": 1, "mac": "CF:75:CE:86:6D:02", "service": "...", "characteristic": "...", "data": [1, 2]}
+//! ```
+
+extern crate easy_bluez;
+use easy_bluez::{Duration, EasyBluez, EasyBluezHandle};
+use easy_bluez::connection::ConnectionEvent;
+use easy_bluez::devices::ScannedDevice;
+use easy_bluez::errors::*;
+
+extern crate env_logger;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration as OldDuration;
+
+const USAGE: &str = "Usage: ezbluez-bridge [--socket PATH]";
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    /// Echoed back on everything the command produces
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    Poll(Endpoint),
+    Subscribe(Endpoint),
+    Read(Endpoint),
+    Write {
+        #[serde(flatten)]
+        endpoint: Endpoint,
+        data: Vec<u8>,
+    },
+    Scan {
+        #[serde(default = "default_scan_secs")]
+        seconds: i64,
+    },
+    /// Stream connection events of all managed devices
+    Events,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Endpoint {
+    mac: String,
+    service: String,
+    characteristic: String,
+}
+
+fn default_scan_secs() -> i64 {
+    5
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply {
+    Ok {
+        id: Option<u64>,
+    },
+    Error {
+        id: Option<u64>,
+        message: String,
+    },
+    Data {
+        id: Option<u64>,
+        #[serde(flatten)]
+        endpoint: Endpoint,
+        data: Vec<u8>,
+    },
+    Devices {
+        id: Option<u64>,
+        devices: Vec<ScannedDevice>,
+    },
+    Connection {
+        id: Option<u64>,
+        event: ConnectionEvent,
+    },
+}
+
+/// Where a client's replies go
+#[derive(Clone)]
+struct Client {
+    tx: Sender<Reply>,
+    /// Set once the client stops sending commands, ending its streams
+    closed: Arc<AtomicBool>,
+}
+
+fn main() {
+    env_logger::init().expect("Failed to initalize logging");
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let socket = match args.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+        [] => None,
+        ["--socket", path] => Some(path.to_string()),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let ez = Arc::new(EasyBluez::new().run());
+
+    let result = match socket {
+        Some(path) => serve_socket(&ez, &path),
+        None => {
+            serve_client(&ez, io::stdin().lock(), io::stdout());
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Accept clients until the process is stopped, each served on its own thread
+fn serve_socket(ez: &Arc<EasyBluezHandle>, path: &str) -> Result<()> {
+    // Left behind by a previous run
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).chain_err(|| "failed to bind socket")?;
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("failed to accept client, {}", e);
+                continue;
+            }
+        };
+        let writer = stream.try_clone().chain_err(|| "failed to clone socket")?;
+        let ez = ez.clone();
+
+        thread::spawn(move || serve_client(&ez, BufReader::new(stream), writer));
+    }
+
+    Ok(())
+}
+
+/// Run commands from one client until it hangs up. Subscriptions end with
+/// the client, but replies to commands still running are sent first
+fn serve_client<R, W>(ez: &Arc<EasyBluezHandle>, reader: R, writer: W)
+where
+    R: BufRead,
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = channel();
+    let writer = thread::spawn(move || write_replies(writer, rx));
+    let client = Client {
+        tx,
+        closed: Arc::new(AtomicBool::new(false)),
+    };
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<Request>(&line) {
+            Ok(req) => run(ez, req, &client),
+            Err(e) => {
+                let _ = client.tx.send(Reply::Error {
+                    id: None,
+                    message: format!("bad request, {}", e),
+                });
+            }
+        }
+    }
+
+    client.closed.store(true, Ordering::Relaxed);
+    drop(client);
+    let _ = writer.join();
+}
+
+fn write_replies<W: Write>(mut writer: W, rx: Receiver<Reply>) {
+    for reply in rx {
+        let line = serde_json::to_string(&reply).expect("replies always serialize");
+
+        if writeln!(writer, "{}", line).and_then(|_| writer.flush()).is_err() {
+            // The client is gone, forwarders notice when their sends fail
+            return;
+        }
+    }
+}
+
+fn run(ez: &Arc<EasyBluezHandle>, req: Request, client: &Client) {
+    let id = req.id;
+
+    let started = match req.command {
+        Command::Poll(ep) => ez.poll(&ep.mac, &ep.service, &ep.characteristic)
+            .map(|rx| forward_data(id, ep, rx, client)),
+        Command::Subscribe(ep) => ez.notify(&ep.mac, &ep.service, &ep.characteristic)
+            .map(|rx| forward_data(id, ep, rx, client)),
+        Command::Events => ez.connection_events()
+            .map(|rx| forward(rx, client, move |event| Reply::Connection { id, event })),
+        Command::Read(ep) => {
+            in_background(ez, client, id, move |ez| {
+                let data = ez.read(&ep.mac, &ep.service, &ep.characteristic)?;
+                Ok(Reply::Data {
+                    id,
+                    endpoint: ep,
+                    data: data.to_vec(),
+                })
+            });
+            return;
+        }
+        Command::Write { endpoint: ep, data } => {
+            in_background(ez, client, id, move |ez| {
+                ez.write(&ep.mac, &ep.service, &ep.characteristic, &data)?;
+                Ok(Reply::Ok { id })
+            });
+            return;
+        }
+        Command::Scan { seconds } => {
+            in_background(ez, client, id, move |ez| {
+                let devices = ez.scan(Duration::seconds(seconds))?;
+                Ok(Reply::Devices { id, devices })
+            });
+            return;
+        }
+    };
+
+    let _ = client.tx.send(match started {
+        Ok(()) => Reply::Ok { id },
+        Err(e) => Reply::Error {
+            id,
+            message: e.to_string(),
+        },
+    });
+}
+
+/// Run a blocking command without holding up the client's other commands
+fn in_background<F>(ez: &Arc<EasyBluezHandle>, client: &Client, id: Option<u64>, cmd: F)
+where
+    F: FnOnce(&EasyBluezHandle) -> Result<Reply> + Send + 'static,
+{
+    let ez = ez.clone();
+    let tx = client.tx.clone();
+
+    thread::spawn(move || {
+        let reply = cmd(&ez).unwrap_or_else(|e| Reply::Error {
+            id,
+            message: e.to_string(),
+        });
+        let _ = tx.send(reply);
+    });
+}
+
+fn forward_data(id: Option<u64>, ep: Endpoint, rx: Receiver<Box<[u8]>>, client: &Client) {
+    forward(rx, client, move |data| Reply::Data {
+        id,
+        endpoint: ep.clone(),
+        data: data.to_vec(),
+    })
+}
+
+/// Forward everything received to the client, until it hangs up
+fn forward<T, F>(rx: Receiver<T>, client: &Client, to_reply: F)
+where
+    T: Send + 'static,
+    F: Fn(T) -> Reply + Send + 'static,
+{
+    let client = client.clone();
+
+    thread::spawn(move || loop {
+        if client.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        match rx.recv_timeout(OldDuration::from_millis(200)) {
+            Ok(item) => {
+                if client.tx.send(to_reply(item)).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::os::unix::net::UnixStream;
+
+    const POLL: &str = r#"{"id": 1, "cmd": "poll", "mac": "CF:75:CE:86:6D:02", "service": "0000180f-0000-1000-8000-00805f9b34fb", "characteristic": "00002a19-0000-1000-8000-00805f9b34fb"}"#;
+
+    /// A handle replaying a battery level polled every 50ms for 5 seconds
+    fn replaying() -> Arc<EasyBluezHandle> {
+        let path = env::temp_dir().join(format!("ezbluez-bridge-{}.jsonl", process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        for i in 1..100 {
+            writeln!(
+                file,
+                r#"{{"at":{},"kind":"poll","mac":"CF:75:CE:86:6D:02","service":"0000180f-0000-1000-8000-00805f9b34fb","characteristic":"00002a19-0000-1000-8000-00805f9b34fb","data":[{}]}}"#,
+                f64::from(i) * 0.05,
+                i
+            ).unwrap();
+        }
+        drop(file);
+
+        let ez = Arc::new(EasyBluez::new().replay(&path, 1.0).unwrap());
+        fs::remove_file(&path).unwrap();
+        ez
+    }
+
+    /// Connect a client, and poll
+    fn poll(ez: &Arc<EasyBluezHandle>) -> (UnixStream, BufReader<UnixStream>) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let writer = theirs.try_clone().unwrap();
+        let ez = ez.clone();
+        thread::spawn(move || serve_client(&ez, BufReader::new(theirs), writer));
+
+        ours.set_read_timeout(Some(OldDuration::from_secs(5))).unwrap();
+        let mut replies = BufReader::new(ours.try_clone().unwrap());
+        writeln!(&ours, "{}", POLL).unwrap();
+
+        let mut line = String::new();
+        replies.read_line(&mut line).unwrap();
+        assert_eq!(line.trim(), r#"{"type":"ok","id":1}"#);
+
+        (ours, replies)
+    }
+
+    fn next_data(replies: &mut BufReader<UnixStream>) -> Vec<u8> {
+        let mut line = String::new();
+        replies.read_line(&mut line).unwrap();
+
+        let reply: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(reply["type"], "data");
+        serde_json::from_value(reply["data"].clone()).unwrap()
+    }
+
+    #[test]
+    fn polling_continues_after_a_client_hangs_up() {
+        let ez = replaying();
+
+        let (client, mut replies) = poll(&ez);
+        next_data(&mut replies);
+        drop(replies);
+        client.shutdown(::std::net::Shutdown::Both).unwrap();
+
+        // Give the handle a few polls to the client that went away
+        thread::sleep(OldDuration::from_millis(300));
+
+        let (_client, mut replies) = poll(&ez);
+        let first = next_data(&mut replies);
+        let second = next_data(&mut replies);
+        assert!(second[0] > first[0]);
+    }
+}
@@ -0,0 +1,51 @@
+//! Bridge devices to an MQTT broker, as described by a JSON configuration
+//! file:
+//!
+//! ```text
+//! {
+//!     "host": "localhost",
+//!     "port": 1883,
+//!     "prefix": "sensors",
+//!     "endpoints": [
+//!         {"mac": "CF:75:CE:86:6D:02", "service": "...", "characteristic": "...", "mode": "poll"},
+//!         {"mac": "CF:75:CE:86:6D:02", "service": "...", "characteristic": "...", "mode": "write"}
+//!     ]
+//! }
+//! ```
+
+extern crate easy_bluez;
+use easy_bluez::EasyBluez;
+use easy_bluez::errors::*;
+use easy_bluez::mqtt::MqttGateway;
+
+extern crate env_logger;
+extern crate serde_json;
+
+use std::env;
+use std::fs::File;
+use std::process;
+
+fn main() {
+    env_logger::init().expect("Failed to initalize logging");
+
+    let path = match env::args().nth(1) {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: ezbluez-mqtt <config.json>");
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&path) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str) -> Result<()> {
+    let file = File::open(path).chain_err(|| "failed to open configuration")?;
+    let gateway: MqttGateway = serde_json::from_reader(file).chain_err(|| "bad configuration")?;
+
+    let ez = EasyBluez::new().run();
+    gateway.run(&ez)
+}
@@ -0,0 +1,145 @@
+//! Encoding of characteristic values as text
+
+use easy_bluez::errors::*;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Hex,
+    Utf8,
+    Base64,
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl Format {
+    /// Parse a `--format` value, hex when not given
+    pub fn from_name(name: Option<&str>) -> Result<Format> {
+        match name {
+            None | Some("hex") => Ok(Format::Hex),
+            Some("utf8") => Ok(Format::Utf8),
+            Some("base64") => Ok(Format::Base64),
+            Some(other) => bail!("unknown format {}", other),
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> String {
+        match self {
+            Format::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+            Format::Utf8 => String::from_utf8_lossy(data).into_owned(),
+            Format::Base64 => data
+                .chunks(3)
+                .flat_map(|chunk| {
+                    let n = chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+
+                    (0..4).map(move |i| {
+                        if i <= chunk.len() {
+                            BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char
+                        } else {
+                            '='
+                        }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn decode(self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            Format::Hex => {
+                let text = text.trim();
+                let digits: Vec<char> = text.strip_prefix("0x").unwrap_or(text)
+                    .chars()
+                    .filter(|c| !c.is_whitespace() && *c != ':')
+                    .collect();
+                // Parsing alone would let a sign through
+                if !digits.iter().all(|c| c.is_ascii_hexdigit()) {
+                    bail!("not hex");
+                }
+                if !digits.len().is_multiple_of(2) {
+                    bail!("odd number of hex digits");
+                }
+
+                digits
+                    .chunks(2)
+                    .map(|pair| {
+                        let byte: String = pair.iter().collect();
+                        u8::from_str_radix(&byte, 16).chain_err(|| "not hex")
+                    })
+                    .collect()
+            }
+            Format::Utf8 => Ok(text.as_bytes().to_vec()),
+            Format::Base64 => {
+                let mut out = vec![];
+                let mut acc = 0u32;
+                let mut bits = 0;
+
+                for c in text.bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+                    let v = match BASE64.iter().position(|b| *b == c) {
+                        Some(v) => v as u32,
+                        None => bail!("not base64"),
+                    };
+                    acc = (acc << 6) | v;
+                    bits += 6;
+
+                    if bits >= 8 {
+                        bits -= 8;
+                        out.push((acc >> bits) as u8);
+                        acc &= (1 << bits) - 1;
+                    }
+                }
+
+                Ok(out)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_bytes() -> Vec<u8> {
+        (0..=255).collect()
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let data = all_bytes();
+        assert_eq!(Format::Hex.decode(&Format::Hex.encode(&data)).unwrap(), data);
+        assert_eq!(Format::Hex.encode(&[0x0a, 0xff]), "0aff");
+        assert_eq!(Format::Hex.decode("0x0AfF").unwrap(), vec![0x0a, 0xff]);
+        assert_eq!(Format::Hex.decode("0a:ff 01").unwrap(), vec![0x0a, 0xff, 0x01]);
+        assert_eq!(Format::Hex.decode("").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn bad_hex_is_rejected() {
+        for text in &["0x0x12", "abc", "zz", "+f", "-1", "12x0"] {
+            assert!(Format::Hex.decode(text).is_err(), "{} decoded", text);
+        }
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        assert_eq!(Format::Base64.encode(b"f"), "Zg==");
+        assert_eq!(Format::Base64.encode(b"fo"), "Zm8=");
+        assert_eq!(Format::Base64.encode(b"foo"), "Zm9v");
+        assert_eq!(Format::Base64.decode("Zm9vYg==").unwrap(), b"foob");
+
+        let data = all_bytes();
+        for len in 0..data.len() {
+            let text = Format::Base64.encode(&data[..len]);
+            assert_eq!(text.len() % 4, 0);
+            assert_eq!(Format::Base64.decode(&text).unwrap(), &data[..len]);
+        }
+    }
+
+    #[test]
+    fn bad_base64_is_rejected() {
+        assert!(Format::Base64.decode("Zm9v!").is_err());
+        assert!(Format::Base64.decode("Zm-v").is_err());
+    }
+}
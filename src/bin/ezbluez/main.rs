@@ -0,0 +1,236 @@
+extern crate easy_bluez;
+use easy_bluez::{Duration, EasyBluez, EasyBluezHandle};
+use easy_bluez::devices::ScannedDevice;
+use easy_bluez::errors::*;
+
+extern crate env_logger;
+#[macro_use]
+extern crate error_chain;
+extern crate rustyline;
+extern crate serde_json;
+
+mod format;
+mod shell;
+
+use std::env;
+use std::process;
+
+use format::Format;
+
+const USAGE: &str = "\
+Usage: ezbluez <command> [options]
+
+Commands:
+    scan [--duration SECS] [--name TEXT] [--min-rssi DBM] [--service UUID] [--json]
+    info <mac> [--json]
+    read <mac> <service> <characteristic> [--format hex|utf8|base64]
+    write <mac> <service> <characteristic> <data> [--format hex|utf8|base64]
+    watch <mac> <service> <characteristic> [--poll] [--format hex|utf8|base64]
+    connect <mac>
+    disconnect <mac>
+    pair <mac>
+    shell
+
+Set RUST_LOG=easy_bluez=info to see what the library is doing.";
+
+fn main() {
+    env_logger::init().expect("Failed to initalize logging");
+
+    let args = Args::parse(env::args().skip(1).collect());
+
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        for cause in e.iter().skip(1) {
+            eprintln!("  caused by: {}", cause);
+        }
+        process::exit(1);
+    }
+}
+
+/// Positional arguments, and `--flag [value]` options
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+/// Options that stand alone, every other option takes a value
+const SWITCHES: &[&str] = &["json", "poll"];
+
+impl Args {
+    fn parse(raw: Vec<String>) -> Self {
+        let mut args = Args {
+            positional: vec![],
+            options: vec![],
+        };
+        let mut raw = raw.into_iter();
+
+        while let Some(arg) = raw.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = if SWITCHES.contains(&name) { None } else { raw.next() };
+                args.options.push((name.to_string(), value));
+            } else {
+                args.positional.push(arg);
+            }
+        }
+
+        args
+    }
+
+    fn command(&self) -> Option<&str> {
+        self.positional.first().map(|c| c.as_str())
+    }
+
+    /// The nth argument after the command
+    fn arg(&self, n: usize, name: &str) -> Result<&str> {
+        match self.positional.get(n + 1) {
+            Some(a) => Ok(a),
+            None => bail!("missing <{}>", name),
+        }
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(n, _)| n == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    fn format(&self) -> Result<Format> {
+        Format::from_name(self.value("format"))
+    }
+}
+
+fn run(args: &Args) -> Result<()> {
+    let cmd = match args.command() {
+        Some(cmd) => cmd,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let ez = EasyBluez::new()
+        .read_timeout(Duration::seconds(20))
+        .run();
+
+    match cmd {
+        "scan" => scan(&ez, args),
+        "info" => info(&ez, args),
+        "read" => {
+            let data = ez.read(args.arg(0, "mac")?, args.arg(1, "service")?, args.arg(2, "characteristic")?)?;
+            println!("{}", args.format()?.encode(&data));
+            Ok(())
+        }
+        "write" => {
+            let data = args.format()?.decode(args.arg(3, "data")?)?;
+            ez.write(args.arg(0, "mac")?, args.arg(1, "service")?, args.arg(2, "characteristic")?, &data)
+        }
+        "watch" => watch(&ez, args),
+        "connect" => ez.connect(args.arg(0, "mac")?),
+        "disconnect" => ez.disconnect(args.arg(0, "mac")?),
+        "pair" => ez.pair(args.arg(0, "mac")?),
+        "shell" => shell::run(&ez),
+        "help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => bail!("unknown command {}, try `ezbluez help`", other),
+    }
+}
+
+fn scan(ez: &EasyBluezHandle, args: &Args) -> Result<()> {
+    let secs = match args.value("duration") {
+        Some(s) => s.parse().chain_err(|| "bad duration")?,
+        None => 5,
+    };
+    let min_rssi: Option<i16> = match args.value("min-rssi") {
+        Some(r) => Some(r.parse().chain_err(|| "bad RSSI")?),
+        None => None,
+    };
+    let name = args.value("name").map(|n| n.to_lowercase());
+    let service = args.value("service").map(|s| s.to_lowercase());
+
+    let devices: Vec<ScannedDevice> = ez.scan(Duration::seconds(secs))?
+        .into_iter()
+        .filter(|d| match name {
+            Some(ref n) => d.name.as_ref().is_some_and(|dn| dn.to_lowercase().contains(n)),
+            None => true,
+        })
+        .filter(|d| match min_rssi {
+            Some(min) => d.rssi.is_some_and(|r| r >= min),
+            None => true,
+        })
+        .filter(|d| match service {
+            Some(ref s) => d.services.iter().any(|u| u.hyphenated().to_string() == *s),
+            None => true,
+        })
+        .collect();
+
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&devices).chain_err(|| "")?);
+        return Ok(());
+    }
+
+    println!("{:<17}  {:>5}  {:<4}  {:<24}  SERVICES", "ADDRESS", "RSSI", "CONN", "NAME");
+    for d in devices {
+        let rssi = d.rssi.map(|r| r.to_string()).unwrap_or_default();
+        let conn = if d.connected { "yes" } else { "" };
+        let services: Vec<String> = d.services.iter().map(|u| u.hyphenated().to_string()).collect();
+
+        println!(
+            "{:<17}  {:>5}  {:<4}  {:<24}  {}",
+            d.mac,
+            rssi,
+            conn,
+            d.name.unwrap_or_default(),
+            services.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+fn info(ez: &EasyBluezHandle, args: &Args) -> Result<()> {
+    let services = ez.services(args.arg(0, "mac")?)?;
+
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&services).chain_err(|| "")?);
+        return Ok(());
+    }
+
+    for svc in services {
+        let kind = if svc.primary { "primary" } else { "secondary" };
+        println!("{} ({})", svc.uuid.hyphenated(), kind);
+
+        for chrc in svc.characteristics {
+            println!("    {} [{}]", chrc.uuid.hyphenated(), chrc.flags.join(", "));
+
+            for desc in chrc.descriptors {
+                println!("        {}", desc.hyphenated());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn watch(ez: &EasyBluezHandle, args: &Args) -> Result<()> {
+    let (mac, svc, chrc) = (args.arg(0, "mac")?, args.arg(1, "service")?, args.arg(2, "characteristic")?);
+    let format = args.format()?;
+
+    let rx = if args.flag("poll") {
+        ez.poll(mac, svc, chrc)?
+    } else {
+        ez.notify(mac, svc, chrc)?
+    };
+
+    while let Ok(data) = rx.recv() {
+        println!("{}", format.encode(&data));
+    }
+
+    Ok(())
+}
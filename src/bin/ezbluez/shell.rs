@@ -0,0 +1,297 @@
+//! An interactive shell over a single handle, so devices stay connected and
+//! subscribed between commands.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration as OldDuration;
+
+use easy_bluez::{Duration, EasyBluezHandle};
+use easy_bluez::connection::ConnectionEvent;
+use easy_bluez::errors::*;
+
+use rustyline::{Context, Editor, Helper};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+
+use format::Format;
+
+const HELP: &str = "\
+    scan [SECS]                          Scan for nearby devices
+    devices                              List devices seen this session
+    select <mac>                         Choose the device to work with
+    ls                                   List services of the selected device
+    read <svc> <chrc> [FORMAT]           Read a characteristic
+    write <svc> <chrc> <data> [FORMAT]   Write a characteristic
+    notify on|off <svc> <chrc> [FORMAT]  Print notifications as they arrive
+    connect | disconnect | pair          Manage the selected device
+    status                               Connection state of the selected device
+    help | quit
+
+FORMAT is hex (the default), utf8 or base64.";
+
+const COMMANDS: &[&str] = &[
+    "scan", "devices", "select", "ls", "read", "write", "notify", "connect", "disconnect",
+    "pair", "status", "help", "quit",
+];
+
+/// Words offered for tab completion, learned as the session goes on
+#[derive(Default)]
+struct Known {
+    macs: BTreeSet<String>,
+    uuids: BTreeSet<String>,
+}
+
+type SharedKnown = Arc<Mutex<Known>>;
+
+struct ShellHelper {
+    known: SharedKnown,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> ::rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let word = line[start..pos].to_lowercase();
+
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let candidates: Vec<String> = if before.is_empty() {
+            COMMANDS.iter().map(|c| c.to_string()).collect()
+        } else if before == ["notify"] {
+            vec!["on".to_string(), "off".to_string()]
+        } else {
+            let known = self.known.lock().unwrap();
+            known.macs.iter().chain(known.uuids.iter()).cloned().collect()
+        };
+
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .filter(|c| c.to_lowercase().starts_with(&word))
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+struct Shell<'a> {
+    ez: &'a EasyBluezHandle,
+    selected: Option<String>,
+    known: SharedKnown,
+    /// Running notification printers, stopped by dropping their sender
+    notifies: HashMap<(String, String, String), Sender<()>>,
+}
+
+pub fn run(ez: &EasyBluezHandle) -> Result<()> {
+    let known = SharedKnown::default();
+    print_events(ez, known.clone())?;
+
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper {
+        known: known.clone(),
+    }));
+
+    let mut shell = Shell {
+        ez,
+        selected: None,
+        known,
+        notifies: HashMap::new(),
+    };
+
+    println!("Type `help` for a list of commands");
+
+    loop {
+        let prompt = match shell.selected {
+            Some(ref mac) => format!("ezbluez [{}]> ", mac),
+            None => "ezbluez> ".to_string(),
+        };
+
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => bail!("failed to read input, {}", e),
+        };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str());
+
+        match words[0] {
+            "quit" | "exit" => return Ok(()),
+            cmd => {
+                if let Err(e) = shell.command(cmd, &words[1..]) {
+                    println!("error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Print connection changes as they happen, in the background
+fn print_events(ez: &EasyBluezHandle, known: SharedKnown) -> Result<()> {
+    let rx = ez.connection_events()?;
+
+    thread::spawn(move || {
+        while let Ok(ev) = rx.recv() {
+            let (what, mac) = match ev {
+                ConnectionEvent::Connected(mac) => ("connected", mac),
+                ConnectionEvent::Disconnected(mac) => ("disconnected", mac),
+                ConnectionEvent::Missing(mac) => ("missing", mac),
+                ConnectionEvent::GaveUp(mac) => ("given up", mac),
+            };
+            println!("[{}] {}", mac, what);
+            known.lock().unwrap().macs.insert(mac.to_string());
+        }
+    });
+
+    Ok(())
+}
+
+impl<'a> Shell<'a> {
+    fn command(&mut self, cmd: &str, args: &[&str]) -> Result<()> {
+        match cmd {
+            "help" => println!("{}", HELP),
+            "scan" => self.scan(args)?,
+            "devices" => {
+                for mac in &self.known.lock().unwrap().macs {
+                    println!("{}", mac);
+                }
+            }
+            "select" => {
+                let mac = arg(args, 0, "mac")?.to_uppercase();
+                self.known.lock().unwrap().macs.insert(mac.clone());
+                self.selected = Some(mac);
+            }
+            "ls" => self.ls()?,
+            "read" => {
+                let format = Format::from_name(args.get(2).cloned())?;
+                let data = self.ez.read(self.selected()?, arg(args, 0, "svc")?, arg(args, 1, "chrc")?)?;
+                println!("{}", format.encode(&data));
+            }
+            "write" => {
+                let format = Format::from_name(args.get(3).cloned())?;
+                let data = format.decode(arg(args, 2, "data")?)?;
+                self.ez.write(self.selected()?, arg(args, 0, "svc")?, arg(args, 1, "chrc")?, &data)?;
+            }
+            "notify" => self.notify(args)?,
+            "connect" => self.ez.connect(self.selected()?)?,
+            "disconnect" => self.ez.disconnect(self.selected()?)?,
+            "pair" => self.ez.pair(self.selected()?)?,
+            "status" => println!("{:#?}", self.ez.device_status(self.selected()?)?),
+            other => bail!("unknown command {}, try `help`", other),
+        }
+
+        Ok(())
+    }
+
+    fn selected(&self) -> Result<&str> {
+        match self.selected {
+            Some(ref mac) => Ok(mac),
+            None => bail!("no device selected, use `select <mac>`"),
+        }
+    }
+
+    fn scan(&mut self, args: &[&str]) -> Result<()> {
+        let secs = match args.first() {
+            Some(s) => s.parse().chain_err(|| "bad duration")?,
+            None => 5,
+        };
+
+        let devices = self.ez.scan(Duration::seconds(secs))?;
+        let mut known = self.known.lock().unwrap();
+
+        for d in devices {
+            let rssi = d.rssi.map(|r| r.to_string()).unwrap_or_default();
+            println!("{}  {:>5}  {}", d.mac, rssi, d.name.unwrap_or_default());
+
+            known.macs.insert(d.mac.to_string());
+            known.uuids.extend(d.services.iter().map(|u| u.hyphenated().to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn ls(&mut self) -> Result<()> {
+        let services = self.ez.services(self.selected()?)?;
+        let mut known = self.known.lock().unwrap();
+
+        for svc in services {
+            println!("{}", svc.uuid.hyphenated());
+            known.uuids.insert(svc.uuid.hyphenated().to_string());
+
+            for chrc in svc.characteristics {
+                println!("    {} [{}]", chrc.uuid.hyphenated(), chrc.flags.join(", "));
+                known.uuids.insert(chrc.uuid.hyphenated().to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn notify(&mut self, args: &[&str]) -> Result<()> {
+        let mac = self.selected()?.to_string();
+        let key = (mac.clone(), arg(args, 1, "svc")?.to_string(), arg(args, 2, "chrc")?.to_string());
+
+        match arg(args, 0, "on|off")? {
+            "on" => {
+                let format = Format::from_name(args.get(3).cloned())?;
+                let rx = self.ez.notify(&key.0, &key.1, &key.2)?;
+                let (tx_stop, rx_stop) = channel::<()>();
+                let chrc = key.2.clone();
+
+                thread::spawn(move || loop {
+                    if rx_stop.try_recv() != Err(TryRecvError::Empty) {
+                        return;
+                    }
+
+                    match rx.recv_timeout(OldDuration::from_millis(200)) {
+                        Ok(data) => println!("[{} {}] {}", mac, chrc, format.encode(&data)),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                });
+
+                self.notifies.insert(key, tx_stop);
+            }
+            "off" => {
+                if self.notifies.remove(&key).is_none() {
+                    bail!("not subscribed");
+                }
+            }
+            other => bail!("expected on or off, not {}", other),
+        }
+
+        Ok(())
+    }
+}
+
+fn arg<'b>(args: &[&'b str], n: usize, name: &str) -> Result<&'b str> {
+    match args.get(n) {
+        Some(a) => Ok(a),
+        None => bail!("missing <{}>", name),
+    }
+}
@@ -0,0 +1,32 @@
+//! Sockets handed out by BlueZ's `AcquireWrite` and `AcquireNotify`, which
+//! carry one ATT packet per read or write, bypassing D-Bus.
+
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+
+use dbus::MessageItem;
+
+use bt_manager::bus::*;
+use bt_manager::signals::CHRC_IFACE;
+use errors::*;
+
+/// Acquire a socket for a characteristic, along with the ATT MTU it was
+/// negotiated with. `method` is `AcquireWrite` or `AcquireNotify`. BlueZ
+/// releases the socket when the acquiring connection goes away, so this
+/// goes through the thread's shared connection
+pub fn acquire(chrc_path: &str, method: &str) -> Result<(File, u16)> {
+    let reply = system_call(chrc_path, CHRC_IFACE, method, &[prop_dict(vec![])])?;
+
+    let mut items = reply.get_items().into_iter();
+    let fd = match items.next() {
+        Some(MessageItem::UnixFd(fd)) => fd,
+        other => bail!("unexpected {} reply: {:?}", method, other),
+    };
+    let mtu = match items.next() {
+        Some(MessageItem::UInt16(mtu)) => mtu,
+        other => bail!("unexpected {} reply: {:?}", method, other),
+    };
+
+    // The reply gave us our own copy of the descriptor
+    Ok((unsafe { File::from_raw_fd(fd.into_fd()) }, mtu))
+}
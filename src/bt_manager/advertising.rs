@@ -0,0 +1,367 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::{Duration as OldDuration, Instant};
+
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, MessageType};
+
+use advertising::{AdvCommand, Advertisement};
+use bt_manager::bus::*;
+use errors::*;
+
+const ADVERTISEMENT_IFACE: &str = "org.bluez.LEAdvertisement1";
+const ADVERTISING_MANAGER: &str = "org.bluez.LEAdvertisingManager1";
+
+static ADV_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct AdvertiserData {
+    path: String,
+    adv: Advertisement,
+    registered: bool,
+
+    /// An in-flight registration, when to give up on it, and who to tell
+    /// about its outcome
+    pending: Option<(u32, Instant, Sender<Result<()>>)>,
+    /// A registration given up on, in case BlueZ still answers it
+    abandoned: Option<u32>,
+    /// How long to wait for BlueZ to answer a registration
+    timeout: OldDuration,
+
+    pub rx_cmds: Receiver<(AdvCommand, Sender<Result<()>>)>,
+}
+
+impl AdvertiserData {
+    pub fn new(
+        adv: Advertisement,
+        timeout: OldDuration,
+        rx_cmds: Receiver<(AdvCommand, Sender<Result<()>>)>,
+    ) -> Self {
+        AdvertiserData {
+            path: format!(
+                "/org/easybluez/advertisement{}",
+                ADV_COUNT.fetch_add(1, Ordering::SeqCst)
+            ),
+            adv,
+            registered: false,
+            pending: None,
+            abandoned: None,
+            timeout,
+            rx_cmds,
+        }
+    }
+
+    /// Serve the advertisement object and process commands until the
+    /// handle is dropped
+    pub fn run(mut self) {
+        let setup = adapter_path().and_then(|adapter| {
+            let conn = Connection::get_private(BusType::System).map_err(|e| e.to_string())?;
+            conn.register_object_path(&self.path).map_err(|e| e.to_string())?;
+            Ok((adapter, conn))
+        });
+
+        let (adapter, conn) = match setup {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to set up advertiser, {:?}", e);
+                // Fail every command until the handle goes away
+                while let Ok((_, reply)) = self.rx_cmds.recv() {
+                    let _ = reply.send(Err(e.to_string().into()));
+                }
+                return;
+            }
+        };
+
+        loop {
+            for item in conn.iter(50) {
+                match item {
+                    ConnectionItem::MethodCall(m) => {
+                        let reply = self.handle_call(&m);
+                        let _ = conn.send(reply);
+                    }
+                    ConnectionItem::MethodReturn(ref m) => self.handle_return(m),
+                    ConnectionItem::Nothing => break,
+                    _ => {}
+                }
+            }
+
+            // Commands are handled one at a time, waiting out registrations
+            // until BlueZ answers or the timeout expires
+            match self.pending {
+                Some((serial, deadline, _)) if Instant::now() >= deadline => {
+                    let (_, _, reply) = self.pending.take().unwrap();
+                    warn!("BlueZ never answered registering {}", self.path);
+                    self.abandoned = Some(serial);
+                    let _ = reply.send(Err("timed out waiting for BlueZ to register the advertisement".into()));
+                }
+                Some(_) => continue,
+                None => {}
+            }
+
+            match self.rx_cmds.try_recv() {
+                Ok((cmd, reply)) => self.handle_command(&conn, &adapter, cmd, reply),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        if self.registered {
+            let _ = self.unregister(&conn, &adapter);
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        conn: &Connection,
+        adapter: &str,
+        cmd: AdvCommand,
+        reply: Sender<Result<()>>,
+    ) {
+        match cmd {
+            AdvCommand::Start if self.registered => {
+                let _ = reply.send(Ok(()));
+            }
+            AdvCommand::Start => self.register(conn, adapter, reply),
+            AdvCommand::Stop => {
+                let _ = reply.send(self.unregister(conn, adapter));
+            }
+            AdvCommand::Update(adv) => {
+                self.adv = adv;
+
+                if !self.registered {
+                    let _ = reply.send(Ok(()));
+                    return;
+                }
+
+                // BlueZ only reads the properties on registration
+                match self.unregister(conn, adapter) {
+                    Ok(()) => self.register(conn, adapter, reply),
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start a registration. BlueZ calls back into us to read the
+    /// properties, so the reply is picked up from the main loop
+    fn register(&mut self, conn: &Connection, adapter: &str, reply: Sender<Result<()>>) {
+        let sent = method_call(
+            adapter,
+            ADVERTISING_MANAGER,
+            "RegisterAdvertisement",
+            &[object_path(&self.path), prop_dict(vec![])],
+        ).and_then(|m| conn.send(m).map_err(|_| "failed to send!".into()));
+
+        match sent {
+            Ok(serial) => self.pending = Some((serial, Instant::now() + self.timeout, reply)),
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            }
+        }
+    }
+
+    fn unregister(&mut self, conn: &Connection, adapter: &str) -> Result<()> {
+        if !self.registered {
+            return Ok(());
+        }
+
+        call(
+            conn,
+            adapter,
+            ADVERTISING_MANAGER,
+            "UnregisterAdvertisement",
+            &[object_path(&self.path)],
+        )?;
+        info!("Stopped advertisement {}", self.path);
+        self.registered = false;
+        Ok(())
+    }
+
+    fn handle_return(&mut self, m: &Message) {
+        if self.abandoned.is_some() && m.get_reply_serial() == self.abandoned {
+            // Too late to report, but a late success still has to be
+            // unregistered
+            self.abandoned = None;
+            self.registered = m.msg_type() != MessageType::Error;
+            return;
+        }
+
+        let serial = match self.pending {
+            Some((serial, _, _)) => serial,
+            None => return,
+        };
+
+        if m.get_reply_serial() != Some(serial) {
+            return;
+        }
+
+        let (_, _, reply) = self.pending.take().unwrap();
+        let res = if m.msg_type() == MessageType::Error {
+            Err(format!("Failed to register advertisement: {:?}", m.get_items()).into())
+        } else {
+            info!("Started advertisement {}", self.path);
+            self.registered = true;
+            Ok(())
+        };
+
+        let _ = reply.send(res);
+    }
+
+    fn handle_call(&mut self, m: &Message) -> Message {
+        let (_, path, iface, member) = m.headers();
+        let iface = iface.unwrap_or_default();
+        let member = member.unwrap_or_default();
+
+        if path.as_ref() != Some(&self.path) {
+            return error_reply(m, "org.freedesktop.DBus.Error.UnknownObject", "Unknown object");
+        }
+
+        match (&*iface, &*member) {
+            (PROPERTIES, _) => properties_reply(m, ADVERTISEMENT_IFACE, self.props()),
+            (ADVERTISEMENT_IFACE, "Release") => {
+                // BlueZ dropped the advertisement on its own
+                warn!("Advertisement {} released by BlueZ", self.path);
+                self.registered = false;
+                Message::new_method_return(m).unwrap()
+            }
+            _ => error_reply(m, "org.freedesktop.DBus.Error.UnknownMethod", "Unknown method"),
+        }
+    }
+
+    fn props(&self) -> Vec<(&'static str, MessageItem)> {
+        let adv = &self.adv;
+        let kind = if adv.connectable { "peripheral" } else { "broadcast" };
+        let mut props = vec![("Type", kind.into())];
+
+        if !adv.service_uuids.is_empty() {
+            let uuids: Vec<String> = adv.service_uuids
+                .iter()
+                .map(|u| u.hyphenated().to_string())
+                .collect();
+            props.push(("ServiceUUIDs", string_array(&uuids)));
+        }
+
+        if !adv.manufacturer_data.is_empty() {
+            let entries = adv.manufacturer_data
+                .iter()
+                .map(|&(id, ref data)| {
+                    MessageItem::DictEntry(Box::new(id.into()), Box::new(variant(byte_array(data))))
+                })
+                .collect();
+            props.push(("ManufacturerData", MessageItem::Array(entries, Cow::Borrowed("{qv}"))));
+        }
+
+        if !adv.service_data.is_empty() {
+            let entries = adv.service_data
+                .iter()
+                .map(|(uuid, data)| {
+                    MessageItem::DictEntry(
+                        Box::new(uuid.hyphenated().to_string().into()),
+                        Box::new(variant(byte_array(data))),
+                    )
+                })
+                .collect();
+            props.push(("ServiceData", MessageItem::Array(entries, Cow::Borrowed("{sv}"))));
+        }
+
+        if let Some(ref name) = adv.local_name {
+            props.push(("LocalName", name.clone().into()));
+        }
+
+        if let Some(appearance) = adv.appearance {
+            props.push(("Appearance", appearance.into()));
+        }
+
+        if adv.include_tx_power {
+            props.push(("Includes", string_array(&["tx-power"])));
+        }
+
+        if let Some((min, max)) = adv.interval {
+            props.push(("MinInterval", (min.num_milliseconds() as u32).into()));
+            props.push(("MaxInterval", (max.num_milliseconds() as u32).into()));
+        }
+
+        props
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+
+    use uuid::Uuid;
+
+    use Duration;
+
+    fn props(adv: Advertisement) -> Vec<(&'static str, MessageItem)> {
+        let (_tx, rx) = channel();
+        AdvertiserData::new(adv, OldDuration::from_secs(1), rx).props()
+    }
+
+    fn prop<'a>(props: &'a [(&'static str, MessageItem)], name: &str) -> Option<&'a MessageItem> {
+        props.iter().find(|p| p.0 == name).map(|p| &p.1)
+    }
+
+    #[test]
+    fn empty_advertisement() {
+        assert_eq!(props(Advertisement::new()), [("Type", "peripheral".into())]);
+        assert_eq!(
+            props(Advertisement::new().connectable(false)),
+            [("Type", "broadcast".into())]
+        );
+    }
+
+    #[test]
+    fn advertised_data() {
+        let svc = Uuid::from_str("0000180f-0000-1000-8000-00805f9b34fb").unwrap();
+        let adv = Advertisement::new()
+            .local_name("sensor")
+            .service_uuid(svc)
+            .manufacturer_data(0x0059, &[1, 2])
+            .manufacturer_data(0xffff, &[])
+            .service_data(svc, &[0x64])
+            .appearance(0x0540)
+            .include_tx_power(true)
+            .interval(Duration::milliseconds(100), Duration::milliseconds(200));
+        let props = props(adv);
+
+        assert_eq!(prop(&props, "LocalName"), Some(&MessageItem::Str("sensor".into())));
+        assert_eq!(
+            prop(&props, "ServiceUUIDs"),
+            Some(&string_array(&["0000180f-0000-1000-8000-00805f9b34fb"]))
+        );
+        assert_eq!(
+            prop(&props, "ManufacturerData"),
+            Some(&MessageItem::Array(
+                vec![
+                    MessageItem::DictEntry(
+                        Box::new(MessageItem::UInt16(0x0059)),
+                        Box::new(variant(byte_array(&[1, 2]))),
+                    ),
+                    MessageItem::DictEntry(
+                        Box::new(MessageItem::UInt16(0xffff)),
+                        Box::new(variant(byte_array(&[]))),
+                    ),
+                ],
+                Cow::Borrowed("{qv}"),
+            ))
+        );
+        assert_eq!(
+            prop(&props, "ServiceData"),
+            Some(&MessageItem::Array(
+                vec![MessageItem::DictEntry(
+                    Box::new(MessageItem::Str("0000180f-0000-1000-8000-00805f9b34fb".into())),
+                    Box::new(variant(byte_array(&[0x64]))),
+                )],
+                Cow::Borrowed("{sv}"),
+            ))
+        );
+        assert_eq!(prop(&props, "Appearance"), Some(&MessageItem::UInt16(0x0540)));
+        assert_eq!(prop(&props, "Includes"), Some(&string_array(&["tx-power"])));
+        assert_eq!(prop(&props, "MinInterval"), Some(&MessageItem::UInt32(100)));
+        assert_eq!(prop(&props, "MaxInterval"), Some(&MessageItem::UInt32(200)));
+    }
+}
@@ -0,0 +1,211 @@
+//! Helpers for talking to BlueZ directly over D-Bus, for the parts of the
+//! API that blurz does not cover, such as exporting local objects.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use blurz::BluetoothAdapter;
+use dbus::{BusType, Connection, Message, MessageItem, Path};
+
+use errors::*;
+
+pub const BLUEZ: &str = "org.bluez";
+pub const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+pub const OBJECT_MANAGER: &str = "org.freedesktop.DBus.ObjectManager";
+
+/// How long to wait for BlueZ to answer a method call, in milliseconds
+pub const TIMEOUT_MS: i32 = 5000;
+
+/// Error libdbus returns once the bus has closed a connection
+const DISCONNECTED: &str = "org.freedesktop.DBus.Error.Disconnected";
+
+thread_local! {
+    /// Connections can't be moved between threads, so every thread making
+    /// one-off calls keeps its own, opened on first use
+    static SYSTEM_BUS: RefCell<Option<Connection>> = const { RefCell::new(None) };
+}
+
+/// Object path of the default adapter, e.g. `/org/bluez/hci0`
+pub fn adapter_path() -> Result<String> {
+    let adapter = BluetoothAdapter::init().map_err(|e| e.to_string())?;
+    Ok(adapter.get_id())
+}
+
+/// Build a method call to a BlueZ object
+pub fn method_call(path: &str, iface: &str, method: &str, args: &[MessageItem]) -> Result<Message> {
+    let mut m = Message::new_method_call(BLUEZ, path, iface, method)?;
+    m.append_items(args);
+    Ok(m)
+}
+
+/// Call a method on a BlueZ object, and wait for the reply
+pub fn call(
+    conn: &Connection,
+    path: &str,
+    iface: &str,
+    method: &str,
+    args: &[MessageItem],
+) -> Result<Message> {
+    call_with_timeout(conn, path, iface, method, args, TIMEOUT_MS)
+}
+
+/// Like `call`, for methods that may take longer than usual, such as pairing
+pub fn call_with_timeout(
+    conn: &Connection,
+    path: &str,
+    iface: &str,
+    method: &str,
+    args: &[MessageItem],
+    timeout_ms: i32,
+) -> Result<Message> {
+    let m = method_call(path, iface, method, args)?;
+    conn.send_with_reply_and_block(m, timeout_ms)
+        .map_err(|e| e.to_string().into())
+}
+
+/// Like `call`, on this thread's shared system bus connection rather than
+/// one opened by the caller
+pub fn system_call(path: &str, iface: &str, method: &str, args: &[MessageItem]) -> Result<Message> {
+    system_call_with_timeout(path, iface, method, args, TIMEOUT_MS)
+}
+
+/// Like `call_with_timeout`, on this thread's shared system bus connection.
+/// The connection is opened again on the next call if the bus closed it
+pub fn system_call_with_timeout(
+    path: &str,
+    iface: &str,
+    method: &str,
+    args: &[MessageItem],
+    timeout_ms: i32,
+) -> Result<Message> {
+    let m = method_call(path, iface, method, args)?;
+
+    SYSTEM_BUS.with(|bus| {
+        let mut bus = bus.borrow_mut();
+        if bus.is_none() {
+            *bus = Some(Connection::get_private(BusType::System).map_err(|e| e.to_string())?);
+        }
+
+        let reply = bus.as_ref().unwrap().send_with_reply_and_block(m, timeout_ms);
+        reply.map_err(|e| {
+            if e.name() == Some(DISCONNECTED) {
+                *bus = None;
+            }
+            e.to_string().into()
+        })
+    })
+}
+
+pub fn object_path(path: &str) -> MessageItem {
+    MessageItem::ObjectPath(Path::new(path.to_string()).unwrap())
+}
+
+pub fn variant<T: Into<MessageItem>>(item: T) -> MessageItem {
+    MessageItem::Variant(Box::new(item.into()))
+}
+
+pub fn byte_array(bytes: &[u8]) -> MessageItem {
+    MessageItem::Array(
+        bytes.iter().map(|b| MessageItem::Byte(*b)).collect(),
+        Cow::Borrowed("y"),
+    )
+}
+
+pub fn string_array<S: AsRef<str>>(strs: &[S]) -> MessageItem {
+    MessageItem::Array(
+        strs.iter().map(|s| MessageItem::Str(s.as_ref().to_string())).collect(),
+        Cow::Borrowed("s"),
+    )
+}
+
+pub fn path_array<S: AsRef<str>>(paths: &[S]) -> MessageItem {
+    MessageItem::Array(
+        paths.iter().map(|p| object_path(p.as_ref())).collect(),
+        Cow::Borrowed("o"),
+    )
+}
+
+/// Build an `a{sv}` dictionary, wrapping each value in a variant
+pub fn prop_dict(props: Vec<(&str, MessageItem)>) -> MessageItem {
+    MessageItem::Array(
+        props
+            .into_iter()
+            .map(|(k, v)| MessageItem::DictEntry(Box::new(k.into()), Box::new(variant(v))))
+            .collect(),
+        Cow::Borrowed("{sv}"),
+    )
+}
+
+/// Extract a byte array, looking through any variant wrapping it
+pub fn bytes_from_item(item: &MessageItem) -> Option<Vec<u8>> {
+    match *item {
+        MessageItem::Variant(ref inner) => bytes_from_item(inner),
+        MessageItem::Array(ref items, _) => items.iter().map(|i| i.inner::<u8>().ok()).collect(),
+        _ => None,
+    }
+}
+
+/// Look up a key in an `a{sv}` dictionary, returning the unwrapped value
+pub fn dict_get<'a>(dict: &'a MessageItem, key: &str) -> Option<&'a MessageItem> {
+    let entries: &[MessageItem] = dict.inner().ok()?;
+    for entry in entries {
+        if let MessageItem::DictEntry(ref k, ref v) = *entry {
+            if k.inner::<&str>().ok() == Some(key) {
+                return match **v {
+                    MessageItem::Variant(ref inner) => Some(inner),
+                    ref other => Some(other),
+                };
+            }
+        }
+    }
+    None
+}
+
+/// Answer a call on `org.freedesktop.DBus.Properties` for a local object
+/// exposing a single interface
+pub fn properties_reply(m: &Message, iface: &str, props: Vec<(&str, MessageItem)>) -> Message {
+    match property_lookup(m, iface, props) {
+        Ok(item) => Message::new_method_return(m).unwrap().append(item),
+        Err((name, msg)) => error_reply(m, name, msg),
+    }
+}
+
+/// What a `Get` or `GetAll` call is answered with, or the name and message
+/// of the error it fails with
+pub fn property_lookup(
+    m: &Message,
+    iface: &str,
+    props: Vec<(&str, MessageItem)>,
+) -> ::std::result::Result<MessageItem, (&'static str, &'static str)> {
+    let args = m.get_items();
+    let req_iface = args.first().and_then(|i| i.inner::<&str>().ok());
+
+    if req_iface != Some(iface) {
+        return Err(("org.freedesktop.DBus.Error.InvalidArgs", "No such interface"));
+    }
+
+    match m.headers().3.as_deref() {
+        Some("GetAll") => Ok(prop_dict(props)),
+        Some("Get") => {
+            let name = args.get(1).and_then(|i| i.inner::<&str>().ok());
+            match props.into_iter().find(|&(k, _)| Some(k) == name) {
+                Some((_, v)) => Ok(variant(v)),
+                None => Err(("org.freedesktop.DBus.Error.InvalidArgs", "No such property")),
+            }
+        }
+        _ => Err(("org.freedesktop.DBus.Error.NotSupported", "Read only")),
+    }
+}
+
+/// Emit `PropertiesChanged` for a local object
+pub fn properties_changed(path: &str, iface: &str, props: Vec<(&str, MessageItem)>) -> Message {
+    Message::new_signal(path, PROPERTIES, "PropertiesChanged")
+        .unwrap()
+        .append(iface)
+        .append(prop_dict(props))
+        .append(MessageItem::Array(vec![], Cow::Borrowed("s")))
+}
+
+pub fn error_reply(m: &Message, name: &str, msg: &str) -> Message {
+    Message::new_error(m, name, msg).unwrap()
+}
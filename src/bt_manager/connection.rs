@@ -1,27 +1,74 @@
-use std::sync::mpsc::{Receiver, Sender};
-use std::time::Instant;
-use std::time::Duration as OldDuration;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::{Duration as OldDuration, Instant};
 
 use Duration;
+use BtMacAddress;
 use bt_manager::Connectable;
+use bt_manager::signals::SharedCache;
+use bt_manager::demand::Demand;
+use connection::{ConnectMode, ConnectionEvent, GiveUpAction, Priority, ReconnectPolicy};
+use metrics::Metrics;
+use recording::Recorder;
 
 use errors::*;
 
+/// Connection history of a device
+#[derive(Clone, Default)]
+pub struct ConnStats {
+    pub connected_since: Option<Instant>,
+    /// Time spent connected, not counting the current connection
+    pub uptime: OldDuration,
+    pub connections: u32,
+}
+
+pub type SharedStats = Arc<RwLock<HashMap<BtMacAddress, ConnStats>>>;
+
 pub struct ConnectionDb {
     pub incoming: Receiver<Connectable>,
-    pub outgoing: Sender<(String, bool)>, // TODO better types
-
-    // TODO sender for connect events
-    // TODO sender for disconnect
     pub db: Vec<Connectable>,
     pub connect_interval: Duration,
+    pub cache: SharedCache,
+
+    /// How long a device may go unconnected before it is reported missing
+    pub missing_threshold: Duration,
+    pub default_policy: ReconnectPolicy,
+    pub rx_policies: Receiver<(BtMacAddress, ReconnectPolicy)>,
+    pub policies: HashMap<BtMacAddress, ReconnectPolicy>,
+
+    pub rx_event_subs: Receiver<Sender<ConnectionEvent>>,
+    pub event_subs: Vec<Sender<ConnectionEvent>>,
+
+    pub mode: ConnectMode,
+
+    /// Most devices to keep connected at once, if limited
+    pub max_connections: Option<usize>,
+    /// How long a device without a pinned slot keeps it, while others wait
+    pub rotation_interval: Duration,
+    pub rx_priorities: Receiver<(BtMacAddress, Priority)>,
+    pub priorities: HashMap<BtMacAddress, Priority>,
+    pub demand: Demand,
+
+    pub stats: SharedStats,
+
+    /// Devices handed back to discovery after giving up on them
+    pub tx_dropped: Sender<(BtMacAddress, GiveUpAction)>,
+
+    pub metrics: Metrics,
+    pub recorder: Recorder,
 }
 
 pub fn connect_task(data: &mut ConnectionDb) -> Option<Duration> {
     trace!("Connect Tick...");
+    let _timer = data.metrics.timer("connection");
 
     match data.manage_connection() {
-        Ok(()) => Some(data.connect_interval),
+        Ok(true) => Some(data.connect_interval),
+        Ok(false) => {
+            info!("Shutting down connection manager");
+            None
+        }
         Err(e) => {
             // An error has occurred, bail
             error!("Error, connect_task bailing, {:?}", e);
@@ -31,18 +78,99 @@ pub fn connect_task(data: &mut ConnectionDb) -> Option<Duration> {
 }
 
 impl ConnectionDb {
-    pub fn manage_connection(&mut self) -> Result<()> {
+    /// Check on every managed device, returns false once the handle is gone
+    pub fn manage_connection(&mut self) -> Result<bool> {
         while let Ok(new_dev) = self.incoming.try_recv() {
+            self.stats
+                .write()
+                .unwrap()
+                .entry(new_dev.mac.clone())
+                .or_default();
             self.db.push(new_dev);
         }
 
-        for man_dev in self.db.iter_mut() {
-            let too_idle = man_dev.last_connected.elapsed() > OldDuration::from_secs(30);
+        loop {
+            match self.rx_policies.try_recv() {
+                Ok((mac, policy)) => {
+                    // Let the device try again under its new policy
+                    for dev in self.db.iter_mut().filter(|d| d.mac == mac) {
+                        dev.gave_up = false;
+                        dev.attempts = 0;
+                        dev.backoff = Duration::zero();
+                    }
+                    self.policies.insert(mac, policy);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            }
+        }
+
+        while let Ok((mac, priority)) = self.rx_priorities.try_recv() {
+            self.priorities.insert(mac, priority);
+        }
+
+        while let Ok(sub) = self.rx_event_subs.try_recv() {
+            self.event_subs.push(sub);
+        }
+
+        let missing_threshold = self.missing_threshold
+            .to_std()
+            .chain_err(|| "bad missing threshold")?;
+
+        let idle_timeout = match self.mode {
+            ConnectMode::Persistent => None,
+            ConnectMode::OnDemand { idle_timeout } => {
+                Some(idle_timeout.to_std().chain_err(|| "bad idle timeout")?)
+            }
+        };
 
-            let is_connected = if man_dev.bluez_handle.is_connected().map_err(|e| e.to_string())? {
-                trace!("{} is connected :)", man_dev.mac_addr);
+        // Slots and connect attempts are decided against the same instant
+        let now = Instant::now();
+
+        // Whether each device is connected, and whether it is needed
+        let states: Vec<(bool, bool)> = self.db
+            .iter()
+            .map(|d| {
+                let dev_path = d.bluez_handle.get_id();
+                let idle = idle_timeout.is_some_and(|t| !self.demand.wanted_within(&dev_path, t));
+                (self.cache.read().unwrap().is_connected(&dev_path), idle)
+            })
+            .collect();
+        let slots = self.assign_slots(&states, now)?;
+
+        let mut dropped = vec![];
+
+        for (i, man_dev) in self.db.iter_mut().enumerate() {
+            let mut events = vec![];
+            let (is_connected, idle) = states[i];
+
+            if is_connected {
+                trace!("{:?} is connected :)", man_dev.mac);
                 man_dev.last_connected = Instant::now();
-                true
+
+                if !man_dev.connected {
+                    info!("{:?} connected", man_dev.mac);
+                    events.push(ConnectionEvent::Connected(man_dev.mac.clone()));
+                    man_dev.connected_since = Instant::now();
+
+                    let mut stats = self.stats.write().unwrap();
+                    let stats = stats.entry(man_dev.mac.clone()).or_default();
+                    stats.connected_since = Some(man_dev.connected_since);
+                    stats.connections += 1;
+                }
+
+                man_dev.connected = true;
+                man_dev.missing = false;
+                man_dev.gave_up = false;
+                man_dev.attempts = 0;
+                man_dev.backoff = Duration::zero();
+
+                if idle || slots[i] == Slot::Evicted {
+                    info!("{:?} is idle or out of turn, disconnecting", man_dev.mac);
+                    if let Err(e) = man_dev.bluez_handle.disconnect() {
+                        error!("Failed to disconnect, {:?}", e);
+                    }
+                }
 
             // TODO: Pair with devices? Might be necessary for some behavior
             // if !man_dev.bluez_handle.is_paired()? {
@@ -50,19 +178,273 @@ impl ConnectionDb {
             //     man_dev.bluez_handle.pair()?;
             // }
             } else {
-                trace!("{} isn't connected :(", man_dev.mac_addr);
-                if too_idle {
-                    warn!("Device {} is missing!", man_dev.mac_addr);
+                trace!("{:?} isn't connected :(", man_dev.mac);
+
+                if man_dev.connected {
+                    info!("{:?} disconnected", man_dev.mac);
+                    events.push(ConnectionEvent::Disconnected(man_dev.mac.clone()));
+                    man_dev.connected = false;
+                    self.metrics.record(|m| m.disconnects += 1);
+
+                    if let Some(stats) = self.stats.write().unwrap().get_mut(&man_dev.mac) {
+                        if let Some(since) = stats.connected_since.take() {
+                            stats.uptime += since.elapsed();
+                        }
+                    }
+                }
+
+                let idle = idle || slots[i] == Slot::Queued;
+                if idle {
+                    // Nothing needs the device, or it is waiting for a free
+                    // connection slot, so it isn't missing either
+                    man_dev.last_connected = Instant::now();
+                    man_dev.missing = false;
+                } else if !man_dev.missing && man_dev.last_connected.elapsed() > missing_threshold {
+                    warn!("Device {:?} is missing!", man_dev.mac);
+                    events.push(ConnectionEvent::Missing(man_dev.mac.clone()));
+                    man_dev.missing = true;
+                }
+
+                let policy = self.policies
+                    .get(&man_dev.mac)
+                    .unwrap_or(&self.default_policy);
+
+                if !idle && !man_dev.gave_up && now >= man_dev.next_attempt {
+                    if policy.max_attempts.is_some_and(|max| man_dev.attempts >= max) {
+                        warn!(
+                            "Giving up on {:?} after {} attempts, {:?}",
+                            man_dev.mac, man_dev.attempts, policy.give_up
+                        );
+                        events.push(ConnectionEvent::GaveUp(man_dev.mac.clone()));
+                        man_dev.gave_up = true;
+
+                        if policy.give_up != GiveUpAction::EmitEvent {
+                            dropped.push((man_dev.mac.clone(), policy.give_up));
+                        }
+                    } else {
+                        let connected = man_dev.connect();
+                        self.metrics.record(|m| {
+                            m.connect_attempts += 1;
+                            if connected {
+                                m.connect_successes += 1;
+                            } else {
+                                m.connect_failures += 1;
+                            }
+                        });
+                        if !connected {
+                            self.metrics.error("connect");
+                        }
+                        man_dev.backoff = policy.next_backoff(man_dev.backoff);
+                        man_dev.next_attempt = Instant::now()
+                            + man_dev.backoff.to_std().chain_err(|| "bad backoff")?;
+                    }
                 }
-                man_dev.connect();
-                false
-            };
+            }
 
-            self.outgoing
-                .send((man_dev.mac_addr.clone(), is_connected))
+            for ev in events {
+                self.recorder.connection(&ev);
+                self.event_subs.retain(|tx| tx.send(ev.clone()).is_ok());
+            }
+        }
+
+        for (mac, action) in dropped {
+            self.db.retain(|d| d.mac != mac);
+            self.tx_dropped
+                .send((mac, action))
                 .chain_err(|| "failed to send!")?;
         }
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Decide which devices may use a connection slot this tick. Pinned
+    /// devices always get one, the rest take turns, by priority and then by
+    /// how long ago they were last connected. A device only gets a slot
+    /// that is free, so one taken from another device is granted once that
+    /// device has disconnected
+    fn assign_slots(&self, states: &[(bool, bool)], now: Instant) -> Result<Vec<Slot>> {
+        let mut slots = vec![Slot::Granted; self.db.len()];
+
+        let max = match self.max_connections {
+            Some(max) => max,
+            None => return Ok(slots),
+        };
+        let turn = self.rotation_interval.to_std().chain_err(|| "bad rotation interval")?;
+        let priority = |i: usize| self.priorities.get(&self.db[i].mac).cloned().unwrap_or_default();
+
+        let in_use: Vec<usize> = (0..self.db.len())
+            .filter(|&i| states[i] == (true, false))
+            .collect();
+
+        let mut waiting: Vec<usize> = (0..self.db.len())
+            .filter(|&i| {
+                let dev = &self.db[i];
+                states[i] == (false, false) && !dev.gave_up && now >= dev.next_attempt
+            })
+            .collect();
+        waiting.sort_by_key(|&i| (priority(i), self.db[i].connected_since));
+
+        // Least important, longest connected first
+        let mut evictable: Vec<usize> = in_use
+            .iter()
+            .cloned()
+            .filter(|&i| priority(i) != Priority::Pinned)
+            .collect();
+        evictable.sort_by_key(|&i| (::std::cmp::Reverse(priority(i)), self.db[i].connected_since));
+
+        let mut free = max.saturating_sub(in_use.len());
+
+        for i in waiting {
+            if free > 0 {
+                free -= 1;
+                continue;
+            }
+
+            // Only take a slot from a device that has had its turn, or
+            // from a less important one when a pinned device is waiting
+            let pos = evictable.iter().position(|&e| {
+                priority(e) >= priority(i)
+                    && (now.saturating_duration_since(self.db[e].connected_since) > turn
+                        || priority(i) == Priority::Pinned)
+            });
+
+            if let Some(pos) = pos {
+                let e = evictable.remove(pos);
+                debug!("{:?} yields its slot to {:?}", self.db[e].mac, self.db[i].mac);
+                slots[e] = Slot::Evicted;
+            }
+
+            trace!("{:?} is waiting for a connection slot", self.db[i].mac);
+            slots[i] = Slot::Queued;
+        }
+
+        Ok(slots)
+    }
+}
+
+/// Whether a device may hold a connection this tick
+#[derive(Clone, Copy, PartialEq)]
+enum Slot {
+    Granted,
+    /// Waiting for another device to give up its slot
+    Queued,
+    /// Must disconnect to make room for a waiting device
+    Evicted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+    use blurz::BluetoothDevice;
+
+    const CONNECTED: (bool, bool) = (true, false);
+    const WAITING: (bool, bool) = (false, false);
+
+    fn db(devices: usize, max: usize) -> ConnectionDb {
+        let (_, incoming) = channel();
+        let (_, rx_policies) = channel();
+        let (_, rx_event_subs) = channel();
+        let (_, rx_priorities) = channel();
+        let (tx_dropped, _) = channel();
+        let start = Instant::now();
+
+        let db = (0..devices)
+            .map(|i| {
+                let mac = BtMacAddress::from_str(&format!("CF:75:CE:86:6D:{:02X}", i)).unwrap();
+                let mut dev = Connectable::new(mac, BluetoothDevice::new(format!("/dev_{}", i)));
+                // Earlier devices were connected longer ago
+                dev.connected_since = start + OldDuration::from_secs(i as u64);
+                dev.next_attempt = start;
+                dev
+            })
+            .collect();
+
+        ConnectionDb {
+            incoming,
+            db,
+            connect_interval: Duration::seconds(1),
+            cache: SharedCache::default(),
+            missing_threshold: Duration::seconds(60),
+            default_policy: ReconnectPolicy::default(),
+            rx_policies,
+            policies: HashMap::new(),
+            rx_event_subs,
+            event_subs: vec![],
+            mode: ConnectMode::Persistent,
+            max_connections: Some(max),
+            rotation_interval: Duration::seconds(10),
+            rx_priorities,
+            priorities: HashMap::new(),
+            demand: Demand::default(),
+            stats: SharedStats::default(),
+            tx_dropped,
+            metrics: Metrics::default(),
+            recorder: Recorder::default(),
+        }
+    }
+
+    fn later(secs: u64) -> Instant {
+        Instant::now() + OldDuration::from_secs(secs)
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut db = db(3, 0);
+        db.max_connections = None;
+
+        let slots = db.assign_slots(&[WAITING; 3], later(0)).unwrap();
+        assert!(slots.iter().all(|s| *s == Slot::Granted));
+    }
+
+    #[test]
+    fn free_slots_go_in_turn() {
+        let db = db(3, 2);
+
+        let slots = db.assign_slots(&[WAITING; 3], later(0)).unwrap();
+        assert!(slots == [Slot::Granted, Slot::Granted, Slot::Queued]);
+    }
+
+    #[test]
+    fn waits_for_a_turn_to_end() {
+        let mut db = db(2, 1);
+        // The waiting device's last turn started before the current one
+        db.db[0].connected_since = db.db[1].connected_since + OldDuration::from_secs(1);
+        let states = [CONNECTED, WAITING];
+
+        let slots = db.assign_slots(&states, later(5)).unwrap();
+        assert!(slots == [Slot::Granted, Slot::Queued]);
+
+        // The turn is over, but the slot is only granted once it is free
+        let slots = db.assign_slots(&states, later(13)).unwrap();
+        assert!(slots == [Slot::Evicted, Slot::Queued]);
+
+        let slots = db.assign_slots(&[WAITING, WAITING], later(14)).unwrap();
+        assert!(slots == [Slot::Queued, Slot::Granted]);
+    }
+
+    #[test]
+    fn pinned_devices_evict_at_once() {
+        let mut db = db(3, 2);
+        let pinned = db.db[2].mac.clone();
+        db.priorities.insert(pinned, Priority::Pinned);
+        let low = db.db[1].mac.clone();
+        db.priorities.insert(low, Priority::Low);
+
+        let slots = db.assign_slots(&[CONNECTED, CONNECTED, WAITING], later(1)).unwrap();
+        assert!(slots == [Slot::Granted, Slot::Evicted, Slot::Queued]);
+
+        let slots = db.assign_slots(&[CONNECTED, WAITING, WAITING], later(2)).unwrap();
+        assert!(slots == [Slot::Granted, Slot::Queued, Slot::Granted]);
+    }
+
+    #[test]
+    fn backing_off_devices_are_not_waiting() {
+        let mut db = db(2, 1);
+        db.db[1].next_attempt = later(30);
+
+        let slots = db.assign_slots(&[CONNECTED, WAITING], later(20)).unwrap();
+        assert!(slots == [Slot::Granted, Slot::Granted]);
     }
 }
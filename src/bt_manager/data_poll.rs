@@ -1,45 +1,105 @@
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
 
 use Duration;
 use blurz::BluetoothGATTCharacteristic;
+use bt_manager::demand::{device_path, Demand};
+use bt_manager::signals::SharedCache;
+use metrics::Metrics;
+use recording::Recorder;
+use capture::Capture;
 
 use errors::*;
 
 pub struct DataDb {
     pub poll_interval: Duration,
+    /// How soon to try again when a device wasn't connected for its poll
+    pub retry_interval: Duration,
     pub poll_rx: Receiver<(BluetoothGATTCharacteristic, Sender<Box<[u8]>>)>,
-    pub polls: Vec<(BluetoothGATTCharacteristic, Sender<Box<[u8]>>)>,
+    pub polls: Vec<Poll>,
+    pub cache: SharedCache,
+    pub demand: Demand,
+    pub metrics: Metrics,
+    pub recorder: Recorder,
+    pub capture: Capture,
+}
+
+pub struct Poll {
+    pub chrc: BluetoothGATTCharacteristic,
+    pub tx: Sender<Box<[u8]>>,
+    pub next_read: Instant,
 }
 
 pub fn data_poll_task(data: &mut DataDb) -> Option<Duration> {
     trace!("DataPoll Tick...");
+    let _timer = data.metrics.timer("data_poll");
 
-    if let Ok(_) = data.poll_data() {
-        Some(data.poll_interval)
-    } else {
-        // An error has occurred, bail
-        error!("Error, data_poll_task bailing");
-        None
+    match data.poll_data() {
+        Ok(false) => Some(data.poll_interval),
+        Ok(true) => Some(data.retry_interval),
+        Err(_) => {
+            // An error has occurred, bail
+            error!("Error, data_poll_task bailing");
+            None
+        }
     }
 }
 
 impl DataDb {
-    pub fn poll_data(&mut self) -> Result<()> {
-        while let Ok(new_poll) = self.poll_rx.try_recv() {
-            self.polls.push(new_poll);
+    /// Read every poll that is due, returns true if any had to be put off
+    /// until their device is connected
+    pub fn poll_data(&mut self) -> Result<bool> {
+        while let Ok((chrc, tx)) = self.poll_rx.try_recv() {
+            self.polls.push(Poll {
+                chrc,
+                tx,
+                next_read: Instant::now(),
+            });
         }
 
-        for &(ref blurz_chr, ref txer) in self.polls.iter() {
-            match blurz_chr.read_value() {
+        let interval = self.poll_interval.to_std().chain_err(|| "bad poll interval")?;
+        let mut waiting = false;
+        // Polls whose receiver has been dropped
+        let mut gone = vec![];
+
+        for (i, poll) in self.polls.iter_mut().enumerate() {
+            if Instant::now() < poll.next_read {
+                continue;
+            }
+
+            let path = poll.chrc.get_id();
+            self.demand.want(&path);
+
+            if !self.cache.read().unwrap().is_connected(device_path(&path)) {
+                waiting = true;
+                continue;
+            }
+
+            match poll.chrc.read_value() {
                 Ok(new_data) => {
-                    txer.send(new_data.into_boxed_slice()).unwrap();
+                    self.metrics.endpoint(&path, |m| {
+                        m.reads += 1;
+                        m.bytes_read += new_data.len() as u64;
+                    });
+                    self.recorder.poll(&path, &new_data);
+                    self.capture.read(&path, &new_data);
+                    if poll.tx.send(new_data.into_boxed_slice()).is_err() {
+                        debug!("Nobody is polling {} anymore", path);
+                        gone.push(i);
+                    }
+                    poll.next_read = Instant::now() + interval;
                 }
                 Err(e) => {
                     error!("Failed to read, {:?}", e);
+                    self.metrics.error("read");
                 }
             }
         }
 
-        Ok(())
+        for i in gone.into_iter().rev() {
+            self.polls.remove(i);
+        }
+
+        Ok(waiting)
     }
 }
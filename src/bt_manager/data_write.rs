@@ -1,18 +1,45 @@
+use std::fs::File;
+use std::io::Write as IoWrite;
 use std::sync::mpsc::Receiver;
 
 use Duration;
 use blurz::BluetoothGATTCharacteristic;
+use bt_manager::ResolvedWrite;
+use bt_manager::acquire::acquire;
+use bt_manager::bus::*;
+use bt_manager::demand::{device_path, Demand};
+use bt_manager::signals::{SharedCache, CHRC_IFACE};
+use writes::{WriteKind, WriteOptions, DEFAULT_MTU};
+use metrics::Metrics;
+use recording::Recorder;
+use capture::Capture;
 
 use errors::*;
 
 pub struct DataWDb {
     pub write_interval: Duration,
-    pub write_rx: Receiver<(BluetoothGATTCharacteristic, Receiver<Box<[u8]>>)>,
-    pub writes: Vec<(BluetoothGATTCharacteristic, Receiver<Box<[u8]>>)>,
+    pub write_rx: Receiver<ResolvedWrite>,
+    pub writes: Vec<Write>,
+    pub cache: SharedCache,
+    pub demand: Demand,
+    pub metrics: Metrics,
+    pub recorder: Recorder,
+    pub capture: Capture,
+}
+
+pub struct Write {
+    pub chrc: BluetoothGATTCharacteristic,
+    pub rx: Receiver<Box<[u8]>>,
+    pub options: WriteOptions,
+    /// A message held back until its device is connected
+    pub pending: Option<Box<[u8]>>,
+    /// Socket from `AcquireWrite`, and its MTU, when streaming
+    pub socket: Option<(File, u16)>,
 }
 
 pub fn data_write_task(data: &mut DataWDb) -> Option<Duration> {
     trace!("DataWrite Tick...");
+    let _timer = data.metrics.timer("data_write");
 
     if let Ok(_) = data.write_data() {
         Some(data.write_interval)
@@ -25,17 +52,117 @@ pub fn data_write_task(data: &mut DataWDb) -> Option<Duration> {
 
 impl DataWDb {
     pub fn write_data(&mut self) -> Result<()> {
-        while let Ok(new_write) = self.write_rx.try_recv() {
-            self.writes.push(new_write);
+        while let Ok((chrc, rx, options)) = self.write_rx.try_recv() {
+            self.writes.push(Write {
+                chrc,
+                rx,
+                options,
+                pending: None,
+                socket: None,
+            });
         }
 
-        for &(ref blurz_chr, ref rxer) in self.writes.iter() {
-            if let Ok(msg) = rxer.try_recv() {
-                blurz_chr.write_value(msg.to_vec())
-                    .map_err(|e| e.to_string())?;
+        for write in self.writes.iter_mut() {
+            if write.pending.is_none() {
+                write.pending = write.rx.try_recv().ok();
+            }
+
+            let msg = match write.pending.take() {
+                Some(msg) => msg,
+                None => continue,
+            };
+
+            let path = write.chrc.get_id();
+            self.demand.want(&path);
+
+            let cache = self.cache.read().unwrap();
+            if !cache.is_connected(device_path(&path)) {
+                write.pending = Some(msg);
+                continue;
+            }
+
+            if write.options.streaming {
+                if write.socket.is_none() {
+                    match acquire(&path, "AcquireWrite") {
+                        Ok(sock) => write.socket = Some(sock),
+                        Err(e) => {
+                            warn!("Can't stream to {}, falling back to WriteValue, {:?}", path, e);
+                            write.options.streaming = false;
+                        }
+                    }
+                }
+
+                if let Some((ref mut sock, mtu)) = write.socket {
+                    if let Err(e) = write_socket(sock, &write.options, &msg, mtu) {
+                        // Most likely a disconnect, acquire again once reconnected
+                        debug!("Write socket closed, {:?}", e);
+                        write.socket = None;
+                        write.pending = Some(msg);
+                    } else {
+                        self.metrics.endpoint(&path, |m| {
+                            m.writes += 1;
+                            m.bytes_written += msg.len() as u64;
+                        });
+                        self.recorder.write(&path, &msg);
+                        self.capture.write(&path, &msg, false);
+                    }
+                    continue;
+                }
+            }
+
+            let written = if write.options == WriteOptions::default() {
+                write.chrc.write_value(msg.to_vec())
+                    .map_err(|e| e.to_string().into())
+            } else {
+                let mtu = cache.mtu(device_path(&path)).unwrap_or(DEFAULT_MTU);
+                write_with_options(&path, &write.options, &msg, mtu)
+            };
+
+            if written.is_err() {
+                self.metrics.error("write");
             }
+            written?;
+
+            self.metrics.endpoint(&path, |m| {
+                m.writes += 1;
+                m.bytes_written += msg.len() as u64;
+            });
+            self.recorder.write(&path, &msg);
+            self.capture.write(&path, &msg, write.options.kind != WriteKind::Command);
         }
 
         Ok(())
     }
 }
+
+fn write_socket(sock: &mut File, options: &WriteOptions, msg: &[u8], mtu: u16) -> Result<()> {
+    // Every write on the socket is sent as a single packet
+    let options = WriteOptions {
+        chunked: true,
+        ..options.clone()
+    };
+
+    for chunk in options.fragment(msg, mtu) {
+        sock.write_all(&chunk).chain_err(|| "failed to write to socket")?;
+    }
+
+    Ok(())
+}
+
+/// Write through BlueZ's `WriteValue` options, splitting the payload first
+/// if asked to
+pub fn write_with_options(path: &str, options: &WriteOptions, msg: &[u8], mtu: u16) -> Result<()> {
+    let kind = match options.kind {
+        WriteKind::Default => None,
+        WriteKind::Request => Some("request"),
+        WriteKind::Command => Some("command"),
+        WriteKind::Reliable => Some("reliable"),
+    };
+
+    for chunk in options.fragment(msg, mtu) {
+        let opts = kind.map(|k| vec![("type", k.into())]).unwrap_or_default();
+        system_call(path, CHRC_IFACE, "WriteValue", &[byte_array(&chunk), prop_dict(opts)])?;
+    }
+
+    Ok(())
+}
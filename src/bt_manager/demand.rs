@@ -0,0 +1,37 @@
+//! Tracks which devices have work waiting on them, so devices can be
+//! connected only while they are needed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as OldDuration, Instant};
+
+/// When each device last had work waiting on it, by device object path
+#[derive(Clone, Default)]
+pub struct Demand(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Demand {
+    /// Note that a device is needed right now
+    pub fn want(&self, dev_path: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(device_path(dev_path).to_string(), Instant::now());
+    }
+
+    /// Whether the device was needed within the last `window`
+    pub fn wanted_within(&self, dev_path: &str, window: OldDuration) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(dev_path)
+            .is_some_and(|t| t.elapsed() < window)
+    }
+}
+
+/// Object path of the device owning a service, characteristic, or descriptor
+pub fn device_path(path: &str) -> &str {
+    match path.find("/service") {
+        Some(i) => &path[..i],
+        None => path,
+    }
+}
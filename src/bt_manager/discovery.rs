@@ -1,38 +1,110 @@
 use std::collections::HashSet;
 use std::str::FromStr;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::thread;
-use std::time::Duration as OldDuration;
+use std::time::Instant;
 
 use blurz::{BluetoothAdapter, BluetoothDevice, BluetoothDiscoverySession};
 
 use Duration;
 use BtMacAddress;
+use beacon::{estimate_distance, parse_beacons, BeaconEvent};
 use bt_manager::Connectable;
+use connection::GiveUpAction;
+use bt_manager::scanner::{self, Scanner};
+use bt_manager::signals::{self, BusEvent, BusSubscription, SharedCache, DEVICE_IFACE};
+use metrics::Metrics;
+use presence::{PresenceEvent, SharedPresence};
 use errors::*;
 
 pub struct DiscoveryData {
+    /// Whitelisted devices handed to the connection manager
     pub db: HashSet<BtMacAddress>,
     pub wl: HashSet<BtMacAddress>,
     pub receiver: Receiver<BtMacAddress>,
+    /// Whitelisted Classic devices, which an LE-only scan can't find
+    pub rx_classic: Receiver<BtMacAddress>,
+    pub classic: HashSet<BtMacAddress>,
     pub sender_connect: Sender<Connectable>,
     pub sender_endpoints: Sender<(BtMacAddress, BluetoothDevice)>,
     pub scan_interval: Duration,
     pub scan_duration: Duration,
+
+    pub rx_beacon_subs: Receiver<Sender<BeaconEvent>>,
+    pub beacon_subs: Vec<Sender<BeaconEvent>>,
+
+    /// Keep discovery running instead of scanning in windows
+    pub continuous: bool,
+    pub scanner: Option<Scanner>,
+    pub tx_bus_subs: Sender<BusSubscription>,
+
+    /// Devices the connection manager has given up on
+    pub rx_dropped: Receiver<(BtMacAddress, GiveUpAction)>,
+
+    pub metrics: Metrics,
+
+    /// Every device seen while scanning, and who to tell as they come and go
+    pub presence: SharedPresence,
+    pub rx_presence_subs: Receiver<Sender<PresenceEvent>>,
+    pub presence_subs: Vec<Sender<PresenceEvent>>,
+    pub cache: SharedCache,
 }
 
 pub fn discovery_task(data: &mut DiscoveryData) -> Option<Duration> {
     trace!("Discovery Tick...");
+    let _timer = data.metrics.timer("discovery");
 
     // Process any new whitelist items
-    while let Ok(new_wl) = data.receiver.try_recv() {
-        info!("Adding {:?}", new_wl);
-        data.wl.insert(new_wl);
+    loop {
+        match data.receiver.try_recv() {
+            Ok(new_wl) => {
+                info!("Adding {:?}", new_wl);
+                data.wl.insert(new_wl);
+            }
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => {
+                // The handle is gone, stop any running scan
+                info!("Shutting down discovery");
+                data.scanner = None;
+                return None;
+            }
+        }
     }
 
-    if data.wl.len() == 0 {
-        // No whitelist items, no point in scanning
+    while let Ok(mac) = data.rx_classic.try_recv() {
+        data.classic.insert(mac);
+    }
+
+    while let Ok((mac, action)) = data.rx_dropped.try_recv() {
+        // Forgotten devices must be whitelisted again to come back
+        if action == GiveUpAction::Forget {
+            info!("Forgetting {:?}", mac);
+            data.wl.remove(&mac);
+            data.classic.remove(&mac);
+        }
+        data.db.remove(&mac);
+    }
+
+    while let Ok(sub) = data.rx_beacon_subs.try_recv() {
+        data.beacon_subs.push(sub);
+    }
+    while let Ok(sub) = data.rx_presence_subs.try_recv() {
+        data.presence_subs.push(sub);
+    }
+
+    data.connected_present();
+    let lost = data.presence.write().unwrap().expire(Instant::now());
+    data.report_presence(lost);
+
+    if data.wl.len() == 0 && data.beacon_subs.is_empty() && data.presence_subs.is_empty() {
+        // No whitelist items, beacon or presence listeners, no point in scanning
         warn!("No whitelist items, skipping scan");
+        data.scanner = None;
+        return Some(data.scan_interval);
+    }
+
+    if data.continuous {
+        data.process_scan_updates();
         return Some(data.scan_interval);
     }
 
@@ -41,6 +113,7 @@ pub fn discovery_task(data: &mut DiscoveryData) -> Option<Duration> {
         Some(data.scan_interval)
     } else {
         // An error has occurred, bail
+        data.metrics.error("discovery");
         error!("Error, discovery_task bailing");
         None
     }
@@ -59,28 +132,195 @@ impl DiscoveryData {
             if !self.db.contains(&btm) {
                 info!("Adding {:?}", btm);
                 self.db.insert(btm.clone());
+                self.metrics.record(|m| m.devices_found += 1);
 
-                // trigger a connect, and pass on for later handling
-                let mut new_dev = Connectable::new(d.clone());
-                new_dev.connect();
-                self.sender_connect.send(new_dev).unwrap();
+                // pass on for later handling, connecting is up to the
+                // connection manager's reconnect policy
+                self.sender_connect.send(Connectable::new(btm.clone(), d.clone())).unwrap();
                 self.sender_endpoints.send((btm, d)).unwrap();
             }
         }
     }
 
-    fn discover_new(&self) -> Result<Vec<BluetoothDevice>> {
+    /// Handle devices reported by the continuous scanner since the last tick,
+    /// (re)starting it if needed
+    fn process_scan_updates(&mut self) {
+        let mut paths = HashSet::new();
+        // Devices heard since the last tick, rather than only remembered
+        let mut heard = HashSet::new();
+
+        // Classic devices are only found by inquiry, which an LE filter
+        // leaves out
+        let transport = if self.classic.is_empty() {
+            scanner::LE
+        } else {
+            scanner::LE_AND_CLASSIC
+        };
+
+        let scanner_alive = match self.scanner {
+            Some(ref scanner) => {
+                while let Ok(ev) = scanner.updates.try_recv() {
+                    if ev.advertised() {
+                        heard.insert(ev.path().to_string());
+                    }
+                    match ev {
+                        BusEvent::Removed { .. } => {}
+                        _ if ev.involves(DEVICE_IFACE) => {
+                            paths.insert(ev.path().to_string());
+                        }
+                        _ => {}
+                    }
+                }
+                scanner.is_running() && scanner.transport == transport
+            }
+            None => false,
+        };
+
+        if !scanner_alive {
+            match self.scanner.take() {
+                Some(ref s) if s.transport != transport => {
+                    info!("Restarting continuous scan on the {} transport", transport);
+                }
+                Some(_) => warn!("Continuous scan stopped, restarting"),
+                None => {}
+            }
+
+            let rx = match signals::subscribe(&self.tx_bus_subs) {
+                Ok(rx) => rx,
+                Err(e) => {
+                    error!("Can't scan, {}", e);
+                    return;
+                }
+            };
+            self.scanner = Some(Scanner::start(rx, transport));
+            self.metrics.record(|m| m.discovery_runs += 1);
+
+            // Devices BlueZ already knows about won't be announced again
+            match BluetoothAdapter::init().and_then(|a| a.get_device_list()) {
+                Ok(devs) => paths.extend(devs),
+                Err(e) => error!("Failed to list devices, {:?}", e),
+            }
+        }
+
+        self.report_heard(&heard);
+
+        let mut new_devices = vec![];
+        for path in paths {
+            let device = BluetoothDevice::new(path);
+
+            match device.get_address().map(|id| BtMacAddress::from_str(&id)) {
+                Ok(Ok(ref mac)) if self.wl.contains(mac) => new_devices.push(device),
+                _ => {}
+            }
+        }
+
+        self.manage_new_devices(new_devices);
+    }
+
+    /// Report presence and beacons of the devices heard advertising. Only
+    /// these, as the advertised data of others is whatever BlueZ cached
+    fn report_heard(&mut self, heard: &HashSet<String>) {
+        for path in heard {
+            self.track_presence(path);
+
+            if !self.beacon_subs.is_empty() {
+                self.report_beacons(&BluetoothDevice::new(path.clone()));
+            }
+        }
+    }
+
+    /// Note a sighting of a device that was just heard advertising
+    fn track_presence(&mut self, dev_path: &str) {
+        let device = match self.cache.read().unwrap().scanned_device(dev_path) {
+            Some(d) => d,
+            None => return,
+        };
+
+        let appeared = self.presence.write().unwrap().saw(&device.mac, device.rssi, Instant::now());
+        self.report_presence(appeared.into_iter().collect());
+    }
+
+    /// Connected devices stop advertising, but are certainly present
+    fn connected_present(&mut self) {
+        let connected: Vec<BtMacAddress> = self.cache
+            .read()
+            .unwrap()
+            .devices()
+            .into_iter()
+            .filter(|d| d.connected)
+            .map(|d| d.mac)
+            .collect();
+
+        let now = Instant::now();
+        let appeared: Vec<PresenceEvent> = {
+            let mut presence = self.presence.write().unwrap();
+            connected.iter().filter_map(|mac| presence.saw(mac, None, now)).collect()
+        };
+        self.report_presence(appeared);
+    }
+
+    fn report_presence(&mut self, events: Vec<PresenceEvent>) {
+        for ev in events {
+            debug!("{:?}", ev);
+            // Drop listeners that have gone away
+            self.presence_subs.retain(|tx| tx.send(ev.clone()).is_ok());
+        }
+    }
+
+    /// Forward any beacon frames advertised by a scanned device
+    fn report_beacons(&mut self, device: &BluetoothDevice) {
+        let mac = match device.get_address().ok().and_then(|a| BtMacAddress::from_str(&a).ok()) {
+            Some(mac) => mac,
+            None => return,
+        };
+
+        let mfr_data = device.get_manufacturer_data().unwrap_or_default();
+        let svc_data = device.get_service_data().unwrap_or_default();
+        let rssi = device.get_rssi().ok();
+
+        for beacon in parse_beacons(&mfr_data, &svc_data) {
+            trace!("Found beacon {:?} from {:?}", beacon, mac);
+
+            let distance = match (beacon.measured_power(), rssi) {
+                (Some(power), Some(rssi)) => Some(estimate_distance(power, rssi)),
+                _ => None,
+            };
+
+            let ev = BeaconEvent {
+                mac: mac.clone(),
+                rssi,
+                beacon,
+                distance,
+            };
+
+            // Drop listeners that have gone away
+            self.beacon_subs.retain(|tx| tx.send(ev.clone()).is_ok());
+        }
+    }
+
+    fn discover_new(&mut self) -> Result<Vec<BluetoothDevice>> {
         let adapter: BluetoothAdapter = BluetoothAdapter::init()
             .map_err(|e| e.to_string())?;
 
         let session = BluetoothDiscoverySession::create_session(adapter.get_id())
             .map_err(|e| e.to_string())?;
-        thread::sleep(OldDuration::from_millis(200));
+
+        // Listen for what is heard during the window, as BlueZ also lists
+        // devices it merely remembers
+        let updates = signals::subscribe(&self.tx_bus_subs)?;
 
         session.start_discovery()
             .map_err(|e| e.to_string())?;
+        self.metrics.record(|m| m.discovery_runs += 1);
         thread::sleep(Duration::to_std(&self.scan_duration).map_err(|e| e.to_string())?);
 
+        let heard: HashSet<String> = updates
+            .try_iter()
+            .filter(|ev| ev.advertised())
+            .map(|ev| ev.path().to_string())
+            .collect();
+        self.report_heard(&heard);
+
         let mut devices = adapter.get_device_list()
             .map_err(|e| e.to_string())?;
         let mut new_devices = vec![];
@@ -1,33 +1,67 @@
-use std::str::FromStr;
 use std::collections::HashMap;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 
-use blurz::{BluetoothDevice, BluetoothGATTCharacteristic, BluetoothGATTService};
-use uuid::Uuid;
+use blurz::{BluetoothDevice, BluetoothGATTCharacteristic};
 
 use Duration;
 use BtMacAddress;
-use bt_manager::SomethingItem;
+use bt_manager::{NotifyRequest, NotifyRoute, ReadRequest, ResolvedWrite, SomethingItem, WriteRequest};
+use bt_manager::acquire::acquire;
+use bt_manager::demand::Demand;
+use bt_manager::socket::forward_reads;
+use bt_manager::signals::{Resolved, SharedCache, CHRC_IFACE};
+use metrics::Metrics;
+use recording::Recorder;
+use capture::Capture;
 use errors::*;
 
 pub struct EndpointsDb {
     pub rx_polls: Receiver<(SomethingItem, Sender<Box<[u8]>>)>,
-    pub rx_writes: Receiver<(SomethingItem, Receiver<Box<[u8]>>)>,
+    pub rx_writes: Receiver<WriteRequest>,
 
     pub pending_poll: Vec<(SomethingItem, Sender<Box<[u8]>>)>,
-    pub pending_write: Vec<(SomethingItem, Receiver<Box<[u8]>>)>,
+    pub pending_write: Vec<WriteRequest>,
+
+    pub rx_reads: Receiver<ReadRequest>,
+    pub pending_read: Vec<ReadRequest>,
 
     pub tx_poll_characs: Sender<(BluetoothGATTCharacteristic, Sender<Box<[u8]>>)>,
-    pub tx_write_characs: Sender<(BluetoothGATTCharacteristic, Receiver<Box<[u8]>>)>,
+    pub tx_write_characs: Sender<ResolvedWrite>,
+
+    pub rx_notifies: Receiver<NotifyRequest>,
+    pub notifies: Vec<Subscription>,
+    pub tx_notify_routes: Sender<NotifyRoute>,
 
     pub rx_devs: Receiver<(BtMacAddress, BluetoothDevice)>,
     pub devices: HashMap<BtMacAddress, BluetoothDevice>,
+    pub cache: SharedCache,
+    pub demand: Demand,
 
     pub endpoint_interval: Duration,
+    pub metrics: Metrics,
+    pub recorder: Recorder,
+    pub capture: Capture,
+}
+
+/// A notification subscription, and the characteristic currently routed to it
+pub struct Subscription {
+    pub si: SomethingItem,
+    pub tx: Sender<Box<[u8]>>,
+    pub path: Option<String>,
+    /// Deliver notifications through `AcquireNotify` where supported
+    pub streaming: bool,
+    /// Alive while an acquired socket is being forwarded
+    pub stream: Option<Receiver<()>>,
+    /// Set once a notification can't be delivered, as the subscriber has
+    /// dropped its receiver
+    pub closed: Arc<AtomicBool>,
 }
 
 pub fn endpoints_task(data: &mut EndpointsDb) -> Option<Duration> {
     trace!("Endpoint Tick...");
+    let _timer = data.metrics.timer("endpoints");
 
     if let Ok(_) = data.manage_endpoints() {
         // data.manage_new_devices(devs);
@@ -53,6 +87,8 @@ impl EndpointsDb {
     pub fn discover_services(&mut self) -> Result<()> {
         self.handle_polls()?;
         self.handle_writes()?;
+        self.handle_reads()?;
+        self.handle_notifies()?;
 
         Ok(())
     }
@@ -64,47 +100,18 @@ impl EndpointsDb {
         }
 
         let mut rem = vec![];
-        'polls: for (i, &(ref si, ref tx)) in self.pending_poll.iter().enumerate() {
+        for (i, &(ref si, ref tx)) in self.pending_poll.iter().enumerate() {
             let dev = match self.devices.get(&si.mac) {
                 Some(x) => x,
                 _ => continue,
             };
+            self.demand.want(&dev.get_id());
 
-            let mut svcs = dev.get_gatt_services()
-                .map_err(|e| e.to_string())?;
-
-            if svcs.len() == 0 {
-                debug!("No services found, waiting");
-                continue;
-            }
-
-            'servs: for serv in svcs.drain(..) {
-                // Discover Services
-                let service = BluetoothGATTService::new(serv);
-                let serv_uuid =
-                    Uuid::from_str(&service.get_uuid().map_err(|e| e.to_string())?).chain_err(|| "failed to parse svc uuid")?;
-
-                if si.svc != serv_uuid {
-                    continue 'servs;
-                }
-
-                // Discover characteristics
-                'chrcs: for charac_str in service.get_gatt_characteristics().map_err(|e| e.to_string())? {
-                    let charac = BluetoothGATTCharacteristic::new(charac_str);
-                    let chr_uuid = Uuid::from_str(&charac.get_uuid().map_err(|e| e.to_string())?)
-                        .chain_err(|| "failed to parse chr uuid")?;
-
-                    if si.chrc != chr_uuid {
-                        continue 'chrcs;
-                    }
-
-                    // do something with charac
-                    rem.push(i);
-                    self.tx_poll_characs
-                        .send((charac, tx.clone()))
-                        .chain_err(|| "")?;
-                    continue 'polls;
-                }
+            if let Resolved::Found(path) = self.find_characteristic(dev, si) {
+                rem.push(i);
+                self.tx_poll_characs
+                    .send((BluetoothGATTCharacteristic::new(path), tx.clone()))
+                    .chain_err(|| "")?;
             }
         }
 
@@ -125,56 +132,271 @@ impl EndpointsDb {
         let mut rem = vec![];
         let mut charcs_found = vec![];
 
-        'polls: for (i, &(ref si, ref _rx)) in self.pending_write.iter().enumerate() {
+        for (i, (si, _, _)) in self.pending_write.iter().enumerate() {
             let dev = match self.devices.get(&si.mac) {
                 Some(x) => x,
                 _ => continue,
             };
+            self.demand.want(&dev.get_id());
+
+            if let Resolved::Found(path) = self.find_characteristic(dev, si) {
+                rem.push(i);
+                charcs_found.push(BluetoothGATTCharacteristic::new(path));
+            }
+        }
+
+        let mut rmvd: usize = 0;
+        for r in rem {
+            let (_, rx, options) = self.pending_write.remove(r - rmvd);
+            self.tx_write_characs
+                .send((charcs_found.remove(0), rx, options))
+                .chain_err(|| "")?;
+            rmvd += 1;
+        }
+
+        Ok(())
+    }
+
+    /// One-shot reads are answered directly from this task. Unlike polls and
+    /// writes, a read for an endpoint the device does not have is answered
+    /// with `None` rather than waiting forever.
+    pub fn handle_reads(&mut self) -> Result<()> {
+        while let Ok(r) = self.rx_reads.try_recv() {
+            info!("Received Read request: {:?}", r);
+            self.pending_read.push(r);
+        }
+
+        let mut rem = vec![];
+        for (i, (si, tx)) in self.pending_read.iter().enumerate() {
+            let dev = match self.devices.get(&si.mac) {
+                Some(x) => x,
+                _ => continue,
+            };
+            self.demand.want(&dev.get_id());
+
+            let reply = match self.find_characteristic(dev, si) {
+                Resolved::Pending => continue,
+                Resolved::Missing => None,
+                Resolved::Found(path) => match BluetoothGATTCharacteristic::new(path.clone()).read_value() {
+                    Ok(data) => {
+                        self.metrics.endpoint(&path, |m| {
+                            m.reads += 1;
+                            m.bytes_read += data.len() as u64;
+                        });
+                        self.capture.read(&path, &data);
+                        Some(data.into_boxed_slice())
+                    }
+                    Err(e) => {
+                        error!("Failed to read, {:?}", e);
+                        self.metrics.error("read");
+                        None
+                    }
+                },
+            };
+
+            rem.push(i);
+
+            // The requester may have given up waiting, that's fine
+            let _ = tx.send(reply);
+        }
+
+        for (rmvd, r) in rem.into_iter().enumerate() {
+            self.pending_read.remove(r - rmvd);
+        }
+
+        Ok(())
+    }
+
+    /// Keep notification subscriptions armed. Characteristics are looked up
+    /// again on every tick, as BlueZ may re-create them after a reconnect
+    pub fn handle_notifies(&mut self) -> Result<()> {
+        while let Ok((si, tx, streaming)) = self.rx_notifies.try_recv() {
+            info!("Received Notify request: {:?}", si);
+            self.notifies.push(Subscription {
+                si,
+                tx,
+                path: None,
+                streaming,
+                stream: None,
+                closed: Arc::default(),
+            });
+        }
+
+        self.prune_notifies();
 
-            let mut svcs = dev.get_gatt_services().map_err(|e| e.to_string())?;
+        for sub in self.notifies.iter_mut() {
+            let dev = match self.devices.get(&sub.si.mac) {
+                Some(x) => x,
+                _ => continue,
+            };
+            self.demand.want(&dev.get_id());
 
-            if svcs.len() == 0 {
-                debug!("No services found, waiting");
+            let cache = self.cache.read().unwrap();
+            if !cache.is_connected(&dev.get_id()) {
                 continue;
             }
 
-            'servs: for serv in svcs.drain(..) {
-                // Discover Services
-                let service = BluetoothGATTService::new(serv);
-                let serv_uuid =
-                    Uuid::from_str(&service.get_uuid().map_err(|e| e.to_string())?).chain_err(|| "failed to parse svc uuid")?;
+            let path = match cache.find_characteristic(&dev.get_id(), &sub.si.svc, &sub.si.chrc) {
+                Resolved::Found(path) => path,
+                _ => continue,
+            };
 
-                if si.svc != serv_uuid {
-                    continue 'servs;
+            if sub.streaming {
+                match sub.stream.as_ref().map(|s| s.try_recv()) {
+                    Some(Err(TryRecvError::Empty)) => continue,
+                    Some(Ok(())) => {
+                        // Forgotten on the next tick
+                        sub.closed.store(true, Ordering::Relaxed);
+                        continue;
+                    }
+                    _ => {}
                 }
 
-                // Discover characteristics
-                'chrcs: for charac_str in service.get_gatt_characteristics().map_err(|e| e.to_string())? {
-                    let charac = BluetoothGATTCharacteristic::new(charac_str);
-                    let chr_uuid = Uuid::from_str(&charac.get_uuid().map_err(|e| e.to_string())?)
-                        .chain_err(|| "failed to parse chr uuid")?;
-
-                    if si.chrc != chr_uuid {
-                        continue 'chrcs;
+                match acquire(&path, "AcquireNotify") {
+                    Ok((sock, mtu)) => {
+                        debug!("Streaming notifications for {:?}", sub.si);
+                        let (metrics, recorder) = (self.metrics.clone(), self.recorder.clone());
+                        let capture = self.capture.clone();
+                        let counted = path.clone();
+                        let on_read = move |data: &[u8]| {
+                            metrics.endpoint(&counted, |m| {
+                                m.notifications += 1;
+                                m.bytes_notified += data.len() as u64;
+                            });
+                            recorder.notification(&counted, data);
+                            capture.notification(&counted, data);
+                        };
+                        sub.stream = Some(forward_reads(sock, mtu as usize, sub.tx.clone(), on_read));
+                        continue;
                     }
+                    Err(e) => {
+                        warn!("Can't stream {:?}, falling back to StartNotify, {:?}", sub.si, e);
+                        self.metrics.error("acquire");
+                        sub.streaming = false;
+                        sub.stream = None;
+                    }
+                }
+            }
 
-                    // do something with charac
-                    rem.push(i);
-                    charcs_found.push(charac);
-                    continue 'polls;
+            if sub.path.as_ref() != Some(&path) {
+                self.tx_notify_routes
+                    .send((path.clone(), sub.tx.clone(), sub.closed.clone()))
+                    .chain_err(|| "")?;
+                sub.path = Some(path.clone());
+            }
+
+            if cache.bool_property(&path, CHRC_IFACE, "Notifying") != Some(true) {
+                debug!("Starting notifications for {:?}", sub.si);
+                if let Err(e) = BluetoothGATTCharacteristic::new(path).start_notify() {
+                    error!("Failed to start notifications, {:?}", e);
+                    self.metrics.error("notify");
                 }
             }
         }
 
-        let mut rmvd: usize = 0;
-        for r in rem {
-            let (_, rx) = self.pending_write.remove(r - rmvd);
-            self.tx_write_characs
-                .send((charcs_found.remove(0), rx))
-                .chain_err(|| "")?;
-            rmvd += 1;
+        Ok(())
+    }
+
+    /// Forget subscriptions nobody listens to anymore, so their device is no
+    /// longer wanted, and stop notifications nobody else subscribes to
+    fn prune_notifies(&mut self) {
+        let (gone, kept): (Vec<_>, Vec<_>) = self.notifies
+            .drain(..)
+            .partition(|sub| sub.closed.load(Ordering::Relaxed));
+        self.notifies = kept;
+
+        for sub in gone {
+            info!("Dropping notify subscription {:?}, nobody is listening", sub.si);
+
+            // Streams stop along with their socket
+            let path = match sub.path {
+                Some(ref p) if !sub.streaming => p,
+                _ => continue,
+            };
+            if self.notifies.iter().any(|s| s.path.as_ref() == Some(path)) {
+                continue;
+            }
+
+            debug!("Stopping notifications for {:?}", sub.si);
+            if let Err(e) = BluetoothGATTCharacteristic::new(path.clone()).stop_notify() {
+                debug!("Failed to stop notifications, {:?}", e);
+            }
+        }
+    }
+
+    fn find_characteristic(&self, dev: &BluetoothDevice, si: &SomethingItem) -> Resolved {
+        self.cache
+            .read()
+            .unwrap()
+            .find_characteristic(&dev.get_id(), &si.svc, &si.chrc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+
+    use uuid::Uuid;
+
+    const CHRC: &str = "/org/bluez/hci0/dev_CF_75_CE_86_6D_02/service000c/char000d";
+
+    fn endpoints() -> EndpointsDb {
+        EndpointsDb {
+            rx_polls: channel().1,
+            rx_writes: channel().1,
+            pending_poll: vec![],
+            pending_write: vec![],
+            rx_reads: channel().1,
+            pending_read: vec![],
+            tx_poll_characs: channel().0,
+            tx_write_characs: channel().0,
+            rx_notifies: channel().1,
+            notifies: vec![],
+            tx_notify_routes: channel().0,
+            rx_devs: channel().1,
+            devices: HashMap::new(),
+            cache: SharedCache::default(),
+            demand: Demand::default(),
+            endpoint_interval: Duration::milliseconds(100),
+            metrics: Metrics::default(),
+            recorder: Recorder::default(),
+            capture: Capture::default(),
         }
+    }
 
-        Ok(())
+    /// A subscription routed to `CHRC`, and the subscriber's receiver
+    fn subscription(streaming: bool) -> (Subscription, Receiver<Box<[u8]>>) {
+        let (tx, rx) = channel();
+        let sub = Subscription {
+            si: SomethingItem {
+                mac: BtMacAddress::from_str("CF:75:CE:86:6D:02").unwrap(),
+                svc: Uuid::from_str("0000180f-0000-1000-8000-00805f9b34fb").unwrap(),
+                chrc: Uuid::from_str("00002a19-0000-1000-8000-00805f9b34fb").unwrap(),
+            },
+            tx,
+            path: Some(CHRC.to_string()),
+            streaming,
+            stream: None,
+            closed: Arc::default(),
+        };
+        (sub, rx)
+    }
+
+    #[test]
+    fn abandoned_subscriptions_are_dropped() {
+        let mut ep = endpoints();
+
+        let (gone, _) = subscription(false);
+        gone.closed.store(true, Ordering::Relaxed);
+        let (kept, _rx) = subscription(false);
+        let (streamed, _) = subscription(true);
+        streamed.closed.store(true, Ordering::Relaxed);
+        ep.notifies = vec![gone, kept, streamed];
+
+        ep.handle_notifies().unwrap();
+        assert_eq!(ep.notifies.len(), 1);
+        assert!(!ep.notifies[0].closed.load(Ordering::Relaxed));
     }
 }
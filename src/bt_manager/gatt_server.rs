@@ -0,0 +1,602 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, MessageType};
+
+use bt_manager::bus::*;
+use gatt_server::{ChrcKey, GattApplication};
+use errors::*;
+
+const SERVICE_IFACE: &str = "org.bluez.GattService1";
+const CHRC_IFACE: &str = "org.bluez.GattCharacteristic1";
+const DESC_IFACE: &str = "org.bluez.GattDescriptor1";
+const GATT_MANAGER: &str = "org.bluez.GattManager1";
+
+static APP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+struct LocalService {
+    path: String,
+    uuid: String,
+    primary: bool,
+    chrc_paths: Vec<String>,
+}
+
+struct LocalChrc {
+    key: ChrcKey,
+    path: String,
+    svc_path: String,
+    uuid: String,
+    flags: Vec<&'static str>,
+    value: Vec<u8>,
+    on_read: Option<Box<dyn Fn() -> Vec<u8> + Send>>,
+    notifying: bool,
+    subscribers: Vec<Sender<Box<[u8]>>>,
+}
+
+struct LocalDesc {
+    path: String,
+    chrc_path: String,
+    uuid: String,
+    flags: Vec<&'static str>,
+    value: Vec<u8>,
+}
+
+pub struct GattServerData {
+    app_path: String,
+    services: Vec<LocalService>,
+    chrcs: Vec<LocalChrc>,
+    descs: Vec<LocalDesc>,
+
+    pub rx_writes: Receiver<(ChrcKey, Sender<Box<[u8]>>)>,
+    pub rx_notifiers: Receiver<(ChrcKey, Receiver<Box<[u8]>>)>,
+    notifiers: Vec<(usize, Receiver<Box<[u8]>>)>,
+}
+
+impl GattServerData {
+    /// Lay out the object tree for an application
+    pub fn new(
+        app: GattApplication,
+        rx_writes: Receiver<(ChrcKey, Sender<Box<[u8]>>)>,
+        rx_notifiers: Receiver<(ChrcKey, Receiver<Box<[u8]>>)>,
+    ) -> Self {
+        let app_path = format!(
+            "/org/easybluez/app{}",
+            APP_COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+
+        let mut data = GattServerData {
+            app_path,
+            services: vec![],
+            chrcs: vec![],
+            descs: vec![],
+            rx_writes,
+            rx_notifiers,
+            notifiers: vec![],
+        };
+
+        for (i, svc) in app.services.into_iter().enumerate() {
+            let svc_path = format!("{}/service{}", data.app_path, i);
+            let mut chrc_paths = vec![];
+
+            for (j, chrc) in svc.characteristics.into_iter().enumerate() {
+                let chrc_path = format!("{}/char{}", svc_path, j);
+
+                for (k, desc) in chrc.descriptors.into_iter().enumerate() {
+                    data.descs.push(LocalDesc {
+                        path: format!("{}/desc{}", chrc_path, k),
+                        chrc_path: chrc_path.clone(),
+                        uuid: desc.uuid.hyphenated().to_string(),
+                        flags: desc.flags,
+                        value: desc.value,
+                    });
+                }
+
+                chrc_paths.push(chrc_path.clone());
+                data.chrcs.push(LocalChrc {
+                    key: (svc.uuid, chrc.uuid),
+                    path: chrc_path,
+                    svc_path: svc_path.clone(),
+                    uuid: chrc.uuid.hyphenated().to_string(),
+                    flags: chrc.flags,
+                    value: chrc.value,
+                    on_read: chrc.on_read,
+                    notifying: false,
+                    subscribers: vec![],
+                });
+            }
+
+            data.services.push(LocalService {
+                path: svc_path,
+                uuid: svc.uuid.hyphenated().to_string(),
+                primary: svc.primary,
+                chrc_paths,
+            });
+        }
+
+        data
+    }
+
+    /// Export the application and register it with BlueZ, then serve
+    /// requests until the handle is dropped. The outcome of registration
+    /// is reported through `ready`.
+    pub fn run(mut self, ready: Sender<Result<()>>) {
+        let adapter = match adapter_path() {
+            Ok(a) => a,
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        };
+
+        let conn = match self.export() {
+            Ok(c) => c,
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        };
+
+        let reg = method_call(
+            &adapter,
+            GATT_MANAGER,
+            "RegisterApplication",
+            &[object_path(&self.app_path), prop_dict(vec![])],
+        ).and_then(|m| conn.send(m).map_err(|_| "failed to send!".into()));
+
+        // BlueZ calls back into us during registration, so the reply
+        // has to be picked up from the main loop rather than blocking
+        let reg_serial = match reg {
+            Ok(serial) => serial,
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        };
+        let mut ready = Some(ready);
+
+        loop {
+            for item in conn.iter(50) {
+                match item {
+                    ConnectionItem::MethodCall(m) => {
+                        let reply = self.handle_call(&m);
+                        let _ = conn.send(reply);
+                    }
+                    ConnectionItem::MethodReturn(ref m) if m.get_reply_serial() == Some(reg_serial) => {
+                        let res = if m.msg_type() == MessageType::Error {
+                            Err(format!("Failed to register application: {:?}", m.get_items()).into())
+                        } else {
+                            info!("Registered GATT application {}", self.app_path);
+                            Ok(())
+                        };
+                        let failed = res.is_err();
+
+                        if let Some(tx) = ready.take() {
+                            let _ = tx.send(res);
+                        }
+                        if failed {
+                            return;
+                        }
+                    }
+                    ConnectionItem::Nothing => break,
+                    _ => {}
+                }
+            }
+
+            match self.pump_channels(&conn) {
+                Ok(()) => {}
+                Err(_) => break,
+            }
+        }
+
+        info!("Unregistering GATT application {}", self.app_path);
+        let _ = call(
+            &conn,
+            &adapter,
+            GATT_MANAGER,
+            "UnregisterApplication",
+            &[object_path(&self.app_path)],
+        );
+    }
+
+    fn export(&self) -> Result<Connection> {
+        let conn = Connection::get_private(BusType::System).map_err(|e| e.to_string())?;
+
+        let paths = Some(&self.app_path)
+            .into_iter()
+            .chain(self.services.iter().map(|s| &s.path))
+            .chain(self.chrcs.iter().map(|c| &c.path))
+            .chain(self.descs.iter().map(|d| &d.path));
+
+        for path in paths {
+            conn.register_object_path(path).map_err(|e| e.to_string())?;
+        }
+
+        Ok(conn)
+    }
+
+    /// Accept new subscribers, and forward pending notifications. Fails
+    /// once the owning handle has been dropped.
+    fn pump_channels(&mut self, conn: &Connection) -> ::std::result::Result<(), TryRecvError> {
+        loop {
+            match self.rx_writes.try_recv() {
+                Ok((key, tx)) => {
+                    if let Some(chrc) = self.chrcs.iter_mut().find(|c| c.key == key) {
+                        chrc.subscribers.push(tx);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Ok((key, rx)) = self.rx_notifiers.try_recv() {
+            if let Some(idx) = self.chrcs.iter().position(|c| c.key == key) {
+                self.notifiers.push((idx, rx));
+            }
+        }
+
+        let chrcs = &mut self.chrcs;
+        self.notifiers.retain(|&(idx, ref rx)| loop {
+            match rx.try_recv() {
+                Ok(val) => {
+                    let chrc = &mut chrcs[idx];
+                    chrc.value = val.to_vec();
+                    if chrc.notifying {
+                        let sig = properties_changed(
+                            &chrc.path,
+                            CHRC_IFACE,
+                            vec![("Value", byte_array(&chrc.value))],
+                        );
+                        let _ = conn.send(sig);
+                    }
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        });
+
+        Ok(())
+    }
+
+    fn handle_call(&mut self, m: &Message) -> Message {
+        let (_, path, iface, member) = m.headers();
+        let path = path.unwrap_or_default();
+        let iface = iface.unwrap_or_default();
+        let member = member.unwrap_or_default();
+
+        trace!("GATT call {} {}.{}", path, iface, member);
+
+        if path == self.app_path && iface == OBJECT_MANAGER && member == "GetManagedObjects" {
+            return Message::new_method_return(m).unwrap().append(self.managed_objects());
+        }
+
+        if let Some(svc) = self.services.iter().find(|s| s.path == path) {
+            if iface == PROPERTIES {
+                return properties_reply(m, SERVICE_IFACE, service_props(svc));
+            }
+        }
+
+        if let Some(idx) = self.chrcs.iter().position(|c| c.path == path) {
+            return match (&*iface, &*member) {
+                (PROPERTIES, _) => properties_reply(m, CHRC_IFACE, chrc_props(&self.chrcs[idx])),
+                (CHRC_IFACE, _) => self.handle_chrc_call(idx, &member, m),
+                _ => unknown_method(m),
+            };
+        }
+
+        if let Some(desc) = self.descs.iter_mut().find(|d| d.path == path) {
+            return match (&*iface, &*member) {
+                (PROPERTIES, _) => properties_reply(m, DESC_IFACE, desc_props(desc)),
+                (DESC_IFACE, "ReadValue") => {
+                    let offset = read_offset(m);
+                    let val = desc.value.get(offset..).unwrap_or(&[]).to_vec();
+                    Message::new_method_return(m).unwrap().append(byte_array(&val))
+                }
+                (DESC_IFACE, "WriteValue") => match write_args(m) {
+                    Some((val, offset)) => {
+                        splice_value(&mut desc.value, &val, offset);
+                        Message::new_method_return(m).unwrap()
+                    }
+                    None => invalid_args(m),
+                },
+                _ => unknown_method(m),
+            };
+        }
+
+        unknown_method(m)
+    }
+
+    fn handle_chrc_call(&mut self, idx: usize, member: &str, m: &Message) -> Message {
+        let chrc = &mut self.chrcs[idx];
+
+        match member {
+            "ReadValue" => {
+                let val = chrc.read(read_offset(m));
+                Message::new_method_return(m).unwrap().append(byte_array(&val))
+            }
+            "WriteValue" => match write_args(m) {
+                Some((val, offset)) => {
+                    chrc.write(&val, offset);
+                    Message::new_method_return(m).unwrap()
+                }
+                None => invalid_args(m),
+            },
+            "StartNotify" => {
+                chrc.notifying = true;
+                Message::new_method_return(m).unwrap()
+            }
+            "StopNotify" => {
+                chrc.notifying = false;
+                Message::new_method_return(m).unwrap()
+            }
+            _ => unknown_method(m),
+        }
+    }
+
+    fn managed_objects(&self) -> MessageItem {
+        let mut objects = vec![];
+
+        for svc in self.services.iter() {
+            objects.push(managed_object(&svc.path, SERVICE_IFACE, service_props(svc)));
+        }
+        for chrc in self.chrcs.iter() {
+            objects.push(managed_object(&chrc.path, CHRC_IFACE, chrc_props(chrc)));
+        }
+        for desc in self.descs.iter() {
+            objects.push(managed_object(&desc.path, DESC_IFACE, desc_props(desc)));
+        }
+
+        MessageItem::Array(objects, Cow::Borrowed("{oa{sa{sv}}}"))
+    }
+}
+
+impl LocalChrc {
+    fn read(&self, offset: usize) -> Vec<u8> {
+        let val = match self.on_read {
+            Some(ref f) => f(),
+            None => self.value.clone(),
+        };
+        val.get(offset..).unwrap_or(&[]).to_vec()
+    }
+
+    /// Long writes arrive in pieces, subscribers are passed the whole value
+    /// written so far
+    fn write(&mut self, val: &[u8], offset: usize) {
+        splice_value(&mut self.value, val, offset);
+
+        let data: Box<[u8]> = self.value.clone().into_boxed_slice();
+        self.subscribers.retain(|tx| tx.send(data.clone()).is_ok());
+    }
+}
+
+fn service_props(svc: &LocalService) -> Vec<(&'static str, MessageItem)> {
+    vec![
+        ("UUID", svc.uuid.clone().into()),
+        ("Primary", svc.primary.into()),
+        ("Characteristics", path_array(&svc.chrc_paths)),
+    ]
+}
+
+fn chrc_props(chrc: &LocalChrc) -> Vec<(&'static str, MessageItem)> {
+    vec![
+        ("UUID", chrc.uuid.clone().into()),
+        ("Service", object_path(&chrc.svc_path)),
+        ("Flags", string_array(&chrc.flags)),
+        ("Value", byte_array(&chrc.value)),
+        ("Notifying", chrc.notifying.into()),
+    ]
+}
+
+fn desc_props(desc: &LocalDesc) -> Vec<(&'static str, MessageItem)> {
+    vec![
+        ("UUID", desc.uuid.clone().into()),
+        ("Characteristic", object_path(&desc.chrc_path)),
+        ("Flags", string_array(&desc.flags)),
+        ("Value", byte_array(&desc.value)),
+    ]
+}
+
+fn managed_object(path: &str, iface: &str, props: Vec<(&str, MessageItem)>) -> MessageItem {
+    let ifaces = MessageItem::Array(
+        vec![MessageItem::DictEntry(Box::new(iface.into()), Box::new(prop_dict(props)))],
+        Cow::Borrowed("{sa{sv}}"),
+    );
+    MessageItem::DictEntry(Box::new(object_path(path)), Box::new(ifaces))
+}
+
+/// The `offset` option of a `ReadValue` call
+fn read_offset(m: &Message) -> usize {
+    m.get_items()
+        .first()
+        .and_then(|opts| dict_get(opts, "offset").and_then(|o| o.inner::<u16>().ok()))
+        .unwrap_or(0) as usize
+}
+
+/// The value and `offset` option of a `WriteValue` call
+fn write_args(m: &Message) -> Option<(Vec<u8>, usize)> {
+    let items = m.get_items();
+    let val = bytes_from_item(items.first()?)?;
+    let offset = items
+        .get(1)
+        .and_then(|opts| dict_get(opts, "offset").and_then(|o| o.inner::<u16>().ok()))
+        .unwrap_or(0);
+    Some((val, offset as usize))
+}
+
+fn splice_value(value: &mut Vec<u8>, new: &[u8], offset: usize) {
+    value.truncate(offset);
+    value.resize(offset, 0);
+    value.extend_from_slice(new);
+}
+
+fn unknown_method(m: &Message) -> Message {
+    error_reply(m, "org.freedesktop.DBus.Error.UnknownMethod", "Unknown method")
+}
+
+fn invalid_args(m: &Message) -> Message {
+    error_reply(m, "org.bluez.Error.InvalidArguments", "Invalid arguments")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+
+    use uuid::Uuid;
+
+    use gatt_server::{GattCharacteristic, GattDescriptor, GattService};
+
+    const SVC: &str = "0000180f-0000-1000-8000-00805f9b34fb";
+    const CHRC: &str = "00002a19-0000-1000-8000-00805f9b34fb";
+    const DESC: &str = "00002901-0000-1000-8000-00805f9b34fb";
+
+    fn server() -> GattServerData {
+        let uuid = |s| Uuid::from_str(s).unwrap();
+        let app = GattApplication::new().service(
+            GattService::new(uuid(SVC)).characteristic(
+                GattCharacteristic::new(uuid(CHRC))
+                    .read()
+                    .write()
+                    .value(&[100])
+                    .descriptor(GattDescriptor::new(uuid(DESC)).read().value(b"Battery")),
+            ),
+        );
+
+        GattServerData::new(app, channel().1, channel().1)
+    }
+
+    fn call(path: &str, iface: &str, member: &str, args: Vec<MessageItem>) -> Message {
+        let mut m = Message::new_method_call(BLUEZ, path, iface, member).unwrap();
+        m.append_items(&args);
+        m
+    }
+
+    fn options(offset: u16) -> MessageItem {
+        prop_dict(vec![("offset", offset.into())])
+    }
+
+    #[test]
+    fn splices_written_values() {
+        let mut value = vec![1, 2, 3, 4];
+        splice_value(&mut value, &[9], 1);
+        assert_eq!(value, [1, 9]);
+
+        splice_value(&mut value, &[5, 6], 2);
+        assert_eq!(value, [1, 9, 5, 6]);
+
+        // Past the end is padded with zeros
+        splice_value(&mut value, &[7], 6);
+        assert_eq!(value, [1, 9, 5, 6, 0, 0, 7]);
+
+        splice_value(&mut value, &[], 0);
+        assert!(value.is_empty());
+    }
+
+    #[test]
+    fn parses_call_options() {
+        let read = |args| read_offset(&call("/", CHRC_IFACE, "ReadValue", args));
+        assert_eq!(read(vec![options(3)]), 3);
+        assert_eq!(read(vec![prop_dict(vec![])]), 0);
+        assert_eq!(read(vec![]), 0);
+
+        let write = |args| write_args(&call("/", CHRC_IFACE, "WriteValue", args));
+        assert_eq!(write(vec![byte_array(&[1, 2]), options(4)]), Some((vec![1, 2], 4)));
+        assert_eq!(write(vec![byte_array(&[1, 2])]), Some((vec![1, 2], 0)));
+        assert_eq!(write(vec!["not bytes".into()]), None);
+        assert_eq!(write(vec![]), None);
+    }
+
+    #[test]
+    fn lays_out_managed_objects() {
+        let data = server();
+        let svc = format!("{}/service0", data.app_path);
+        let chrc = format!("{}/char0", svc);
+        let desc = format!("{}/desc0", chrc);
+
+        let uuid = |u: &str| ("UUID", u.to_string().into());
+        let expected = MessageItem::Array(
+            vec![
+                managed_object(
+                    &svc,
+                    SERVICE_IFACE,
+                    vec![uuid(SVC), ("Primary", true.into()), ("Characteristics", path_array(&[&chrc]))],
+                ),
+                managed_object(
+                    &chrc,
+                    CHRC_IFACE,
+                    vec![
+                        uuid(CHRC),
+                        ("Service", object_path(&svc)),
+                        ("Flags", string_array(&["read", "write"])),
+                        ("Value", byte_array(&[100])),
+                        ("Notifying", false.into()),
+                    ],
+                ),
+                managed_object(
+                    &desc,
+                    DESC_IFACE,
+                    vec![
+                        uuid(DESC),
+                        ("Characteristic", object_path(&chrc)),
+                        ("Flags", string_array(&["read"])),
+                        ("Value", byte_array(b"Battery")),
+                    ],
+                ),
+            ],
+            Cow::Borrowed("{oa{sa{sv}}}"),
+        );
+        assert_eq!(data.managed_objects(), expected);
+
+        // Every entry is an object path, to a dict of one interface
+        let MessageItem::DictEntry(ref path, ref ifaces) = expected.inner::<&[MessageItem]>().unwrap()[0] else {
+            panic!("not a dict entry");
+        };
+        assert_eq!(**path, object_path(&svc));
+        assert_eq!(ifaces.type_sig(), "a{sa{sv}}");
+    }
+
+    #[test]
+    fn answers_property_calls() {
+        let data = server();
+        let chrc = format!("{}/service0/char0", data.app_path);
+        let lookup = |member, args| {
+            property_lookup(&call(&chrc, PROPERTIES, member, args), CHRC_IFACE, chrc_props(&data.chrcs[0]))
+        };
+
+        assert_eq!(
+            lookup("Get", vec![CHRC_IFACE.into(), "UUID".into()]),
+            Ok(variant(CHRC.to_string()))
+        );
+        assert_eq!(
+            lookup("Get", vec![CHRC_IFACE.into(), "Value".into()]),
+            Ok(variant(byte_array(&[100])))
+        );
+        assert_eq!(
+            lookup("GetAll", vec![CHRC_IFACE.into()]),
+            Ok(prop_dict(chrc_props(&data.chrcs[0])))
+        );
+
+        // Only the object's own interface and properties, read only
+        assert!(lookup("GetAll", vec![SERVICE_IFACE.into()]).is_err());
+        assert!(lookup("Get", vec![CHRC_IFACE.into(), "Missing".into()]).is_err());
+        assert!(lookup("Set", vec![CHRC_IFACE.into(), "Value".into(), variant(byte_array(&[]))]).is_err());
+    }
+
+    #[test]
+    fn long_writes_deliver_the_whole_value() {
+        let mut data = server();
+        let chrc = &mut data.chrcs[0];
+        let (tx, writes) = channel();
+        chrc.subscribers.push(tx);
+
+        chrc.write(b"hello ", 0);
+        chrc.write(b"world", 6);
+        assert_eq!(&*writes.try_recv().unwrap(), b"hello ");
+        assert_eq!(&*writes.try_recv().unwrap(), b"hello world");
+
+        assert_eq!(chrc.read(6), b"world");
+        assert_eq!(chrc.read(20), b"");
+    }
+}
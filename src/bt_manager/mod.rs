@@ -1,14 +1,27 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, Sender};
 use std::time::Instant;
 
-use blurz::BluetoothDevice;
+use blurz::{BluetoothDevice, BluetoothGATTCharacteristic};
 use uuid::Uuid;
 use BtMacAddress;
+use writes::WriteOptions;
+use Duration;
 
 pub mod discovery;
 pub mod connection;
 pub mod endpoints;
 pub mod data_poll;
 pub mod data_write;
+pub mod bus;
+pub mod gatt_server;
+pub mod advertising;
+pub mod scanner;
+pub mod signals;
+pub mod demand;
+pub mod acquire;
+pub mod socket;
 
 
 #[derive(Debug)]
@@ -18,28 +31,72 @@ pub struct SomethingItem {
     pub chrc: Uuid,
 }
 
+/// A writeable endpoint, and how to deliver what is sent to it
+pub type WriteRequest = (SomethingItem, Receiver<Box<[u8]>>, WriteOptions);
+
+/// A write request whose characteristic has been found
+pub type ResolvedWrite = (BluetoothGATTCharacteristic, Receiver<Box<[u8]>>, WriteOptions);
+
+/// A notification subscription, and whether to stream it through a socket
+/// from `AcquireNotify`
+pub type NotifyRequest = (SomethingItem, Sender<Box<[u8]>>, bool);
+
+/// Where to deliver a characteristic's notifications, and a flag set once
+/// the subscriber turns out to be gone
+pub type NotifyRoute = (String, Sender<Box<[u8]>>, Arc<AtomicBool>);
+
+/// A one-shot read, answered with `None` if the endpoint does not exist
+pub type ReadRequest = (SomethingItem, Sender<Option<Box<[u8]>>>);
+
 
 pub struct Connectable {
     pub bluez_handle: BluetoothDevice,
-    pub mac_addr: String,
+    pub mac: BtMacAddress,
     pub last_connected: Instant,
+    /// When the current, or most recent, connection was established
+    pub connected_since: Instant,
+
+    /// Whether the device was connected at the last check
+    pub connected: bool,
+    /// Whether `Missing` has been reported since the device was last connected
+    pub missing: bool,
+    /// Connect attempts since the device was last connected
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub next_attempt: Instant,
+    pub gave_up: bool,
 }
 
 impl Connectable {
-    pub fn new(dev: BluetoothDevice) -> Self {
+    pub fn new(mac: BtMacAddress, dev: BluetoothDevice) -> Self {
         Self {
-            mac_addr: dev.get_address().unwrap_or(String::from("Unknown")),
             bluez_handle: dev,
+            mac,
             last_connected: Instant::now(),
+            connected_since: Instant::now(),
+            connected: false,
+            missing: false,
+            attempts: 0,
+            backoff: Duration::zero(),
+            next_attempt: Instant::now(),
+            gave_up: false,
         }
     }
 
-    /// Attempt to initiate a connect
-    pub fn connect(&mut self) {
+    /// Attempt to initiate a connect, returns whether it succeeded
+    pub fn connect(&mut self) -> bool {
         debug!("Attempting to connect to {:?}", self.bluez_handle);
-        if let Ok(_) = self.bluez_handle.connect() {
-            info!("connected to {:?}", self.bluez_handle);
-            self.last_connected = Instant::now();
+        self.attempts += 1;
+        match self.bluez_handle.connect() {
+            Ok(_) => {
+                info!("connected to {:?}", self.bluez_handle);
+                self.last_connected = Instant::now();
+                true
+            }
+            Err(e) => {
+                debug!("Failed to connect to {:?}, {:?}", self.bluez_handle, e);
+                false
+            }
         }
     }
 }
@@ -0,0 +1,86 @@
+//! A discovery session that runs until stopped. Devices are reported by the
+//! signal dispatcher as BlueZ sees them, rather than in fixed scan windows.
+
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use dbus::{BusType, Connection};
+
+use bt_manager::bus::*;
+use bt_manager::signals::BusEvent;
+use errors::*;
+
+const ADAPTER_IFACE: &str = "org.bluez.Adapter1";
+
+/// Discovery filter transports
+pub const LE: &str = "le";
+/// LE and Classic, interleaving LE scans with Classic inquiry
+pub const LE_AND_CLASSIC: &str = "auto";
+
+/// A running continuous scan. Discovery is stopped when this is dropped.
+pub struct Scanner {
+    /// Changes to the object tree while scanning
+    pub updates: Receiver<BusEvent>,
+    /// Transport the scan is filtered to
+    pub transport: &'static str,
+    alive: Receiver<()>,
+    _stop: Sender<()>,
+    _thread: thread::JoinHandle<()>,
+}
+
+impl Scanner {
+    pub fn start(updates: Receiver<BusEvent>, transport: &'static str) -> Self {
+        let (tx_stop, rx_stop) = channel();
+        let (tx_alive, rx_alive) = channel::<()>();
+
+        let thread = thread::spawn(move || {
+            let _alive = tx_alive;
+            if let Err(e) = scan(&rx_stop, transport) {
+                error!("Continuous scan failed, {:?}", e);
+            }
+        });
+
+        Scanner {
+            updates,
+            transport,
+            alive: rx_alive,
+            _stop: tx_stop,
+            _thread: thread,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !matches!(self.alive.try_recv(), Err(TryRecvError::Disconnected))
+    }
+}
+
+fn scan(stop: &Receiver<()>, transport: &str) -> Result<()> {
+    let adapter = adapter_path()?;
+
+    // BlueZ ties the discovery session to this connection, so it has to
+    // stay open for as long as we scan
+    let conn = Connection::get_private(BusType::System).map_err(|e| e.to_string())?;
+
+    // Ask for every advertisement, not just the first from each device,
+    // so RSSI and advertised data stay fresh
+    call(
+        &conn,
+        &adapter,
+        ADAPTER_IFACE,
+        "SetDiscoveryFilter",
+        &[prop_dict(vec![
+            ("Transport", transport.into()),
+            ("DuplicateData", true.into()),
+        ])],
+    )?;
+    call(&conn, &adapter, ADAPTER_IFACE, "StartDiscovery", &[])?;
+    info!("Started continuous scan");
+
+    // Block until the scanner is dropped
+    let _ = stop.recv();
+
+    info!("Stopping continuous scan");
+    call(&conn, &adapter, ADAPTER_IFACE, "StopDiscovery", &[])?;
+
+    Ok(())
+}
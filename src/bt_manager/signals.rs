@@ -0,0 +1,703 @@
+//! A single listener for BlueZ's D-Bus signals. It mirrors the BlueZ object
+//! tree into a shared cache, so the other tasks can look up connection state
+//! and GATT layout without a D-Bus round trip, and it delivers
+//! characteristic notifications as soon as they arrive.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration as OldDuration, Instant};
+
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, Path};
+use uuid::Uuid;
+
+use BtMacAddress;
+use devices::{CharacteristicInfo, ScannedDevice, ServiceInfo};
+use bt_manager::{NotifyRoute, SomethingItem};
+use bt_manager::bus::*;
+use errors::*;
+use metrics::Metrics;
+use recording::Recorder;
+use capture::Capture;
+
+pub const DEVICE_IFACE: &str = "org.bluez.Device1";
+pub const SERVICE_IFACE: &str = "org.bluez.GattService1";
+pub const CHRC_IFACE: &str = "org.bluez.GattCharacteristic1";
+pub const DESC_IFACE: &str = "org.bluez.GattDescriptor1";
+
+pub type Properties = HashMap<String, MessageItem>;
+
+const DBUS: &str = "org.freedesktop.DBus";
+const DBUS_LOCAL: &str = "org.freedesktop.DBus.Local";
+
+/// Bounds on how long to wait before listening again, after losing BlueZ
+const MIN_RETRY_MS: u64 = 1000;
+const MAX_RETRY_MS: u64 = 30000;
+
+/// How long to wait for the dispatcher to take a subscription
+const SUBSCRIBE_TIMEOUT_MS: u64 = 5000;
+
+/// Every BlueZ object, by path, then interface
+#[derive(Default)]
+pub struct ObjectCache {
+    objects: HashMap<String, HashMap<String, Properties>>,
+    /// Why the cache can't be kept up to date, while BlueZ or the bus is
+    /// unreachable
+    unavailable: Option<String>,
+}
+
+pub type SharedCache = Arc<RwLock<ObjectCache>>;
+
+/// Something changed in the object tree
+#[derive(Clone, Debug)]
+pub enum BusEvent {
+    Added { path: String, ifaces: Vec<String> },
+    Removed { path: String, ifaces: Vec<String> },
+    /// Properties that changed or were invalidated
+    Changed { path: String, iface: String, props: Vec<String> },
+}
+
+/// Device properties that only change as advertisements are received
+const ADVERTISED_PROPS: [&str; 4] = ["RSSI", "ManufacturerData", "ServiceData", "TxPower"];
+
+impl BusEvent {
+    pub fn path(&self) -> &str {
+        match *self {
+            BusEvent::Added { ref path, .. }
+            | BusEvent::Removed { ref path, .. }
+            | BusEvent::Changed { ref path, .. } => path,
+        }
+    }
+
+    pub fn involves(&self, iface: &str) -> bool {
+        match *self {
+            BusEvent::Added { ref ifaces, .. } | BusEvent::Removed { ref ifaces, .. } => {
+                ifaces.iter().any(|i| i == iface)
+            }
+            BusEvent::Changed { iface: ref i, .. } => i == iface,
+        }
+    }
+
+    /// Whether a device was just heard advertising, rather than merely
+    /// remembered by BlueZ
+    pub fn advertised(&self) -> bool {
+        match *self {
+            BusEvent::Added { .. } => self.involves(DEVICE_IFACE),
+            BusEvent::Changed { ref iface, ref props, .. } => {
+                iface == DEVICE_IFACE && props.iter().any(|p| ADVERTISED_PROPS.contains(&p.as_str()))
+            }
+            BusEvent::Removed { .. } => false,
+        }
+    }
+}
+
+/// Where to send bus events, and where to acknowledge taking the
+/// subscription
+pub type BusSubscription = (Sender<BusEvent>, Sender<()>);
+
+/// Subscribe to bus events, waiting for the dispatcher to take the
+/// subscription, so nothing that happens once this returns is missed
+pub fn subscribe(tx_subs: &Sender<BusSubscription>) -> Result<Receiver<BusEvent>> {
+    let (tx, rx) = channel();
+    let (tx_ack, rx_ack) = channel();
+
+    tx_subs.send((tx, tx_ack)).chain_err(|| "signal dispatcher is gone")?;
+    rx_ack
+        .recv_timeout(OldDuration::from_millis(SUBSCRIBE_TIMEOUT_MS))
+        .chain_err(|| "signal dispatcher did not take the subscription")?;
+
+    Ok(rx)
+}
+
+/// Outcome of looking up an endpoint on a device
+pub enum Resolved {
+    /// The device has not exposed any services yet
+    Pending,
+    /// Services are available, but not the requested one
+    Missing,
+    /// Object path of the characteristic
+    Found(String),
+}
+
+impl ObjectCache {
+    pub fn property(&self, path: &str, iface: &str, prop: &str) -> Option<&MessageItem> {
+        self.objects.get(path)?.get(iface)?.get(prop)
+    }
+
+    pub fn bool_property(&self, path: &str, iface: &str, prop: &str) -> Option<bool> {
+        self.property(path, iface, prop)?.inner::<bool>().ok()
+    }
+
+    fn str_property(&self, path: &str, iface: &str, prop: &str) -> Option<&str> {
+        match *self.property(path, iface, prop)? {
+            MessageItem::Str(ref s) => Some(s),
+            MessageItem::ObjectPath(ref p) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn uuid_property(&self, path: &str, iface: &str) -> Option<Uuid> {
+        Uuid::from_str(self.str_property(path, iface, "UUID")?).ok()
+    }
+
+    /// Why BlueZ can't be reached, if it can't
+    pub fn unavailable(&self) -> Option<&str> {
+        self.unavailable.as_deref()
+    }
+
+    /// Forget every object, as BlueZ can't be reached
+    fn set_unavailable(&mut self, reason: String) {
+        self.objects.clear();
+        self.unavailable = Some(reason);
+    }
+
+    pub fn is_connected(&self, dev_path: &str) -> bool {
+        self.bool_property(dev_path, DEVICE_IFACE, "Connected")
+            .unwrap_or(false)
+    }
+
+    /// Object path of the device with this address, if BlueZ knows it
+    pub fn device_path(&self, mac: &BtMacAddress) -> Option<String> {
+        self.objects
+            .keys()
+            .find(|path| {
+                self.str_property(path, DEVICE_IFACE, "Address")
+                    .and_then(|a| BtMacAddress::from_str(a).ok())
+                    .as_ref() == Some(mac)
+            })
+            .cloned()
+    }
+
+    /// Whether the device uses a random, rather than public, LE address
+    pub fn has_random_address(&self, dev_path: &str) -> bool {
+        self.str_property(dev_path, DEVICE_IFACE, "AddressType") == Some("random")
+    }
+
+    pub fn rssi(&self, dev_path: &str) -> Option<i16> {
+        self.property(dev_path, DEVICE_IFACE, "RSSI")?.inner::<i16>().ok()
+    }
+
+    /// Negotiated ATT MTU of a connected device. Only newer BlueZ versions
+    /// report it, as a property of each characteristic
+    pub fn mtu(&self, dev_path: &str) -> Option<u16> {
+        self.children(SERVICE_IFACE, "Device", dev_path)
+            .flat_map(|svc| self.children(CHRC_IFACE, "Service", svc))
+            .find_map(|chrc| self.property(chrc, CHRC_IFACE, "MTU")?.inner::<u16>().ok())
+    }
+
+    pub fn services_resolved(&self, dev_path: &str) -> bool {
+        self.bool_property(dev_path, DEVICE_IFACE, "ServicesResolved")
+            .unwrap_or(false)
+    }
+
+    fn strings_property(&self, path: &str, iface: &str, prop: &str) -> Vec<String> {
+        self.property(path, iface, prop).map(strings).unwrap_or_default()
+    }
+
+    /// Describe a device, if BlueZ knows it
+    pub fn scanned_device(&self, dev_path: &str) -> Option<ScannedDevice> {
+        let mac = BtMacAddress::from_str(self.str_property(dev_path, DEVICE_IFACE, "Address")?).ok()?;
+
+        Some(ScannedDevice {
+            mac,
+            name: self.str_property(dev_path, DEVICE_IFACE, "Name").map(|n| n.to_string()),
+            rssi: self.rssi(dev_path),
+            services: self.strings_property(dev_path, DEVICE_IFACE, "UUIDs")
+                .iter()
+                .filter_map(|u| Uuid::from_str(u).ok())
+                .collect(),
+            connected: self.is_connected(dev_path),
+            paired: self.bool_property(dev_path, DEVICE_IFACE, "Paired").unwrap_or(false),
+        })
+    }
+
+    /// Every device BlueZ knows about
+    pub fn devices(&self) -> Vec<ScannedDevice> {
+        let mut paths: Vec<&String> = self.objects
+            .iter()
+            .filter(|&(_, ifaces)| ifaces.contains_key(DEVICE_IFACE))
+            .map(|(path, _)| path)
+            .collect();
+        paths.sort();
+
+        paths.into_iter().filter_map(|p| self.scanned_device(p)).collect()
+    }
+
+    /// The GATT layout of a device, as far as it has been resolved
+    pub fn services(&self, dev_path: &str) -> Vec<ServiceInfo> {
+        let mut services: Vec<(&str, ServiceInfo)> = self.children(SERVICE_IFACE, "Device", dev_path)
+            .filter_map(|svc| {
+                let mut chrcs: Vec<(&str, CharacteristicInfo)> = self.children(CHRC_IFACE, "Service", svc)
+                    .filter_map(|chrc| {
+                        let mut descs: Vec<&str> = self.children(DESC_IFACE, "Characteristic", chrc).collect();
+                        descs.sort();
+
+                        Some((chrc, CharacteristicInfo {
+                            uuid: self.uuid_property(chrc, CHRC_IFACE)?,
+                            flags: self.strings_property(chrc, CHRC_IFACE, "Flags"),
+                            descriptors: descs
+                                .iter()
+                                .filter_map(|d| self.uuid_property(d, DESC_IFACE))
+                                .collect(),
+                        }))
+                    })
+                    .collect();
+                chrcs.sort_by_key(|&(path, _)| path);
+
+                Some((svc, ServiceInfo {
+                    uuid: self.uuid_property(svc, SERVICE_IFACE)?,
+                    primary: self.bool_property(svc, SERVICE_IFACE, "Primary").unwrap_or(true),
+                    characteristics: chrcs.into_iter().map(|(_, c)| c).collect(),
+                }))
+            })
+            .collect();
+
+        // Object paths follow the attribute handles
+        services.sort_by_key(|&(path, _)| path);
+        services.into_iter().map(|(_, s)| s).collect()
+    }
+
+    /// Paths of all objects implementing `iface` whose `parent_prop`
+    /// property points at `parent`
+    fn children<'a>(
+        &'a self,
+        iface: &'a str,
+        parent_prop: &'a str,
+        parent: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.objects.keys().filter_map(move |path| {
+            if self.str_property(path, iface, parent_prop) == Some(parent) {
+                Some(&path[..])
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The device and UUIDs of a characteristic, if its service and device
+    /// are known
+    pub fn endpoint(&self, chrc_path: &str) -> Option<SomethingItem> {
+        let svc_path = self.str_property(chrc_path, CHRC_IFACE, "Service")?;
+        let dev_path = self.str_property(svc_path, SERVICE_IFACE, "Device")?;
+
+        Some(SomethingItem {
+            mac: BtMacAddress::from_str(self.str_property(dev_path, DEVICE_IFACE, "Address")?).ok()?,
+            svc: self.uuid_property(svc_path, SERVICE_IFACE)?,
+            chrc: self.uuid_property(chrc_path, CHRC_IFACE)?,
+        })
+    }
+
+    /// Name a characteristic as `<mac>/<service>/<characteristic>`, if its
+    /// service and device are known
+    pub fn endpoint_name(&self, chrc_path: &str) -> Option<String> {
+        let ep = self.endpoint(chrc_path)?;
+        Some(format!("{}/{}/{}", ep.mac, ep.svc.hyphenated(), ep.chrc.hyphenated()))
+    }
+
+    /// Find a characteristic of a device by service and characteristic UUID
+    pub fn find_characteristic(&self, dev_path: &str, svc: &Uuid, chrc: &Uuid) -> Resolved {
+        let resolved = self.bool_property(dev_path, DEVICE_IFACE, "ServicesResolved")
+            .unwrap_or(false);
+        let mut any_services = false;
+
+        for svc_path in self.children(SERVICE_IFACE, "Device", dev_path) {
+            any_services = true;
+
+            if self.uuid_property(svc_path, SERVICE_IFACE).as_ref() != Some(svc) {
+                continue;
+            }
+
+            for chrc_path in self.children(CHRC_IFACE, "Service", svc_path) {
+                if self.uuid_property(chrc_path, CHRC_IFACE).as_ref() == Some(chrc) {
+                    return Resolved::Found(chrc_path.to_string());
+                }
+            }
+        }
+
+        if !any_services || !resolved {
+            debug!("No services found, waiting");
+            Resolved::Pending
+        } else {
+            Resolved::Missing
+        }
+    }
+
+    fn insert(&mut self, path: &str, iface: &str, props: Properties) {
+        self.objects
+            .entry(path.to_string())
+            .or_default()
+            .insert(iface.to_string(), props);
+    }
+
+    fn remove(&mut self, path: &str, ifaces: &[String]) {
+        let now_empty = match self.objects.get_mut(path) {
+            Some(obj) => {
+                for iface in ifaces {
+                    obj.remove(iface);
+                }
+                obj.is_empty()
+            }
+            None => false,
+        };
+
+        if now_empty {
+            self.objects.remove(path);
+        }
+    }
+
+    fn update(&mut self, path: &str, iface: &str, changed: Properties, invalidated: &[String]) {
+        let props = self.objects
+            .entry(path.to_string())
+            .or_default()
+            .entry(iface.to_string())
+            .or_default();
+
+        props.extend(changed);
+        for prop in invalidated {
+            props.remove(prop);
+        }
+    }
+}
+
+pub struct Dispatcher {
+    pub cache: SharedCache,
+
+    pub rx_subs: Receiver<BusSubscription>,
+    subs: Vec<Sender<BusEvent>>,
+
+    /// Where to deliver value changes, by characteristic path
+    pub rx_notify: Receiver<NotifyRoute>,
+    notify_routes: Vec<NotifyRoute>,
+
+    metrics: Metrics,
+    recorder: Recorder,
+    capture: Capture,
+}
+
+impl Dispatcher {
+    pub fn new(
+        cache: SharedCache,
+        rx_subs: Receiver<BusSubscription>,
+        rx_notify: Receiver<NotifyRoute>,
+        metrics: Metrics,
+        recorder: Recorder,
+        capture: Capture,
+    ) -> Self {
+        Dispatcher {
+            cache,
+            rx_subs,
+            subs: vec![],
+            rx_notify,
+            notify_routes: vec![],
+            metrics,
+            recorder,
+            capture,
+        }
+    }
+
+    /// Listen for as long as the process runs. Whenever the bus or BlueZ
+    /// goes away, the cache is emptied and marked unavailable, and listening
+    /// starts over with a fresh snapshot once BlueZ is back
+    pub fn run(mut self) {
+        let mut backoff = MIN_RETRY_MS;
+
+        loop {
+            let started = Instant::now();
+            let e = match self.listen() {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+
+            error!("Signal dispatcher lost BlueZ, retrying in {}ms, {:?}", backoff, e);
+            self.cache.write().unwrap().set_unavailable(e.to_string());
+
+            // Back off only while failing straight away
+            if started.elapsed() > OldDuration::from_millis(MAX_RETRY_MS) {
+                backoff = MIN_RETRY_MS;
+            }
+            thread::sleep(OldDuration::from_millis(backoff));
+            backoff = cmp::min(backoff * 2, MAX_RETRY_MS);
+        }
+    }
+
+    fn listen(&mut self) -> Result<()> {
+        let conn = Connection::get_private(BusType::System).map_err(|e| e.to_string())?;
+
+        // Subscribe before taking the snapshot, so nothing falls in between
+        for member in &["InterfacesAdded", "InterfacesRemoved"] {
+            conn.add_match(&format!(
+                "type='signal',sender='{}',interface='{}',member='{}'",
+                BLUEZ, OBJECT_MANAGER, member
+            )).map_err(|e| e.to_string())?;
+        }
+        conn.add_match(&format!(
+            "type='signal',sender='{}',interface='{}',member='PropertiesChanged'",
+            BLUEZ, PROPERTIES
+        )).map_err(|e| e.to_string())?;
+        conn.add_match(&format!(
+            "type='signal',sender='{}',interface='{}',member='NameOwnerChanged',arg0='{}'",
+            DBUS, DBUS, BLUEZ
+        )).map_err(|e| e.to_string())?;
+
+        let snapshot = call(&conn, "/", OBJECT_MANAGER, "GetManagedObjects", &[])?;
+        self.load_snapshot(&snapshot);
+        info!("Signal dispatcher running");
+
+        loop {
+            for item in conn.iter(100) {
+                match item {
+                    ConnectionItem::Signal(ref m) => {
+                        if let Some(reason) = lost_bluez(m) {
+                            bail!(reason);
+                        }
+                        // Signals can arrive back to back while scanning
+                        self.take_subscriptions();
+                        self.handle_signal(m);
+                    }
+                    ConnectionItem::Nothing => break,
+                    _ => {}
+                }
+            }
+
+            self.take_subscriptions();
+            while let Ok(route) = self.rx_notify.try_recv() {
+                self.notify_routes.push(route);
+            }
+        }
+    }
+
+    fn take_subscriptions(&mut self) {
+        while let Ok((sub, ack)) = self.rx_subs.try_recv() {
+            self.subs.push(sub);
+            let _ = ack.send(());
+        }
+    }
+
+    fn load_snapshot(&mut self, m: &Message) {
+        let items = m.get_items();
+        let objects: &[MessageItem] = match items.first().and_then(|i| i.inner().ok()) {
+            Some(objs) => objs,
+            None => return,
+        };
+
+        let mut cache = self.cache.write().unwrap();
+        cache.objects.clear();
+        cache.unavailable = None;
+        for obj in objects {
+            if let Ok((path, ifaces)) = obj.inner::<(&MessageItem, &MessageItem)>() {
+                let path = match path.inner::<&Path>() {
+                    Ok(p) => p.to_string(),
+                    Err(_) => continue,
+                };
+                for (iface, props) in interfaces(ifaces) {
+                    cache.insert(&path, &iface, props);
+                }
+            }
+        }
+    }
+
+    fn handle_signal(&mut self, m: &Message) {
+        let (_, path, _, member) = m.headers();
+        let items = m.get_items();
+
+        let event = match (member.as_deref(), path) {
+            (Some("InterfacesAdded"), _) => {
+                let path = match items.first().and_then(|p| p.inner::<&Path>().ok()) {
+                    Some(p) => p.to_string(),
+                    None => return,
+                };
+                let ifaces = match items.get(1) {
+                    Some(i) => interfaces(i),
+                    None => return,
+                };
+
+                let names = ifaces.iter().map(|(name, _)| name.clone()).collect();
+                let mut cache = self.cache.write().unwrap();
+                for (iface, props) in ifaces {
+                    cache.insert(&path, &iface, props);
+                }
+
+                BusEvent::Added { path, ifaces: names }
+            }
+            (Some("InterfacesRemoved"), _) => {
+                let path = match items.first().and_then(|p| p.inner::<&Path>().ok()) {
+                    Some(p) => p.to_string(),
+                    None => return,
+                };
+                let ifaces = items.get(1).map(strings).unwrap_or_default();
+
+                self.cache.write().unwrap().remove(&path, &ifaces);
+
+                BusEvent::Removed { path, ifaces }
+            }
+            (Some("PropertiesChanged"), Some(path)) => {
+                let iface = match items.first().and_then(|i| i.inner::<&str>().ok()) {
+                    Some(i) => i.to_string(),
+                    None => return,
+                };
+                let changed = items.get(1).map(properties).unwrap_or_default();
+                let invalidated = items.get(2).map(strings).unwrap_or_default();
+                let props = changed.keys().chain(invalidated.iter()).cloned().collect();
+
+                if iface == CHRC_IFACE {
+                    if let Some(val) = changed.get("Value").and_then(bytes_from_item) {
+                        self.route_notification(&path, val);
+                    }
+                }
+
+                self.cache
+                    .write()
+                    .unwrap()
+                    .update(&path, &iface, changed, &invalidated);
+
+                BusEvent::Changed { path, iface, props }
+            }
+            _ => return,
+        };
+
+        trace!("{:?}", event);
+        self.subs.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    fn route_notification(&mut self, path: &str, val: Vec<u8>) {
+        let data: Box<[u8]> = val.into_boxed_slice();
+        if self.notify_routes.iter().any(|(p, _, _)| p == path) {
+            self.metrics.endpoint(path, |m| {
+                m.notifications += 1;
+                m.bytes_notified += data.len() as u64;
+            });
+            self.recorder.notification(path, &data);
+            self.capture.notification(path, &data);
+        }
+        self.notify_routes.retain(|(p, tx, closed)| {
+            if p != path || tx.send(data.clone()).is_ok() {
+                return true;
+            }
+            closed.store(true, Ordering::Relaxed);
+            false
+        });
+    }
+}
+
+/// Whether a signal means the bus connection closed, or BlueZ stopped or
+/// restarted, and the cache can no longer be trusted
+fn lost_bluez(m: &Message) -> Option<&'static str> {
+    let (_, _, iface, member) = m.headers();
+
+    match (iface.as_deref(), member.as_deref()) {
+        (Some(DBUS_LOCAL), Some("Disconnected")) => Some("disconnected from the system bus"),
+        (Some(DBUS), Some("NameOwnerChanged")) => Some("BlueZ stopped or restarted"),
+        _ => None,
+    }
+}
+
+/// Unpack an `a{sa{sv}}` of interfaces and their properties
+fn interfaces(item: &MessageItem) -> Vec<(String, Properties)> {
+    let entries: &[MessageItem] = match item.inner() {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (name, props) = entry.inner::<(&MessageItem, &MessageItem)>().ok()?;
+            Some((name.inner::<&str>().ok()?.to_string(), properties(props)))
+        })
+        .collect()
+}
+
+/// Unpack an `a{sv}`, dropping the variant wrappers
+fn properties(item: &MessageItem) -> Properties {
+    let entries: &[MessageItem] = match item.inner() {
+        Ok(e) => e,
+        Err(_) => return Properties::new(),
+    };
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (name, val) = entry.inner::<(&MessageItem, &MessageItem)>().ok()?;
+            let val = match *val {
+                MessageItem::Variant(ref inner) => (**inner).clone(),
+                ref other => other.clone(),
+            };
+            Some((name.inner::<&str>().ok()?.to_string(), val))
+        })
+        .collect()
+}
+
+fn strings(item: &MessageItem) -> Vec<String> {
+    match item.inner::<&[MessageItem]>() {
+        Ok(items) => items
+            .iter()
+            .filter_map(|s| s.inner::<&str>().ok().map(|s| s.to_string()))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::channel;
+
+    const CHRC: &str = "/org/bluez/hci0/dev_CF_75_CE_86_6D_02/service000c/char000d";
+
+    #[test]
+    fn notifications_to_dropped_receivers_close_the_route() {
+        let mut dispatcher = Dispatcher::new(
+            SharedCache::default(),
+            channel().1,
+            channel().1,
+            Metrics::default(),
+            Recorder::default(),
+            Capture::default(),
+        );
+
+        let (tx, rx) = channel();
+        let kept: Arc<AtomicBool> = Arc::default();
+        dispatcher.notify_routes.push((CHRC.to_string(), tx, kept.clone()));
+        let gone: Arc<AtomicBool> = Arc::default();
+        dispatcher.notify_routes.push((CHRC.to_string(), channel().0, gone.clone()));
+
+        dispatcher.route_notification(CHRC, vec![1, 2]);
+        assert_eq!(&*rx.try_recv().unwrap(), &[1, 2]);
+        assert!(!kept.load(Ordering::Relaxed));
+        assert!(gone.load(Ordering::Relaxed));
+        assert_eq!(dispatcher.notify_routes.len(), 1);
+    }
+
+    #[test]
+    fn subscriptions_are_acknowledged() {
+        let (tx_subs, rx_subs) = channel();
+        let mut dispatcher = Dispatcher::new(
+            SharedCache::default(),
+            rx_subs,
+            channel().1,
+            Metrics::default(),
+            Recorder::default(),
+            Capture::default(),
+        );
+
+        let subscriber = ::std::thread::spawn(move || subscribe(&tx_subs));
+        while dispatcher.subs.is_empty() {
+            dispatcher.take_subscriptions();
+        }
+
+        assert!(subscriber.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn subscribing_fails_without_a_dispatcher() {
+        let (tx_subs, rx_subs) = channel();
+        drop(rx_subs);
+
+        assert!(subscribe(&tx_subs).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+//! Byte streams over raw Bluetooth sockets, RFCOMM and L2CAP. The socket is
+//! reconnected following a `ReconnectPolicy` until the stream is dropped.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration as OldDuration;
+
+use libc;
+
+use Duration;
+use BtMacAddress;
+use ByteStream;
+use connection::ReconnectPolicy;
+use errors::*;
+
+const BTPROTO_L2CAP: libc::c_int = 0;
+const BTPROTO_RFCOMM: libc::c_int = 3;
+
+const BDADDR_LE_PUBLIC: u8 = 1;
+const BDADDR_LE_RANDOM: u8 = 2;
+
+/// Shortest wait between connection attempts
+const RETRY_FLOOR_MS: u64 = 1000;
+
+/// Largest single read, L2CAP packets can be up to 64kB
+const READ_BUF_LEN: usize = 65536;
+
+#[repr(C)]
+struct SockaddrRc {
+    rc_family: libc::sa_family_t,
+    rc_bdaddr: [u8; 6],
+    rc_channel: u8,
+}
+
+#[repr(C)]
+struct SockaddrL2 {
+    l2_family: libc::sa_family_t,
+    /// Little endian
+    l2_psm: u16,
+    l2_bdaddr: [u8; 6],
+    l2_cid: u16,
+    l2_bdaddr_type: u8,
+}
+
+/// BlueZ sockets take addresses least significant byte first
+pub fn bdaddr(mac: &BtMacAddress) -> [u8; 6] {
+    let mut addr = [0u8; 6];
+    addr.copy_from_slice(mac.0.as_bytes());
+    addr.reverse();
+    addr
+}
+
+/// Open a socket, optionally bind it to `local`, and connect it to `addr`
+pub fn connect_raw<A>(
+    kind: libc::c_int,
+    proto: libc::c_int,
+    local: Option<&A>,
+    addr: &A,
+) -> Result<File> {
+    unsafe {
+        let fd = libc::socket(libc::AF_BLUETOOTH, kind | libc::SOCK_CLOEXEC, proto);
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "failed to open socket");
+        }
+
+        // Owned from here on, so it is closed on any error
+        let sock = File::from_raw_fd(fd);
+        let len = mem::size_of::<A>() as libc::socklen_t;
+
+        if let Some(local) = local {
+            if libc::bind(fd, local as *const A as *const libc::sockaddr, len) < 0 {
+                return Err(io::Error::last_os_error()).chain_err(|| "failed to bind socket");
+            }
+        }
+
+        if libc::connect(fd, addr as *const A as *const libc::sockaddr, len) < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "failed to connect socket");
+        }
+
+        Ok(sock)
+    }
+}
+
+pub fn connect_rfcomm(mac: &BtMacAddress, channel: u8) -> Result<File> {
+    let addr = SockaddrRc {
+        rc_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        rc_bdaddr: bdaddr(mac),
+        rc_channel: channel,
+    };
+
+    connect_raw(libc::SOCK_STREAM, BTPROTO_RFCOMM, None, &addr)
+}
+
+/// Connect an LE connection-oriented channel to a PSM
+pub fn connect_l2cap(mac: &BtMacAddress, psm: u16, random_address: bool) -> Result<File> {
+    let addr_type = if random_address { BDADDR_LE_RANDOM } else { BDADDR_LE_PUBLIC };
+
+    // Bind to any local adapter, on the LE transport
+    let local = SockaddrL2 {
+        l2_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        l2_psm: 0,
+        l2_bdaddr: [0; 6],
+        l2_cid: 0,
+        l2_bdaddr_type: BDADDR_LE_PUBLIC,
+    };
+    let addr = SockaddrL2 {
+        l2_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        l2_psm: psm.to_le(),
+        l2_bdaddr: bdaddr(mac),
+        l2_cid: 0,
+        l2_bdaddr_type: addr_type,
+    };
+
+    connect_raw(libc::SOCK_SEQPACKET, BTPROTO_L2CAP, Some(&local), &addr)
+}
+
+/// Forward everything read from a socket, one message per read, calling
+/// `on_read` with each. The returned receiver disconnects once the socket
+/// closes, or receives `()` first if it was the subscriber that went away
+pub fn forward_reads<F>(mut sock: File, buf_len: usize, tx: Sender<Box<[u8]>>, on_read: F) -> Receiver<()>
+where
+    F: Fn(&[u8]) + Send + 'static,
+{
+    let (tx_alive, rx_alive) = channel::<()>();
+
+    thread::spawn(move || {
+        let mut buf = vec![0u8; buf_len];
+
+        loop {
+            match sock.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    on_read(&buf[..n]);
+                    if tx.send(buf[..n].to_vec().into_boxed_slice()).is_err() {
+                        let _ = tx_alive.send(());
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("Socket closed, {:?}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    rx_alive
+}
+
+/// Keep a socket connected, moving data between it and a pair of channels,
+/// until the caller drops both ends, or the policy gives up. `keepalive`
+/// is called regularly while the stream is wanted
+pub fn spawn_stream<F, K>(
+    name: String,
+    connect: F,
+    keepalive: K,
+    policy: ReconnectPolicy,
+) -> ByteStream
+where
+    F: Fn() -> Result<File> + Send + 'static,
+    K: Fn() + Send + 'static,
+{
+    let (tx_out, rx_out) = channel::<Box<[u8]>>();
+    let (tx_in, rx_in) = channel();
+
+    thread::spawn(move || {
+        let mut attempts = 0;
+        let mut backoff = Duration::zero();
+        // A message whose write failed, sent again once reconnected
+        let mut pending = None;
+
+        loop {
+            keepalive();
+
+            match connect() {
+                Ok(sock) => {
+                    info!("{} connected", name);
+                    attempts = 0;
+                    backoff = Duration::zero();
+
+                    if !pump(sock, &rx_out, &tx_in, &mut pending, &keepalive) {
+                        info!("{} closed", name);
+                        return;
+                    }
+                    warn!("{} disconnected", name);
+                }
+                Err(e) => {
+                    attempts += 1;
+                    debug!("{} failed to connect, {:?}", name, e);
+
+                    if policy.max_attempts.is_some_and(|max| attempts >= max) {
+                        warn!("Giving up on {} after {} attempts", name, attempts);
+                        return;
+                    }
+
+                    backoff = policy.next_backoff(backoff);
+                    let wait = backoff.to_std().unwrap_or_default();
+                    thread::sleep(wait.max(OldDuration::from_millis(RETRY_FLOOR_MS)));
+                }
+            }
+        }
+    });
+
+    (tx_out, rx_in)
+}
+
+/// Move data until the socket closes, returns false if the caller is gone.
+/// A message that couldn't be written is left in `pending`, and is written
+/// first by the next call
+fn pump<K: Fn()>(
+    mut sock: File,
+    rx_out: &Receiver<Box<[u8]>>,
+    tx_in: &Sender<Box<[u8]>>,
+    pending: &mut Option<Box<[u8]>>,
+    keepalive: &K,
+) -> bool {
+    let reader = match sock.try_clone() {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to clone socket, {:?}", e);
+            return true;
+        }
+    };
+    let alive = forward_reads(reader, READ_BUF_LEN, tx_in.clone(), |_: &[u8]| {});
+    // Whether the caller still has its `Receiver`, found out on a read
+    let mut reading = true;
+
+    let open = loop {
+        keepalive();
+
+        let msg = match pending.take() {
+            Some(msg) => Some(msg),
+            None => match rx_out.recv_timeout(OldDuration::from_millis(100)) {
+                Ok(msg) => Some(msg),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => {
+                    if !reading {
+                        break false;
+                    }
+                    thread::sleep(OldDuration::from_millis(100));
+                    None
+                }
+            },
+        };
+
+        if let Some(msg) = msg {
+            if let Err(e) = sock.write_all(&msg) {
+                debug!("Write failed, {:?}", e);
+                *pending = Some(msg);
+                break true;
+            }
+        }
+
+        if reading {
+            match alive.try_recv() {
+                Ok(()) => {
+                    debug!("Nobody is reading anymore");
+                    reading = false;
+                }
+                Err(TryRecvError::Disconnected) => break true,
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+    };
+
+    // Wake the reader thread, it holds its own copy of the socket
+    unsafe {
+        libc::shutdown(sock.as_raw_fd(), libc::SHUT_RDWR);
+    }
+
+    open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::str::FromStr;
+
+    /// A socket for `forward_reads`, and the device end of it
+    fn socket() -> (File, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        (File::from(OwnedFd::from(ours)), theirs)
+    }
+
+    /// Pump a socket on its own thread, as `spawn_stream` would
+    fn pumping(
+        sock: File,
+        rx_out: Receiver<Box<[u8]>>,
+        tx_in: Sender<Box<[u8]>>,
+        mut pending: Option<Box<[u8]>>,
+    ) -> thread::JoinHandle<(bool, Option<Box<[u8]>>)> {
+        thread::spawn(move || {
+            let open = pump(sock, &rx_out, &tx_in, &mut pending, &|| {});
+            (open, pending)
+        })
+    }
+
+    #[test]
+    fn addresses_are_reversed() {
+        let mac = BtMacAddress::from_str("CF:75:CE:86:6D:02").unwrap();
+        assert_eq!(bdaddr(&mac), [0x02, 0x6d, 0x86, 0xce, 0x75, 0xcf]);
+    }
+
+    #[test]
+    fn failed_writes_are_resent_after_reconnecting() {
+        let (tx_out, rx_out) = channel();
+        let (tx_in, _rx_in) = channel();
+
+        let (sock, device) = socket();
+        drop(device);
+        tx_out.send(b"lost".to_vec().into_boxed_slice()).unwrap();
+        let (open, pending) = pumping(sock, rx_out, tx_in.clone(), None).join().unwrap();
+        assert!(open);
+        assert_eq!(pending.as_deref(), Some(&b"lost"[..]));
+
+        let (sock, mut device) = socket();
+        let (_tx_out, rx_out) = channel();
+        let pump = pumping(sock, rx_out, tx_in, pending);
+        let mut buf = [0u8; 4];
+        device.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"lost");
+
+        drop(device);
+        assert_eq!(pump.join().unwrap(), (true, None));
+    }
+
+    #[test]
+    fn streams_end_once_both_ends_are_dropped() {
+        let (tx_out, rx_out) = channel();
+        let (tx_in, rx_in) = channel();
+        let (sock, mut device) = socket();
+        let pump = pumping(sock, rx_out, tx_in, None);
+
+        // Still written to after the caller stops reading
+        drop(rx_in);
+        device.write_all(b"unread").unwrap();
+        thread::sleep(OldDuration::from_millis(300));
+        tx_out.send(b"sent".to_vec().into_boxed_slice()).unwrap();
+        let mut buf = [0u8; 4];
+        device.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"sent");
+
+        drop(tx_out);
+        assert_eq!(pump.join().unwrap(), (false, None));
+    }
+
+    #[test]
+    fn streams_are_still_read_without_a_sender() {
+        let (tx_out, rx_out) = channel::<Box<[u8]>>();
+        let (tx_in, rx_in) = channel();
+        let (sock, mut device) = socket();
+        drop(tx_out);
+        let pump = pumping(sock, rx_out, tx_in, None);
+
+        device.write_all(b"read").unwrap();
+        assert_eq!(&*rx_in.recv_timeout(OldDuration::from_secs(5)).unwrap(), b"read");
+
+        drop(rx_in);
+        device.write_all(b"unread").unwrap();
+        assert_eq!(pump.join().unwrap(), (false, None));
+    }
+
+    #[test]
+    fn forwarding_stops_when_the_subscriber_is_gone() {
+        let (sock, mut device) = socket();
+        let (tx, rx) = channel();
+        let alive = forward_reads(sock, 16, tx, |_: &[u8]| {});
+
+        device.write_all(b"one").unwrap();
+        assert_eq!(&*rx.recv_timeout(OldDuration::from_secs(5)).unwrap(), b"one");
+        assert_eq!(alive.try_recv(), Err(TryRecvError::Empty));
+
+        drop(rx);
+        device.write_all(b"two").unwrap();
+        assert_eq!(alive.recv_timeout(OldDuration::from_secs(5)), Ok(()));
+        assert_eq!(alive.recv_timeout(OldDuration::from_secs(5)), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn forwarding_stops_when_the_socket_closes() {
+        let (sock, device) = socket();
+        let (tx, _rx) = channel();
+        let alive = forward_reads(sock, 16, tx, |_: &[u8]| {});
+
+        drop(device);
+        assert_eq!(alive.recv_timeout(OldDuration::from_secs(5)), Err(RecvTimeoutError::Disconnected));
+    }
+}
@@ -0,0 +1,456 @@
+//! Captures of the ATT operations performed through easy-bluez, for opening
+//! in Wireshark. Enabled with `EasyBluez::capture`.
+//!
+//! BlueZ does not expose the packets themselves, so each read, write and
+//! notification is written as the ATT PDUs it stands for, addressed to the
+//! characteristic's value handle. Payloads are captured as handed to or
+//! from BlueZ, rather than split the way they were sent over the air.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use BtMacAddress;
+use bt_manager::signals::SharedCache;
+use errors::*;
+
+/// Microseconds from year 0 to the Unix epoch, as used by btsnoop
+const BTSNOOP_EPOCH_DELTA: u64 = 0x00dc_ddb3_0f2f_8000;
+/// btsnoop datalink for HCI UART (H4) packets
+const BTSNOOP_HCI_UART: u32 = 1002;
+const H4_ACL: u8 = 0x02;
+
+/// Bluetooth LE link layer, with the radio pseudo-header
+const LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR: u16 = 256;
+/// Largest link layer data payload
+const LL_MAX_PAYLOAD: usize = 251;
+/// Start of an L2CAP frame, and its continuation fragments
+const LLID_START: u8 = 0x02;
+const LLID_CONTINUATION: u8 = 0x01;
+/// Pseudo-header flags: dewhitened, reference access address valid
+const PHDR_FLAGS: u16 = 0x0001 | 0x0010;
+/// Pseudo-header PDU types for data from the central, and the peripheral.
+/// 0 is unspecified, and 1 auxiliary advertising
+const PHDR_CENTRAL_TO_PERIPHERAL: u16 = 2 << 7;
+const PHDR_PERIPHERAL_TO_CENTRAL: u16 = 3 << 7;
+/// Access addresses are made up, one per device
+const ACCESS_ADDRESS_BASE: u32 = 0x5065_0000;
+
+const L2CAP_ATT_CID: u16 = 0x0004;
+
+const ATT_READ_REQ: u8 = 0x0a;
+const ATT_READ_RSP: u8 = 0x0b;
+const ATT_WRITE_REQ: u8 = 0x12;
+const ATT_WRITE_RSP: u8 = 0x13;
+const ATT_WRITE_CMD: u8 = 0x52;
+const ATT_HANDLE_VALUE_NTF: u8 = 0x1b;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// btsnoop, as HCI ACL packets, the way Android and `btmon` log them
+    Btsnoop,
+    /// pcap-ng, as Bluetooth LE link layer packets
+    PcapNg,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    ToDevice,
+    FromDevice,
+}
+
+pub(crate) struct CaptureFile {
+    out: BufWriter<File>,
+    format: CaptureFormat,
+    /// Devices in the order they were first seen, numbering their
+    /// connections from 1
+    devices: Vec<BtMacAddress>,
+}
+
+impl CaptureFile {
+    /// Create the file at `path`, and write the format's header
+    pub fn create(path: &Path, format: CaptureFormat) -> Result<Self> {
+        let file = File::create(path).chain_err(|| "failed to create capture")?;
+        let mut capture = CaptureFile {
+            out: BufWriter::new(file),
+            format,
+            devices: vec![],
+        };
+
+        let header = match format {
+            CaptureFormat::Btsnoop => {
+                let mut header = b"btsnoop\0".to_vec();
+                header.extend_from_slice(&1u32.to_be_bytes());
+                header.extend_from_slice(&BTSNOOP_HCI_UART.to_be_bytes());
+                header
+            }
+            CaptureFormat::PcapNg => {
+                let mut header = pcapng_block(0x0a0d_0d0a, &section_header());
+                header.extend(pcapng_block(0x0000_0001, &interface_description()));
+                header
+            }
+        };
+        capture.write_all(&header)?;
+
+        Ok(capture)
+    }
+
+    fn connection(&mut self, mac: &BtMacAddress) -> u16 {
+        let index = match self.devices.iter().position(|d| d == mac) {
+            Some(i) => i,
+            None => {
+                self.devices.push(mac.clone());
+                self.devices.len() - 1
+            }
+        };
+        index as u16 + 1
+    }
+
+    fn packet(&mut self, mac: &BtMacAddress, dir: Direction, att: &[u8]) -> Result<()> {
+        let conn = self.connection(mac);
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+
+        let mut l2cap = Vec::with_capacity(att.len() + 4);
+        l2cap.extend_from_slice(&(att.len() as u16).to_le_bytes());
+        l2cap.extend_from_slice(&L2CAP_ATT_CID.to_le_bytes());
+        l2cap.extend_from_slice(att);
+
+        let packets = match self.format {
+            CaptureFormat::Btsnoop => vec![btsnoop_record(conn, dir, micros, &l2cap)],
+            CaptureFormat::PcapNg => l2cap
+                .chunks(LL_MAX_PAYLOAD)
+                .enumerate()
+                .map(|(i, fragment)| {
+                    let llid = if i == 0 { LLID_START } else { LLID_CONTINUATION };
+                    let ll = le_packet(conn, dir, llid, fragment);
+                    pcapng_block(0x0000_0006, &enhanced_packet(micros, &ll))
+                })
+                .collect(),
+        };
+
+        for packet in packets {
+            self.write_all(&packet)?;
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        // Flushed per packet, so a capture survives the process being killed
+        self.out
+            .write_all(bytes)
+            .and_then(|_| self.out.flush())
+            .chain_err(|| "failed to write capture")
+    }
+}
+
+/// Where the background tasks capture ATT operations. Capturing does
+/// nothing unless it is enabled
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Option<(Arc<Mutex<CaptureFile>>, SharedCache)>);
+
+impl Capture {
+    /// Capture to `file`, resolving devices through `cache`
+    pub fn new(file: CaptureFile, cache: SharedCache) -> Self {
+        Capture(Some((Arc::new(Mutex::new(file)), cache)))
+    }
+
+    pub fn read(&self, chrc_path: &str, value: &[u8]) {
+        self.operation(chrc_path, |handle| {
+            vec![
+                (Direction::ToDevice, att_pdu(ATT_READ_REQ, Some(handle), &[])),
+                (Direction::FromDevice, att_pdu(ATT_READ_RSP, None, value)),
+            ]
+        });
+    }
+
+    /// A write, with a response unless it was a write command
+    pub fn write(&self, chrc_path: &str, value: &[u8], with_response: bool) {
+        self.operation(chrc_path, |handle| {
+            if with_response {
+                vec![
+                    (Direction::ToDevice, att_pdu(ATT_WRITE_REQ, Some(handle), value)),
+                    (Direction::FromDevice, att_pdu(ATT_WRITE_RSP, None, &[])),
+                ]
+            } else {
+                vec![(Direction::ToDevice, att_pdu(ATT_WRITE_CMD, Some(handle), value))]
+            }
+        });
+    }
+
+    pub fn notification(&self, chrc_path: &str, value: &[u8]) {
+        self.operation(chrc_path, |handle| {
+            vec![(Direction::FromDevice, att_pdu(ATT_HANDLE_VALUE_NTF, Some(handle), value))]
+        });
+    }
+
+    fn operation<F>(&self, chrc_path: &str, pdus: F)
+    where
+        F: FnOnce(u16) -> Vec<(Direction, Vec<u8>)>,
+    {
+        let (ref file, ref cache) = match self.0 {
+            Some(ref c) => c,
+            None => return,
+        };
+
+        let mac = cache.read().unwrap().endpoint(chrc_path).map(|ep| ep.mac);
+        let (mac, handle) = match (mac, value_handle(chrc_path)) {
+            (Some(mac), Some(handle)) => (mac, handle),
+            _ => {
+                debug!("Not capturing {}, its device or handle is unknown", chrc_path);
+                return;
+            }
+        };
+
+        let mut file = file.lock().unwrap();
+        for (dir, att) in pdus(handle) {
+            if let Err(e) = file.packet(&mac, dir, &att) {
+                error!("Failed to capture, {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Value handle of a characteristic. BlueZ names characteristics after
+/// their declaration handle, which the value immediately follows
+fn value_handle(chrc_path: &str) -> Option<u16> {
+    let name = chrc_path.rsplit('/').next()?;
+    let declaration = u16::from_str_radix(name.trim_start_matches("char"), 16).ok()?;
+    declaration.checked_add(1)
+}
+
+fn att_pdu(opcode: u8, handle: Option<u16>, value: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![opcode];
+    if let Some(h) = handle {
+        pdu.extend_from_slice(&h.to_le_bytes());
+    }
+    pdu.extend_from_slice(value);
+    pdu
+}
+
+fn btsnoop_record(conn: u16, dir: Direction, micros: u64, l2cap: &[u8]) -> Vec<u8> {
+    // Packet boundary: first non-flushable from the host, first flushable
+    // from the controller
+    let (flags, boundary) = match dir {
+        Direction::ToDevice => (0u32, 0x0000),
+        Direction::FromDevice => (1u32, 0x2000),
+    };
+
+    let mut h4 = vec![H4_ACL];
+    h4.extend_from_slice(&((conn & 0x0fff) | boundary).to_le_bytes());
+    h4.extend_from_slice(&(l2cap.len() as u16).to_le_bytes());
+    h4.extend_from_slice(l2cap);
+
+    let mut record = vec![];
+    record.extend_from_slice(&(h4.len() as u32).to_be_bytes());
+    record.extend_from_slice(&(h4.len() as u32).to_be_bytes());
+    record.extend_from_slice(&flags.to_be_bytes());
+    record.extend_from_slice(&0u32.to_be_bytes());
+    record.extend_from_slice(&(micros + BTSNOOP_EPOCH_DELTA).to_be_bytes());
+    record.extend(h4);
+    record
+}
+
+/// A link layer data packet with its pseudo-header. The CRC is left zero,
+/// and marked as not checked
+fn le_packet(conn: u16, dir: Direction, llid: u8, payload: &[u8]) -> Vec<u8> {
+    let access_address = ACCESS_ADDRESS_BASE | u32::from(conn);
+    let pdu_type = match dir {
+        Direction::ToDevice => PHDR_CENTRAL_TO_PERIPHERAL,
+        Direction::FromDevice => PHDR_PERIPHERAL_TO_CENTRAL,
+    };
+
+    // RF channel, signal power, noise power, access address offenses
+    let mut packet = vec![1, 0, 0, 0];
+    packet.extend_from_slice(&access_address.to_le_bytes());
+    packet.extend_from_slice(&(PHDR_FLAGS | pdu_type).to_le_bytes());
+
+    packet.extend_from_slice(&access_address.to_le_bytes());
+    packet.push(llid);
+    packet.push(payload.len() as u8);
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(&[0, 0, 0]);
+    packet
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Section length is not known up front
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    body
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // No snapshot length limit
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body
+}
+
+fn enhanced_packet(micros: u64, packet: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+    body
+}
+
+/// Wrap a block body with its type, and its total length on both ends
+fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total = (body.len() + 12) as u32;
+
+    let mut block = vec![];
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total.to_le_bytes());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// A read request for handle 0x000c, wrapped in L2CAP
+    const READ_REQ_L2CAP: [u8; 7] = [0x03, 0x00, 0x04, 0x00, 0x0a, 0x0c, 0x00];
+
+    #[test]
+    fn value_handle_follows_declaration() {
+        assert_eq!(value_handle("/org/bluez/hci0/dev_CF_75_CE_86_6D_02/service000a/char000b"), Some(0x000c));
+        assert_eq!(value_handle("/org/bluez/hci0/dev_CF_75_CE_86_6D_02/service000a/char00ff"), Some(0x0100));
+        assert_eq!(value_handle("/org/bluez/hci0/dev_CF_75_CE_86_6D_02/service000a/charffff"), None);
+        assert_eq!(value_handle("/org/bluez/hci0/dev_CF_75_CE_86_6D_02/service000a/char000b/desc000d"), None);
+    }
+
+    #[test]
+    fn att_pdu_layout() {
+        assert_eq!(att_pdu(ATT_READ_REQ, Some(0x1234), &[]), vec![0x0a, 0x34, 0x12]);
+        assert_eq!(att_pdu(ATT_READ_RSP, None, &[1, 2]), vec![0x0b, 1, 2]);
+        assert_eq!(att_pdu(ATT_HANDLE_VALUE_NTF, Some(0x000c), &[9]), vec![0x1b, 0x0c, 0x00, 9]);
+    }
+
+    #[test]
+    fn btsnoop_record_to_device() {
+        let record = btsnoop_record(1, Direction::ToDevice, 0, &READ_REQ_L2CAP);
+
+        let mut expected = vec![
+            0x00, 0x00, 0x00, 0x0c, // original length
+            0x00, 0x00, 0x00, 0x0c, // included length
+            0x00, 0x00, 0x00, 0x00, // flags: sent, data
+            0x00, 0x00, 0x00, 0x00, // drops
+            0x00, 0xdc, 0xdd, 0xb3, 0x0f, 0x2f, 0x80, 0x00, // Unix epoch
+            0x02, 0x01, 0x00, 0x07, 0x00, // H4 ACL, handle 1, length
+        ];
+        expected.extend_from_slice(&READ_REQ_L2CAP);
+        assert_eq!(record, expected);
+    }
+
+    #[test]
+    fn btsnoop_record_from_device() {
+        let record = btsnoop_record(2, Direction::FromDevice, 1, &READ_REQ_L2CAP);
+
+        assert_eq!(&record[8..12], &[0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(&record[16..24], &[0x00, 0xdc, 0xdd, 0xb3, 0x0f, 0x2f, 0x80, 0x01]);
+        assert_eq!(&record[24..29], &[0x02, 0x02, 0x20, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn le_packet_to_device() {
+        let packet = le_packet(1, Direction::ToDevice, LLID_START, &[0xaa]);
+
+        assert_eq!(
+            packet,
+            vec![
+                0x01, 0x00, 0x00, 0x00, // channel, signal, noise, offenses
+                0x01, 0x00, 0x65, 0x50, // reference access address
+                0x11, 0x01, // flags, data central to peripheral
+                0x01, 0x00, 0x65, 0x50, // access address
+                0x02, 0x01, 0xaa, // header and payload
+                0x00, 0x00, 0x00, // CRC
+            ]
+        );
+    }
+
+    #[test]
+    fn le_packet_from_device() {
+        let packet = le_packet(3, Direction::FromDevice, LLID_CONTINUATION, &[]);
+
+        assert_eq!(&packet[4..8], &[0x03, 0x00, 0x65, 0x50]);
+        assert_eq!(&packet[8..10], &[0x91, 0x01]);
+        assert_eq!(&packet[14..16], &[0x01, 0x00]);
+    }
+
+    #[test]
+    fn pcapng_block_wraps_body() {
+        assert_eq!(
+            pcapng_block(6, &[1, 2, 3, 4]),
+            vec![6, 0, 0, 0, 16, 0, 0, 0, 1, 2, 3, 4, 16, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn enhanced_packet_is_padded() {
+        assert_eq!(
+            enhanced_packet(0x0000_0001_0000_0002, &[9]),
+            vec![
+                0, 0, 0, 0, // interface
+                1, 0, 0, 0, // timestamp, high
+                2, 0, 0, 0, // timestamp, low
+                1, 0, 0, 0, // captured length
+                1, 0, 0, 0, // original length
+                9, 0, 0, 0, // packet and padding
+            ]
+        );
+    }
+
+    fn created(name: &str, format: CaptureFormat) -> Vec<u8> {
+        let path = env::temp_dir().join(format!("easy-bluez-{}-{}", name, std::process::id()));
+        CaptureFile::create(&path, format).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        bytes
+    }
+
+    #[test]
+    fn btsnoop_header() {
+        assert_eq!(
+            created("btsnoop", CaptureFormat::Btsnoop),
+            b"btsnoop\0\x00\x00\x00\x01\x00\x00\x03\xea".to_vec()
+        );
+    }
+
+    #[test]
+    fn pcapng_header() {
+        assert_eq!(
+            created("pcapng", CaptureFormat::PcapNg),
+            vec![
+                0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0, // section header
+                0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, // byte order, version 1.0
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // length unknown
+                28, 0, 0, 0,
+                1, 0, 0, 0, 20, 0, 0, 0, // interface description
+                0x00, 0x01, 0, 0, // LE link layer with pseudo-header
+                0, 0, 0, 0, // no snapshot length
+                20, 0, 0, 0,
+            ]
+        );
+    }
+}
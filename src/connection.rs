@@ -0,0 +1,128 @@
+//! Types controlling how managed devices are connected, and the events
+//! reported as their connection state changes.
+
+use Duration;
+use BtMacAddress;
+
+/// A change in the connection state of a managed device
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected(BtMacAddress),
+    Disconnected(BtMacAddress),
+    /// The device has not been connected for longer than the missing threshold
+    Missing(BtMacAddress),
+    /// The reconnect policy ran out of attempts
+    GaveUp(BtMacAddress),
+}
+
+/// A snapshot of a managed device's connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceStatus {
+    pub connected: bool,
+    /// Negotiated ATT MTU, on BlueZ versions that report it
+    pub mtu: Option<u16>,
+    /// Last signal strength BlueZ reported, in dBm
+    pub rssi: Option<i16>,
+    /// How long the current connection has been up
+    pub connected_for: Option<Duration>,
+    /// Total time connected, including the current connection
+    pub uptime: Duration,
+    /// Number of connections established
+    pub connections: u32,
+}
+
+/// When managed devices are connected
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ConnectMode {
+    /// Keep every device connected at all times
+    #[default]
+    Persistent,
+    /// Only connect while reads, writes or subscriptions are waiting on a
+    /// device, and disconnect once it has been idle for `idle_timeout`
+    OnDemand { idle_timeout: Duration },
+}
+
+/// How important it is for a device to hold one of the limited
+/// connection slots
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Always connected, never gives up its slot
+    Pinned,
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// What to do once a reconnect policy runs out of attempts
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum GiveUpAction {
+    /// Stop reconnecting, but keep the device and report `GaveUp`
+    EmitEvent,
+    /// Stop managing the device, and remove it from the whitelist
+    Forget,
+    /// Drop the device, and wait for discovery to find it again
+    Rediscover,
+}
+
+/// How to retry connecting to a device that is not connected
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    pub(crate) multiplier: u32,
+    pub(crate) max_attempts: Option<u32>,
+    pub(crate) give_up: GiveUpAction,
+}
+
+impl Default for ReconnectPolicy {
+    /// Retry on every connection tick, forever
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::zero(),
+            max_backoff: Duration::zero(),
+            multiplier: 1,
+            max_attempts: None,
+            give_up: GiveUpAction::EmitEvent,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Double the wait between attempts after each failure, starting at
+    /// `initial` and capped at `max`
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        ReconnectPolicy {
+            initial_backoff: initial,
+            max_backoff: max,
+            multiplier: 2,
+            ..Self::default()
+        }
+    }
+
+    /// Factor the wait grows by after each failed attempt
+    pub fn multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Give up after this many consecutive failed attempts
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    pub fn on_give_up(mut self, action: GiveUpAction) -> Self {
+        self.give_up = action;
+        self
+    }
+
+    /// Wait before the next attempt, given the wait before the last one
+    pub(crate) fn next_backoff(&self, last: Duration) -> Duration {
+        if last.is_zero() {
+            return self.initial_backoff;
+        }
+
+        ::std::cmp::min(last * self.multiplier as i32, self.max_backoff)
+    }
+}
@@ -0,0 +1,33 @@
+//! Descriptions of remote devices and their GATT layout, as BlueZ last
+//! reported them.
+
+use uuid::Uuid;
+
+use BtMacAddress;
+
+/// A device heard while scanning
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ScannedDevice {
+    pub mac: BtMacAddress,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    /// Advertised service UUIDs
+    pub services: Vec<Uuid>,
+    pub connected: bool,
+    pub paired: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ServiceInfo {
+    pub uuid: Uuid,
+    pub primary: bool,
+    pub characteristics: Vec<CharacteristicInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CharacteristicInfo {
+    pub uuid: Uuid,
+    /// Supported operations, such as `read` or `notify`
+    pub flags: Vec<String>,
+    pub descriptors: Vec<Uuid>,
+}
@@ -0,0 +1,597 @@
+//! Nordic Secure DFU, updating the firmware of nRF5 devices from a DFU zip
+//! package as produced by `nrfutil`. Updates are run with
+//! `EasyBluezHandle::dfu`.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, Sender};
+
+use eui48::MacAddress;
+use serde_json::{self, Value};
+use zip::ZipArchive;
+
+use {BtMacAddress, Duration, EasyBluezHandle};
+use errors::*;
+use services::uuid_from_u16;
+use writes::{WriteKind, WriteOptions, DEFAULT_MTU};
+
+/// Secure DFU service
+pub const DFU_SERVICE: u16 = 0xFE59;
+pub const DFU_CONTROL_POINT: &str = "8ec90001-f315-4f60-9fb8-838830daea50";
+pub const DFU_PACKET: &str = "8ec90002-f315-4f60-9fb8-838830daea50";
+/// Buttonless DFU without bonds, used to reboot into the bootloader
+pub const DFU_BUTTONLESS: &str = "8ec90003-f315-4f60-9fb8-838830daea50";
+
+/// Images in the order they must be sent, by manifest key
+const IMAGE_KINDS: [&str; 4] = ["softdevice_bootloader", "softdevice", "bootloader", "application"];
+
+const OP_CREATE: u8 = 0x01;
+const OP_SET_PRN: u8 = 0x02;
+const OP_CALC_CHECKSUM: u8 = 0x03;
+const OP_EXECUTE: u8 = 0x04;
+const OP_SELECT: u8 = 0x06;
+const OP_RESPONSE: u8 = 0x60;
+
+const BUTTONLESS_ENTER: u8 = 0x01;
+const BUTTONLESS_RESPONSE: u8 = 0x20;
+
+const OBJ_COMMAND: u8 = 0x01;
+const OBJ_DATA: u8 = 0x02;
+
+const RES_SUCCESS: u8 = 0x01;
+
+/// How often to repeat the first request, while waiting for the device
+/// to connect and enable notifications
+const FIRST_CONTACT_RETRY_MS: i64 = 2000;
+
+/// One firmware image of a DFU package
+#[derive(Clone, Debug)]
+pub struct DfuImage {
+    /// Manifest key, such as `application`
+    pub kind: String,
+    pub init_packet: Vec<u8>,
+    pub firmware: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DfuPackage {
+    pub images: Vec<DfuImage>,
+}
+
+impl DfuPackage {
+    /// Load a DFU zip package
+    pub fn from_zip<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path).chain_err(|| "failed to open DFU package")?;
+        let mut zip = ZipArchive::new(file).chain_err(|| "not a zip file")?;
+
+        let mut images = vec![];
+        for (kind, dat_file, bin_file) in manifest_images(&read_entry(&mut zip, "manifest.json")?)? {
+            images.push(DfuImage {
+                kind: kind.to_string(),
+                init_packet: read_entry(&mut zip, &dat_file)?,
+                firmware: read_entry(&mut zip, &bin_file)?,
+            });
+        }
+
+        if images.is_empty() {
+            bail!("DFU package contains no images");
+        }
+
+        Ok(DfuPackage { images })
+    }
+
+    /// A package of a single application image
+    pub fn application(init_packet: Vec<u8>, firmware: Vec<u8>) -> Self {
+        DfuPackage {
+            images: vec![DfuImage {
+                kind: "application".to_string(),
+                init_packet,
+                firmware,
+            }],
+        }
+    }
+}
+
+/// The images listed in a package's `manifest.json`, in the order they are
+/// sent, as their kind and init packet and firmware file names
+fn manifest_images(manifest: &[u8]) -> Result<Vec<(&'static str, String, String)>> {
+    let manifest: Value = serde_json::from_slice(manifest).chain_err(|| "invalid manifest.json")?;
+
+    let mut images = vec![];
+    for kind in IMAGE_KINDS.iter() {
+        let entry = match manifest["manifest"].get(*kind) {
+            Some(e) => e,
+            None => continue,
+        };
+
+        let name = |key: &str| match entry[key].as_str() {
+            Some(n) => Ok(n.to_string()),
+            None => Err(Error::from(format!("manifest has no {} for {}", key, kind))),
+        };
+
+        images.push((*kind, name("dat_file")?, name("bin_file")?));
+    }
+
+    Ok(images)
+}
+
+fn read_entry(zip: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>> {
+    let mut entry = zip.by_name(name)
+        .chain_err(|| format!("{} missing from DFU package", name))?;
+    let mut data = vec![];
+    entry.read_to_end(&mut data).chain_err(|| "failed to read DFU package")?;
+    Ok(data)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DfuOptions {
+    pub(crate) buttonless: bool,
+    pub(crate) bootloader_mac: Option<BtMacAddress>,
+    pub(crate) prn: u16,
+    pub(crate) timeout: Duration,
+}
+
+impl Default for DfuOptions {
+    fn default() -> Self {
+        DfuOptions {
+            buttonless: false,
+            bootloader_mac: None,
+            prn: 12,
+            timeout: Duration::seconds(30),
+        }
+    }
+}
+
+impl DfuOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reboot the device into its bootloader through the buttonless DFU
+    /// service first. The bootloader then advertises with the device
+    /// address plus one, unless `bootloader_mac` says otherwise
+    pub fn buttonless(mut self) -> Self {
+        self.buttonless = true;
+        self
+    }
+
+    pub fn bootloader_mac(mut self, mac: BtMacAddress) -> Self {
+        self.bootloader_mac = Some(mac);
+        self
+    }
+
+    /// Packets sent between checksum receipts from the device, 0 to disable
+    pub fn packet_receipt_notification(mut self, prn: u16) -> Self {
+        self.prn = prn;
+        self
+    }
+
+    /// How long to wait for each response, including for the device to be
+    /// found and connected
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DfuProgress {
+    EnteringBootloader,
+    /// The bootloader responded, and the image is starting
+    Starting { image: String },
+    Uploading {
+        image: String,
+        sent: usize,
+        total: usize,
+    },
+    /// The image was validated and activated by the device
+    ImageComplete { image: String },
+    Complete,
+}
+
+/// An in-progress update of one device
+pub(crate) struct DfuSession<'a> {
+    hdl: &'a EasyBluezHandle,
+    options: &'a DfuOptions,
+    progress: &'a Sender<DfuProgress>,
+}
+
+impl<'a> DfuSession<'a> {
+    pub fn new(
+        hdl: &'a EasyBluezHandle,
+        options: &'a DfuOptions,
+        progress: &'a Sender<DfuProgress>,
+    ) -> Self {
+        DfuSession {
+            hdl,
+            options,
+            progress,
+        }
+    }
+
+    pub fn run(&self, mac_s: &str, package: &DfuPackage) -> Result<()> {
+        let mut mac = BtMacAddress::from_str(mac_s)?;
+
+        if self.options.buttonless {
+            self.report(DfuProgress::EnteringBootloader);
+            self.enter_bootloader(mac_s)?;
+
+            mac = match self.options.bootloader_mac {
+                Some(ref m) => m.clone(),
+                None => next_address(&mac),
+            };
+        }
+
+        let target = Target::open(self.hdl, &mac)?;
+
+        for image in &package.images {
+            self.report(DfuProgress::Starting {
+                image: image.kind.clone(),
+            });
+
+            self.send_init_packet(&target, image)?;
+            self.send_firmware(&target, image)?;
+
+            self.report(DfuProgress::ImageComplete {
+                image: image.kind.clone(),
+            });
+        }
+
+        self.report(DfuProgress::Complete);
+        Ok(())
+    }
+
+    fn report(&self, ev: DfuProgress) {
+        info!("DFU: {:?}", ev);
+        let _ = self.progress.send(ev);
+    }
+
+    fn enter_bootloader(&self, mac_s: &str) -> Result<()> {
+        let svc = uuid_from_u16(DFU_SERVICE).hyphenated().to_string();
+        let rx = self.hdl.notify(mac_s, &svc, DFU_BUTTONLESS)?;
+        let tx = self.hdl.writeable_with(
+            mac_s,
+            &svc,
+            DFU_BUTTONLESS,
+            WriteOptions::new().kind(WriteKind::Request),
+        )?;
+
+        let rsp = request_with_retry(&tx, &rx, &[BUTTONLESS_ENTER], self.options.timeout)?;
+        match *rsp {
+            [BUTTONLESS_RESPONSE, BUTTONLESS_ENTER, RES_SUCCESS, ..] => Ok(()),
+            _ => bail!("device refused to enter the bootloader: {:?}", rsp),
+        }
+    }
+
+    fn send_init_packet(&self, target: &Target, image: &DfuImage) -> Result<()> {
+        let timeout = self.options.timeout;
+
+        // The first request also waits out connecting to the bootloader
+        let rsp = request_with_retry(&target.control, &target.responses, &[OP_SET_PRN, 0, 0], timeout)?;
+        check_response(OP_SET_PRN, &rsp)?;
+
+        let (max_size, _, _) = target.select(OBJ_COMMAND, timeout)?;
+        if image.init_packet.len() > max_size as usize {
+            bail!("init packet too large for the device");
+        }
+
+        target.request(&create(OBJ_COMMAND, image.init_packet.len()), timeout)?;
+        target.packets
+            .send(image.init_packet.clone().into_boxed_slice())
+            .chain_err(|| "")?;
+        target.verify(&image.init_packet, timeout)?;
+        target.request(&[OP_EXECUTE], timeout)?;
+
+        Ok(())
+    }
+
+    fn send_firmware(&self, target: &Target, image: &DfuImage) -> Result<()> {
+        let timeout = self.options.timeout;
+        let prn = self.options.prn;
+
+        target.request(&[OP_SET_PRN, prn as u8, (prn >> 8) as u8], timeout)?;
+        let (max_size, _, _) = target.select(OBJ_DATA, timeout)?;
+
+        // Each message is split into packets of this size by the write task
+        let mtu = self.hdl
+            .device_status(&target.mac_s)
+            .ok()
+            .and_then(|s| s.mtu)
+            .unwrap_or(DEFAULT_MTU);
+        let packet_len = (mtu - 3) as usize;
+        let group_len = if prn == 0 { max_size as usize } else { packet_len * prn as usize };
+
+        let fw = &image.firmware;
+        let total = fw.len();
+        let mut sent = 0;
+
+        for object in fw.chunks(max_size as usize) {
+            target.request(&create(OBJ_DATA, object.len()), timeout)?;
+
+            for group in object.chunks(group_len) {
+                target.packets
+                    .send(group.to_vec().into_boxed_slice())
+                    .chain_err(|| "")?;
+                sent += group.len();
+
+                if prn != 0 && group.len() == group_len {
+                    // Wait for the packet receipt, which carries a checksum
+                    let rsp = target.response(timeout)?;
+                    let (offset, crc) = parse_checksum(&rsp)?;
+                    if offset > sent || crc != crc32(&fw[..offset]) {
+                        bail!("checksum mismatch at offset {}", offset);
+                    }
+                }
+            }
+
+            target.verify(&fw[..sent], timeout)?;
+
+            // The device resets after executing the last object, and may
+            // not get a response out
+            let last = sent == total;
+            match target.request(&[OP_EXECUTE], timeout) {
+                Err(_) if last => warn!("No response to the final execute"),
+                res => {
+                    res?;
+                }
+            }
+
+            self.report(DfuProgress::Uploading {
+                image: image.kind.clone(),
+                sent,
+                total,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The control point and packet endpoints of a device in its bootloader
+struct Target {
+    mac_s: String,
+    control: Sender<Box<[u8]>>,
+    packets: Sender<Box<[u8]>>,
+    responses: Receiver<Box<[u8]>>,
+}
+
+impl Target {
+    fn open(hdl: &EasyBluezHandle, mac: &BtMacAddress) -> Result<Self> {
+        let mac_s = mac.0.to_hex_string().to_uppercase();
+        let svc = uuid_from_u16(DFU_SERVICE).hyphenated().to_string();
+
+        let responses = hdl.notify(&mac_s, &svc, DFU_CONTROL_POINT)?;
+
+        // Registered first, so that queued data is always written before a
+        // control point request that follows it
+        let packets = hdl.writeable_with(
+            &mac_s,
+            &svc,
+            DFU_PACKET,
+            WriteOptions::new().kind(WriteKind::Command).chunked(),
+        )?;
+        let control = hdl.writeable_with(
+            &mac_s,
+            &svc,
+            DFU_CONTROL_POINT,
+            WriteOptions::new().kind(WriteKind::Request),
+        )?;
+
+        Ok(Target {
+            mac_s,
+            control,
+            packets,
+            responses,
+        })
+    }
+
+    fn response(&self, timeout: Duration) -> Result<Box<[u8]>> {
+        let timeout = timeout.to_std().chain_err(|| "bad timeout")?;
+        self.responses
+            .recv_timeout(timeout)
+            .chain_err(|| "timed out waiting for the device")
+    }
+
+    /// Send a control point request, and check its response
+    fn request(&self, req: &[u8], timeout: Duration) -> Result<Box<[u8]>> {
+        self.control.send(req.to_vec().into_boxed_slice()).chain_err(|| "")?;
+
+        loop {
+            let rsp = self.response(timeout)?;
+
+            // Repeated first contact requests may have been answered twice
+            if rsp.len() >= 2 && rsp[0] == OP_RESPONSE && rsp[1] != req[0] {
+                debug!("Ignoring stale DFU response {:?}", rsp);
+                continue;
+            }
+
+            check_response(req[0], &rsp)?;
+            return Ok(rsp);
+        }
+    }
+
+    /// Select an object type, returning its maximum size, and the offset
+    /// and checksum of any data already sent
+    fn select(&self, obj: u8, timeout: Duration) -> Result<(u32, u32, u32)> {
+        let rsp = self.request(&[OP_SELECT, obj], timeout)?;
+        if rsp.len() < 15 {
+            bail!("short select response: {:?}", rsp);
+        }
+        Ok((le_u32(&rsp[3..7]), le_u32(&rsp[7..11]), le_u32(&rsp[11..15])))
+    }
+
+    /// Check that the device received everything sent so far
+    fn verify(&self, sent: &[u8], timeout: Duration) -> Result<()> {
+        let rsp = self.request(&[OP_CALC_CHECKSUM], timeout)?;
+        let (offset, crc) = parse_checksum(&rsp)?;
+
+        if offset != sent.len() || crc != crc32(sent) {
+            bail!(
+                "checksum mismatch, device has {} bytes with CRC {:08X}, expected {} with {:08X}",
+                offset,
+                crc,
+                sent.len(),
+                crc32(sent)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Send a request until the device answers, as it may still be connecting
+fn request_with_retry(
+    tx: &Sender<Box<[u8]>>,
+    rx: &Receiver<Box<[u8]>>,
+    req: &[u8],
+    timeout: Duration,
+) -> Result<Box<[u8]>> {
+    let retry = Duration::milliseconds(FIRST_CONTACT_RETRY_MS)
+        .to_std()
+        .chain_err(|| "bad timeout")?;
+    let attempts = (timeout.num_milliseconds() / FIRST_CONTACT_RETRY_MS).max(1);
+
+    for _ in 0..attempts {
+        tx.send(req.to_vec().into_boxed_slice()).chain_err(|| "")?;
+        if let Ok(rsp) = rx.recv_timeout(retry) {
+            return Ok(rsp);
+        }
+    }
+
+    bail!("device did not respond")
+}
+
+fn create(obj: u8, len: usize) -> Vec<u8> {
+    let len = len as u32;
+    vec![OP_CREATE, obj, len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]
+}
+
+fn check_response(op: u8, rsp: &[u8]) -> Result<()> {
+    match *rsp {
+        [OP_RESPONSE, rsp_op, RES_SUCCESS, ..] if rsp_op == op => Ok(()),
+        [OP_RESPONSE, rsp_op, code, ..] if rsp_op == op => {
+            bail!("DFU request {:02X} failed: {}", op, result_name(code, rsp.get(3)))
+        }
+        _ => bail!("unexpected DFU response {:?} to {:02X}", rsp, op),
+    }
+}
+
+fn result_name(code: u8, ext: Option<&u8>) -> String {
+    match code {
+        0x00 => "invalid opcode".to_string(),
+        0x02 => "opcode not supported".to_string(),
+        0x03 => "invalid parameter".to_string(),
+        0x04 => "insufficient resources".to_string(),
+        0x05 => "invalid object".to_string(),
+        0x07 => "unsupported type".to_string(),
+        0x08 => "operation not permitted".to_string(),
+        0x0A => "operation failed".to_string(),
+        0x0B => format!("extended error {:02X}", ext.cloned().unwrap_or(0)),
+        other => format!("unknown result {:02X}", other),
+    }
+}
+
+fn parse_checksum(rsp: &[u8]) -> Result<(usize, u32)> {
+    check_response(OP_CALC_CHECKSUM, rsp)?;
+    if rsp.len() < 11 {
+        bail!("short checksum response: {:?}", rsp);
+    }
+    Ok((le_u32(&rsp[3..7]) as usize, le_u32(&rsp[7..11])))
+}
+
+/// Buttonless DFU bootloaders advertise with the application's address plus one
+fn next_address(mac: &BtMacAddress) -> BtMacAddress {
+    let mut bytes = [0u8; 6];
+    bytes.copy_from_slice(mac.0.as_bytes());
+
+    for b in bytes.iter_mut().rev() {
+        *b = b.wrapping_add(1);
+        if *b != 0 {
+            break;
+        }
+    }
+
+    BtMacAddress(MacAddress::new(bytes))
+}
+
+fn le_u32(raw: &[u8]) -> u32 {
+    raw[..4].iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// CRC-32 (IEEE 802.3), as used by the DFU checksums
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[0u8; 4]), 0x2144_DF1C);
+    }
+
+    #[test]
+    fn next_address_carries() {
+        let mac = |mac: &str| BtMacAddress::from_str(mac).unwrap();
+        let next = |m: &str| next_address(&mac(m));
+
+        assert_eq!(next("CF:75:CE:86:6D:02"), mac("CF:75:CE:86:6D:03"));
+        assert_eq!(next("CF:75:CE:86:6D:FF"), mac("CF:75:CE:86:6E:00"));
+        assert_eq!(next("CF:75:CE:FF:FF:FF"), mac("CF:75:CF:00:00:00"));
+        assert_eq!(next("FF:FF:FF:FF:FF:FF"), mac("00:00:00:00:00:00"));
+    }
+
+    #[test]
+    fn manifest_in_update_order() {
+        let manifest = br#"{
+            "manifest": {
+                "application": {"bin_file": "app.bin", "dat_file": "app.dat"},
+                "softdevice_bootloader": {"bin_file": "sd_bl.bin", "dat_file": "sd_bl.dat"}
+            }
+        }"#;
+
+        assert_eq!(
+            manifest_images(manifest).unwrap(),
+            vec![
+                ("softdevice_bootloader", "sd_bl.dat".to_string(), "sd_bl.bin".to_string()),
+                ("application", "app.dat".to_string(), "app.bin".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_errors() {
+        assert!(manifest_images(b"not json").is_err());
+        assert!(manifest_images(br#"{"manifest": {"application": {"bin_file": "app.bin"}}}"#).is_err());
+        assert!(manifest_images(br#"{"manifest": {}}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn checksum_response() {
+        let rsp = [OP_RESPONSE, OP_CALC_CHECKSUM, RES_SUCCESS, 0x00, 0x10, 0x00, 0x00, 0x26, 0x39, 0xF4, 0xCB];
+        assert_eq!(parse_checksum(&rsp).unwrap(), (0x1000, 0xCBF4_3926));
+
+        // Too short, failed, or the answer to another request
+        assert!(parse_checksum(&rsp[..10]).is_err());
+        assert!(parse_checksum(&[OP_RESPONSE, OP_CALC_CHECKSUM, 0x0A]).is_err());
+        assert!(parse_checksum(&[OP_RESPONSE, OP_EXECUTE, RES_SUCCESS]).is_err());
+    }
+
+    #[test]
+    fn create_request() {
+        assert_eq!(create(OBJ_DATA, 0x0001_0203), vec![OP_CREATE, OBJ_DATA, 0x03, 0x02, 0x01, 0x00]);
+    }
+}
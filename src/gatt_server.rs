@@ -0,0 +1,210 @@
+//! Builder types for hosting a local GATT application, so the adapter can
+//! act as a peripheral. Applications are started with
+//! `EasyBluezHandle::serve`.
+
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use uuid::Uuid;
+
+use errors::*;
+
+/// A set of services to register with BlueZ as a single application
+#[derive(Default)]
+pub struct GattApplication {
+    pub(crate) services: Vec<GattService>,
+}
+
+pub struct GattService {
+    pub(crate) uuid: Uuid,
+    pub(crate) primary: bool,
+    pub(crate) characteristics: Vec<GattCharacteristic>,
+}
+
+pub struct GattCharacteristic {
+    pub(crate) uuid: Uuid,
+    pub(crate) flags: Vec<&'static str>,
+    pub(crate) value: Vec<u8>,
+    pub(crate) on_read: Option<Box<dyn Fn() -> Vec<u8> + Send>>,
+    pub(crate) descriptors: Vec<GattDescriptor>,
+}
+
+pub struct GattDescriptor {
+    pub(crate) uuid: Uuid,
+    pub(crate) flags: Vec<&'static str>,
+    pub(crate) value: Vec<u8>,
+}
+
+impl GattApplication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn service(mut self, service: GattService) -> Self {
+        self.services.push(service);
+        self
+    }
+}
+
+impl GattService {
+    /// Create a new primary service
+    pub fn new(uuid: Uuid) -> Self {
+        GattService {
+            uuid,
+            primary: true,
+            characteristics: vec![],
+        }
+    }
+
+    /// Mark this service as secondary
+    pub fn secondary(mut self) -> Self {
+        self.primary = false;
+        self
+    }
+
+    pub fn characteristic(mut self, chrc: GattCharacteristic) -> Self {
+        self.characteristics.push(chrc);
+        self
+    }
+}
+
+impl GattCharacteristic {
+    /// Create a new characteristic. Without any flags set, it is not
+    /// accessible to remote devices
+    pub fn new(uuid: Uuid) -> Self {
+        GattCharacteristic {
+            uuid,
+            flags: vec![],
+            value: vec![],
+            on_read: None,
+            descriptors: vec![],
+        }
+    }
+
+    /// Allow remote devices to read the current value
+    pub fn read(self) -> Self {
+        self.flag("read")
+    }
+
+    /// Allow remote devices to write, with response
+    pub fn write(self) -> Self {
+        self.flag("write")
+    }
+
+    /// Allow remote devices to write, without response
+    pub fn write_without_response(self) -> Self {
+        self.flag("write-without-response")
+    }
+
+    /// Allow remote devices to subscribe to notifications
+    pub fn notify(self) -> Self {
+        self.flag("notify")
+    }
+
+    /// Allow remote devices to subscribe to indications
+    pub fn indicate(self) -> Self {
+        self.flag("indicate")
+    }
+
+    /// Initial value, returned for reads until it is written or notified
+    pub fn value(mut self, value: &[u8]) -> Self {
+        self.value = value.to_vec();
+        self
+    }
+
+    /// Compute the value on each read, instead of returning the stored value
+    pub fn on_read<F>(mut self, handler: F) -> Self
+    where
+        F: Fn() -> Vec<u8> + Send + 'static,
+    {
+        self.on_read = Some(Box::new(handler));
+        self
+    }
+
+    pub fn descriptor(mut self, desc: GattDescriptor) -> Self {
+        self.descriptors.push(desc);
+        self
+    }
+
+    fn flag(mut self, flag: &'static str) -> Self {
+        if !self.flags.contains(&flag) {
+            self.flags.push(flag);
+        }
+        self
+    }
+}
+
+impl GattDescriptor {
+    pub fn new(uuid: Uuid) -> Self {
+        GattDescriptor {
+            uuid,
+            flags: vec![],
+            value: vec![],
+        }
+    }
+
+    /// Allow remote devices to read the value
+    pub fn read(mut self) -> Self {
+        self.flags.push("read");
+        self
+    }
+
+    /// Allow remote devices to write the value
+    pub fn write(mut self) -> Self {
+        self.flags.push("write");
+        self
+    }
+
+    pub fn value(mut self, value: &[u8]) -> Self {
+        self.value = value.to_vec();
+        self
+    }
+}
+
+/// Identifies a characteristic of a running application
+pub(crate) type ChrcKey = (Uuid, Uuid);
+
+/// A running GATT application. Dropping this handle unregisters the
+/// application from BlueZ.
+pub struct GattServerHandle {
+    pub(crate) endpoints: Vec<ChrcKey>,
+    pub(crate) write_sender: Sender<(ChrcKey, Sender<Box<[u8]>>)>,
+    pub(crate) notify_sender: Sender<(ChrcKey, Receiver<Box<[u8]>>)>,
+    pub(crate) _server: thread::JoinHandle<()>,
+}
+
+impl GattServerHandle {
+    /// Receive values written to a characteristic by remote devices. Long
+    /// writes arrive in pieces, each delivered as the value written so far
+    pub fn writes(&self, svc_s: &str, chrc_s: &str) -> Result<Receiver<Box<[u8]>>> {
+        let key = self.key(svc_s, chrc_s)?;
+        let (tx, rx) = channel();
+
+        self.write_sender.send((key, tx)).chain_err(|| "")?;
+
+        Ok(rx)
+    }
+
+    /// Update the value of a characteristic. Subscribed remote devices
+    /// are notified of each new value
+    pub fn notifier(&self, svc_s: &str, chrc_s: &str) -> Result<Sender<Box<[u8]>>> {
+        let key = self.key(svc_s, chrc_s)?;
+        let (tx, rx) = channel();
+
+        self.notify_sender.send((key, rx)).chain_err(|| "")?;
+
+        Ok(tx)
+    }
+
+    fn key(&self, svc_s: &str, chrc_s: &str) -> Result<ChrcKey> {
+        let svc = Uuid::from_str(svc_s).chain_err(|| "not a UUID!")?;
+        let chrc = Uuid::from_str(chrc_s).chain_err(|| "not a UUID!")?;
+
+        if !self.endpoints.contains(&(svc, chrc)) {
+            bail!("characteristic is not part of this application");
+        }
+
+        Ok((svc, chrc))
+    }
+}
@@ -0,0 +1,465 @@
+//! An HTTP API over a handle, enabled with the `http` feature. Values are
+//! exchanged as JSON, with data as arrays of bytes.
+//!
+//! * `GET /devices` lists the devices BlueZ knows about
+//! * `GET /devices/{mac}/services` describes a device's GATT layout
+//! * `GET /devices/{mac}/{svc}/{chrc}` reads a characteristic
+//! * `PUT /devices/{mac}/{svc}/{chrc}` writes `{"data": [...]}` to it
+//! * `GET /devices/{mac}/{svc}/{chrc}/notifications` is a WebSocket of
+//!   notifications
+//! * `GET /events` is a WebSocket of connection events
+//! * `GET /metrics` exposes metrics in the Prometheus text format, when
+//!   they are enabled with `EasyBluez::metrics`
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration as OldDuration, Instant};
+
+use serde::Serialize;
+use serde_json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tungstenite::{self, Message, WebSocket};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+
+use EasyBluezHandle;
+use errors::*;
+
+/// How often idle WebSockets are pinged, to notice clients that went away.
+/// A client that hasn't answered by the next ping is dropped
+const PING_INTERVAL_S: u64 = 30;
+
+/// How long a WebSocket waits for the client to say something, between
+/// sends
+const WS_READ_TIMEOUT_MS: u64 = 50;
+
+/// The value of a characteristic, as read or notified
+#[derive(Clone, Debug, Serialize)]
+struct Value<'a> {
+    mac: &'a str,
+    service: &'a str,
+    characteristic: &'a str,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WriteBody {
+    data: Vec<u8>,
+}
+
+pub struct HttpApi {
+    addr: String,
+}
+
+impl HttpApi {
+    /// Serve on `addr`, such as `0.0.0.0:8080`
+    pub fn new(addr: &str) -> Self {
+        HttpApi {
+            addr: addr.to_string(),
+        }
+    }
+
+    /// Serve requests until the process exits, each on its own thread
+    pub fn run(&self, ez: Arc<EasyBluezHandle>) -> Result<()> {
+        let server = Server::http(&self.addr).map_err(|e| e.to_string())?;
+        info!("Serving HTTP on {}", self.addr);
+
+        for req in server.incoming_requests() {
+            let ez = ez.clone();
+            thread::spawn(move || route(&ez, req));
+        }
+
+        Ok(())
+    }
+}
+
+fn route(ez: &EasyBluezHandle, mut req: Request) {
+    let url = req.url().split('?').next().unwrap_or_default().to_string();
+    let path: Vec<&str> = url.split('/').filter(|p| !p.is_empty()).collect();
+    debug!("HTTP {} {}", req.method(), url);
+
+    let result = match (req.method().clone(), path.as_slice()) {
+        (Method::Get, ["devices"]) => Ok(json(&ez.known_devices())),
+        (Method::Get, ["metrics"]) => ez.metrics().map(|m| {
+            Response::from_string(m.to_prometheus())
+                .with_header(header("Content-Type", "text/plain; version=0.0.4"))
+                .boxed()
+        }),
+        (Method::Get, ["devices", mac, "services"]) => ez.services(mac).map(|s| json(&s)),
+        (Method::Get, ["devices", mac, svc, chrc]) => ez.read(mac, svc, chrc).map(|data| {
+            json(&Value {
+                mac,
+                service: svc,
+                characteristic: chrc,
+                data: data.to_vec(),
+            })
+        }),
+        (Method::Put, ["devices", mac, svc, chrc]) => {
+            let mut body = String::new();
+            let written = req.as_reader()
+                .read_to_string(&mut body)
+                .chain_err(|| "failed to read body")
+                .and_then(|_| serde_json::from_str::<WriteBody>(&body).chain_err(|| "bad body"));
+
+            match written {
+                Ok(w) => ez.write(mac, svc, chrc, &w.data).map(|_| Response::empty(204).boxed()),
+                Err(e) => Ok(error(400, &e)),
+            }
+        }
+        (Method::Get, ["devices", mac, svc, chrc, "notifications"]) => {
+            let (mac, svc, chrc) = (mac.to_string(), svc.to_string(), chrc.to_string());
+
+            match ez.notify(&mac, &svc, &chrc) {
+                Ok(rx) => {
+                    let to_value = move |data: Box<[u8]>| {
+                        serde_json::to_string(&Value {
+                            mac: &mac,
+                            service: &svc,
+                            characteristic: &chrc,
+                            data: data.to_vec(),
+                        })
+                    };
+                    return websocket(req, rx, to_value);
+                }
+                Err(e) => Err(e),
+            }
+        }
+        (Method::Get, ["events"]) => match ez.connection_events() {
+            Ok(rx) => return websocket(req, rx, |ev| serde_json::to_string(&ev)),
+            Err(e) => Err(e),
+        },
+        _ => Ok(error(404, &"no such endpoint".into())),
+    };
+
+    let response = result.unwrap_or_else(|e| error(500, &e));
+    if let Err(e) = req.respond(response) {
+        debug!("Failed to respond, {:?}", e);
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Box<dyn Read + Send>> {
+    let body = serde_json::to_string(value).expect("values always serialize");
+
+    Response::from_string(body)
+        .with_header(header("Content-Type", "application/json"))
+        .boxed()
+}
+
+fn error(status: u16, e: &Error) -> Response<Box<dyn Read + Send>> {
+    #[derive(Serialize)]
+    struct ErrorBody {
+        error: String,
+    }
+
+    json(&ErrorBody {
+        error: e.to_string(),
+    }).with_status_code(status)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// Upgrade to a WebSocket, and send everything received on `rx` until
+/// either side goes away
+fn websocket<T, F>(req: Request, rx: Receiver<T>, to_text: F)
+where
+    F: Fn(T) -> serde_json::Result<String>,
+{
+    let key = req.headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| h.value.as_str().to_string());
+
+    let key = match key {
+        Some(k) => k,
+        None => {
+            let _ = req.respond(error(400, &"expected a WebSocket upgrade".into()));
+            return;
+        }
+    };
+
+    let remote = req.remote_addr().cloned();
+    let response = Response::empty(101)
+        .with_header(header("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes())));
+    let stream = req.upgrade("websocket", response);
+    let ws = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+    let timeout = OldDuration::from_millis(WS_READ_TIMEOUT_MS);
+    let readable = match remote.map(|addr| set_read_timeout(&addr, timeout)) {
+        Some(Ok(())) => true,
+        other => {
+            warn!("Not reading from WebSocket client, {:?}", other);
+            false
+        }
+    };
+
+    send_all(ws, rx, to_text, readable);
+}
+
+/// Send everything received on `rx` until either side goes away. Between
+/// sends, a `readable` client is read from, to answer its pings and notice
+/// it closing. Otherwise, a client that went away is only noticed once a
+/// send to it fails
+fn send_all<S, T, F>(mut ws: WebSocket<S>, rx: Receiver<T>, to_text: F, readable: bool)
+where
+    S: Read + Write,
+    F: Fn(T) -> serde_json::Result<String>,
+{
+    let timeout = OldDuration::from_millis(WS_READ_TIMEOUT_MS);
+    let ping_interval = OldDuration::from_secs(PING_INTERVAL_S);
+    let mut last_sent = Instant::now();
+    let mut unanswered_ping = false;
+
+    loop {
+        let msg = match rx.try_recv() {
+            Ok(item) => match to_text(item) {
+                Ok(text) => Some(Message::Text(text)),
+                Err(e) => {
+                    error!("Failed to serialize, {:?}", e);
+                    continue;
+                }
+            },
+            Err(TryRecvError::Empty) if last_sent.elapsed() >= ping_interval => {
+                if unanswered_ping {
+                    debug!("WebSocket client stopped answering pings");
+                    return;
+                }
+                unanswered_ping = readable;
+                Some(Message::Ping(vec![]))
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        if let Some(msg) = msg {
+            if ws.send(msg).is_err() {
+                debug!("WebSocket client went away");
+                return;
+            }
+            last_sent = Instant::now();
+            continue;
+        }
+
+        if !readable {
+            thread::sleep(timeout);
+            continue;
+        }
+
+        // Pings are answered by tungstenite, and a close is answered on
+        // the next flush
+        match ws.read() {
+            Ok(Message::Pong(_)) => unanswered_ping = false,
+            Ok(Message::Close(_)) => {
+                debug!("WebSocket client closed");
+                let _ = ws.flush();
+                return;
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(ref e)) if would_block(e) => {}
+            Err(e) => {
+                debug!("WebSocket client went away, {:?}", e);
+                return;
+            }
+        }
+    }
+
+    let _ = ws.close(None);
+    let _ = ws.flush();
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Give the socket of the client at `remote` a read timeout. tiny_http
+/// doesn't hand out the socket of an upgraded connection, so it is found
+/// among the open descriptors by its peer address. This needs `/proc`, so
+/// only works on Linux, and the descriptors are only borrowed long enough
+/// to ask for their peer. Where the socket isn't found, the WebSocket is
+/// served without reading from the client
+fn set_read_timeout(remote: &SocketAddr, timeout: OldDuration) -> io::Result<()> {
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let fd: RawFd = match entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+
+        // Borrowed, not owned, so the descriptor stays open
+        let sock = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+        if sock.peer_addr().ok().as_ref() == Some(remote) {
+            // Shared by every descriptor of the socket
+            return sock.set_read_timeout(Some(timeout));
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, "no socket for the client"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::TcpListener;
+    use std::process;
+    use std::sync::mpsc::channel;
+
+    use EasyBluez;
+
+    const MAC: &str = "CF:75:CE:86:6D:02";
+    const BATTERY: &str = "0000180f-0000-1000-8000-00805f9b34fb";
+    const LEVEL: &str = "00002a19-0000-1000-8000-00805f9b34fb";
+
+    /// Serve a handle replaying a battery level, returning where. The
+    /// replay runs for a minute, as the handle stops answering once it ends
+    fn serve(name: &str) -> SocketAddr {
+        let path = env::temp_dir().join(format!("easy-bluez-http-{}-{}.jsonl", name, process::id()));
+        fs::write(
+            &path,
+            format!(
+                "{}\n{}\n",
+                format_args!(
+                    r#"{{"at":0.0,"kind":"poll","mac":"{}","service":"{}","characteristic":"{}","data":[90]}}"#,
+                    MAC, BATTERY, LEVEL
+                ),
+                format_args!(r#"{{"at":60.0,"kind":"connection","event":{{"Disconnected":"{}"}}}}"#, MAC)
+            ),
+        ).unwrap();
+        let ez = Arc::new(EasyBluez::new().replay(&path, 1.0).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        thread::spawn(move || {
+            for req in server.incoming_requests() {
+                route(&ez, req);
+            }
+        });
+        addr
+    }
+
+    /// Make a request, returning the status and body of the response
+    fn request(addr: &SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        ).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn routes_requests() {
+        let addr = serve("routes");
+        let chrc = format!("/devices/{}/{}/{}", MAC, BATTERY, LEVEL);
+
+        assert_eq!(request(&addr, "GET", "/devices", ""), (200, "[]".to_string()));
+        assert_eq!(request(&addr, "GET", "/devices?verbose", ""), (200, "[]".to_string()));
+
+        // Once the recorded value has been replayed
+        let deadline = Instant::now() + OldDuration::from_secs(5);
+        let read = loop {
+            let read = request(&addr, "GET", &chrc, "");
+            if read.0 == 200 || Instant::now() > deadline {
+                break read;
+            }
+            thread::sleep(OldDuration::from_millis(50));
+        };
+        assert_eq!(
+            read,
+            (
+                200,
+                format!(
+                    r#"{{"mac":"{}","service":"{}","characteristic":"{}","data":[90]}}"#,
+                    MAC, BATTERY, LEVEL
+                )
+            )
+        );
+
+        let (status, body) = request(&addr, "GET", "/metrics", "");
+        assert_eq!((status, body.as_str()), (500, r#"{"error":"metrics are not enabled"}"#));
+
+        assert_eq!(request(&addr, "GET", "/nowhere", "").0, 404);
+        assert_eq!(request(&addr, "DELETE", "/devices", "").0, 404);
+        assert_eq!(request(&addr, "GET", &format!("{}/notifications", chrc), "").0, 400);
+    }
+
+    #[test]
+    fn bad_write_bodies_are_rejected() {
+        let addr = serve("writes");
+        let chrc = format!("/devices/{}/{}/{}", MAC, BATTERY, LEVEL);
+
+        for body in ["", "[1, 2]", r#"{"data": [256]}"#, r#"{"data": "01"}"#] {
+            let (status, body) = request(&addr, "PUT", &chrc, body);
+            assert_eq!(status, 400);
+            assert!(body.starts_with(r#"{"error":"bad body"#), "{}", body);
+        }
+
+        // A good body gets as far as the handle
+        let (status, body) = request(&addr, "PUT", &chrc, r#"{"data": [1, 2]}"#);
+        assert_eq!((status, body.as_str()), (500, r#"{"error":"not available in replay"}"#));
+    }
+
+    #[test]
+    fn unread_websocket_still_sends_and_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            let ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            send_all(ws, rx, |s: String| Ok(s), false);
+        });
+
+        let url = format!("ws://{}/events", addr);
+        let (mut client, _) = tungstenite::client(url, TcpStream::connect(addr).unwrap()).unwrap();
+
+        tx.send("1".to_string()).unwrap();
+        tx.send("2".to_string()).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Text("1".to_string()));
+        assert_eq!(client.read().unwrap(), Message::Text("2".to_string()));
+
+        drop(tx);
+        assert!(matches!(client.read().unwrap(), Message::Close(_)));
+    }
+
+    #[test]
+    fn websocket_sends_and_closes_with_the_client() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let (tx, rx) = channel();
+        let (tx_done, rx_done) = channel();
+
+        thread::spawn(move || {
+            let req = server.recv().unwrap();
+            websocket(req, rx, |s: String| Ok(s));
+            tx_done.send(()).unwrap();
+        });
+
+        let url = format!("ws://{}/events", addr);
+        let (mut client, _) = tungstenite::client(url, TcpStream::connect(addr).unwrap()).unwrap();
+
+        tx.send("\"hello\"".to_string()).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Text("\"hello\"".to_string()));
+
+        // The server answers the close while `tx` is still open
+        client.close(None).unwrap();
+        rx_done.recv_timeout(OldDuration::from_secs(5)).unwrap();
+        drop(tx);
+    }
+}
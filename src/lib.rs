@@ -1,16 +1,46 @@
 extern crate basic_scheduler;
 extern crate blurz;
+extern crate dbus;
 #[macro_use]
 extern crate error_chain;
 extern crate eui48;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate mvdb;
+#[cfg(feature = "mqtt")]
+extern crate rumqttc;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+#[cfg(feature = "http")]
+extern crate tiny_http;
+#[cfg(feature = "http")]
+extern crate tungstenite;
 extern crate uuid;
+#[cfg(feature = "dfu")]
+extern crate zip;
 
 pub mod errors;
+pub mod services;
+pub mod gatt_server;
+pub mod advertising;
+pub mod beacon;
+pub mod connection;
+pub mod writes;
+#[cfg(feature = "dfu")]
+pub mod dfu;
+pub mod uart;
+pub mod devices;
+pub mod metrics;
+pub mod recording;
+pub mod capture;
+pub mod presence;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "http")]
+pub mod http;
 mod bt_manager;
 mod api;
 
@@ -18,6 +48,7 @@ pub use api::*;
 pub use basic_scheduler::Duration;
 pub use uuid::Uuid;
 
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
@@ -36,6 +67,13 @@ impl From<MacAddress> for BtMacAddress {
     }
 }
 
+/// Formats like BlueZ does, e.g. `CF:75:CE:86:6D:02`
+impl fmt::Display for BtMacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex_string().to_uppercase())
+    }
+}
+
 impl FromStr for BtMacAddress {
     type Err = Error;
     /// Create a MacAddress from String
@@ -0,0 +1,344 @@
+//! Counters for long running gateways. Collection is enabled with
+//! `EasyBluez::metrics`, and the counters are read with
+//! `EasyBluezHandle::metrics` or handed to a `MetricsExporter`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use errors::*;
+
+/// A point in time copy of every counter
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Scans started, counting each restart of a continuous scan
+    pub discovery_runs: u64,
+    /// Whitelisted devices found for the first time
+    pub devices_found: u64,
+    pub connect_attempts: u64,
+    pub connect_successes: u64,
+    pub connect_failures: u64,
+    pub disconnects: u64,
+    /// By endpoint, as `<mac>/<service>/<characteristic>`, or the BlueZ
+    /// object path while the endpoint's device is unknown
+    pub endpoints: BTreeMap<String, EndpointMetrics>,
+    /// Failed operations, by kind, such as `read` or `connect`
+    pub errors: BTreeMap<String, u64>,
+    /// How long each background task took per tick
+    pub ticks: BTreeMap<String, TickMetrics>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct EndpointMetrics {
+    pub reads: u64,
+    pub writes: u64,
+    pub notifications: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub bytes_notified: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct TickMetrics {
+    pub count: u64,
+    pub total_seconds: f64,
+    pub max_seconds: f64,
+}
+
+/// Reads one of the counters of an endpoint
+type EndpointCounter = fn(&EndpointMetrics) -> u64;
+
+/// Somewhere to send metrics regularly, see `EasyBluezHandle::export_metrics`
+pub trait MetricsExporter: Send {
+    fn export(&mut self, metrics: &MetricsSnapshot) -> Result<()>;
+}
+
+/// Writes metrics in the Prometheus text format to a file, for the
+/// node_exporter textfile collector
+pub struct TextfileExporter {
+    path: PathBuf,
+}
+
+impl TextfileExporter {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        TextfileExporter { path: path.into() }
+    }
+}
+
+impl MetricsExporter for TextfileExporter {
+    fn export(&mut self, metrics: &MetricsSnapshot) -> Result<()> {
+        // Replace the file in one go, so it is never scraped half written
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, metrics.to_prometheus()).chain_err(|| "failed to write metrics")?;
+        fs::rename(&tmp, &self.path).chain_err(|| "failed to write metrics")
+    }
+}
+
+impl MetricsSnapshot {
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            ("discovery_runs_total", "Scans started", self.discovery_runs),
+            ("devices_found_total", "Whitelisted devices found", self.devices_found),
+            ("connect_attempts_total", "Connection attempts", self.connect_attempts),
+            ("connect_successes_total", "Successful connection attempts", self.connect_successes),
+            ("connect_failures_total", "Failed connection attempts", self.connect_failures),
+            ("disconnects_total", "Managed devices that disconnected", self.disconnects),
+        ];
+        for &(name, help, value) in &counters {
+            metric_header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "easy_bluez_{} {}", name, value);
+        }
+
+        let per_endpoint: [(&str, &str, EndpointCounter); 6] = [
+            ("reads_total", "Characteristic reads", |e| e.reads),
+            ("writes_total", "Characteristic writes", |e| e.writes),
+            ("notifications_total", "Notifications received", |e| e.notifications),
+            ("read_bytes_total", "Bytes read", |e| e.bytes_read),
+            ("written_bytes_total", "Bytes written", |e| e.bytes_written),
+            ("notified_bytes_total", "Bytes received in notifications", |e| e.bytes_notified),
+        ];
+        for &(name, help, value) in &per_endpoint {
+            metric_header(&mut out, name, help, "counter");
+            for (endpoint, m) in &self.endpoints {
+                let _ = writeln!(
+                    out,
+                    "easy_bluez_{}{{endpoint=\"{}\"}} {}",
+                    name,
+                    escape(endpoint),
+                    value(m)
+                );
+            }
+        }
+
+        metric_header(&mut out, "errors_total", "Failed operations", "counter");
+        for (kind, count) in &self.errors {
+            let _ = writeln!(out, "easy_bluez_errors_total{{kind=\"{}\"}} {}", escape(kind), count);
+        }
+
+        metric_header(&mut out, "task_tick_seconds", "Time spent per task tick", "summary");
+        for (task, t) in &self.ticks {
+            let task = escape(task);
+            let _ = writeln!(out, "easy_bluez_task_tick_seconds_count{{task=\"{}\"}} {}", task, t.count);
+            let _ = writeln!(out, "easy_bluez_task_tick_seconds_sum{{task=\"{}\"}} {}", task, t.total_seconds);
+        }
+
+        metric_header(&mut out, "task_tick_max_seconds", "Longest task tick", "gauge");
+        for (task, t) in &self.ticks {
+            let _ = writeln!(
+                out,
+                "easy_bluez_task_tick_max_seconds{{task=\"{}\"}} {}",
+                escape(task),
+                t.max_seconds
+            );
+        }
+
+        out
+    }
+}
+
+fn metric_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP easy_bluez_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE easy_bluez_{} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Where the background tasks record metrics. Recording does nothing
+/// unless metrics are enabled
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Option<Arc<Mutex<MetricsSnapshot>>>);
+
+impl Metrics {
+    pub fn enabled() -> Self {
+        Metrics(Some(Arc::default()))
+    }
+
+    pub fn record<F: FnOnce(&mut MetricsSnapshot)>(&self, f: F) {
+        if let Some(ref m) = self.0 {
+            f(&mut m.lock().unwrap());
+        }
+    }
+
+    /// Update the counters of an endpoint, by characteristic object path
+    pub fn endpoint<F: FnOnce(&mut EndpointMetrics)>(&self, chrc_path: &str, f: F) {
+        self.record(|m| f(m.endpoints.entry(chrc_path.to_string()).or_default()));
+    }
+
+    pub fn error(&self, kind: &str) {
+        self.record(|m| *m.errors.entry(kind.to_string()).or_default() += 1);
+    }
+
+    /// Time a task tick, which is recorded once the timer is dropped
+    pub fn timer(&self, task: &'static str) -> TickTimer {
+        TickTimer {
+            metrics: self.clone(),
+            task,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Option<MetricsSnapshot> {
+        self.0.as_ref().map(|m| m.lock().unwrap().clone())
+    }
+}
+
+pub(crate) struct TickTimer {
+    metrics: Metrics,
+    task: &'static str,
+    started: Instant,
+}
+
+impl Drop for TickTimer {
+    fn drop(&mut self) {
+        let secs = self.started.elapsed().as_secs_f64();
+
+        self.metrics.record(|m| {
+            let tick = m.ticks.entry(self.task.to_string()).or_default();
+            tick.count += 1;
+            tick.total_seconds += secs;
+            tick.max_seconds = tick.max_seconds.max(secs);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration as OldDuration;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let ep = "CF:75:CE:86:6D:02/0000180f-0000-1000-8000-00805f9b34fb/00002a19-0000-1000-8000-00805f9b34fb";
+        let mut snapshot = MetricsSnapshot {
+            discovery_runs: 1,
+            devices_found: 2,
+            connect_attempts: 3,
+            connect_successes: 2,
+            connect_failures: 1,
+            disconnects: 4,
+            ..MetricsSnapshot::default()
+        };
+        snapshot.endpoints.insert(
+            ep.to_string(),
+            EndpointMetrics {
+                reads: 5,
+                writes: 6,
+                notifications: 7,
+                bytes_read: 8,
+                bytes_written: 9,
+                bytes_notified: 10,
+            },
+        );
+        snapshot.endpoints.insert("a\"b\\c\nd".to_string(), EndpointMetrics::default());
+        snapshot.errors.insert("read".to_string(), 11);
+        snapshot.ticks.insert(
+            "data_poll".to_string(),
+            TickMetrics {
+                count: 12,
+                total_seconds: 0.5,
+                max_seconds: 0.25,
+            },
+        );
+
+        let odd = r#"a\"b\\c\nd"#;
+        let expected = [
+            "# HELP easy_bluez_discovery_runs_total Scans started",
+            "# TYPE easy_bluez_discovery_runs_total counter",
+            "easy_bluez_discovery_runs_total 1",
+            "# HELP easy_bluez_devices_found_total Whitelisted devices found",
+            "# TYPE easy_bluez_devices_found_total counter",
+            "easy_bluez_devices_found_total 2",
+            "# HELP easy_bluez_connect_attempts_total Connection attempts",
+            "# TYPE easy_bluez_connect_attempts_total counter",
+            "easy_bluez_connect_attempts_total 3",
+            "# HELP easy_bluez_connect_successes_total Successful connection attempts",
+            "# TYPE easy_bluez_connect_successes_total counter",
+            "easy_bluez_connect_successes_total 2",
+            "# HELP easy_bluez_connect_failures_total Failed connection attempts",
+            "# TYPE easy_bluez_connect_failures_total counter",
+            "easy_bluez_connect_failures_total 1",
+            "# HELP easy_bluez_disconnects_total Managed devices that disconnected",
+            "# TYPE easy_bluez_disconnects_total counter",
+            "easy_bluez_disconnects_total 4",
+            "# HELP easy_bluez_reads_total Characteristic reads",
+            "# TYPE easy_bluez_reads_total counter",
+            &format!("easy_bluez_reads_total{{endpoint=\"{}\"}} 5", ep),
+            &format!("easy_bluez_reads_total{{endpoint=\"{}\"}} 0", odd),
+            "# HELP easy_bluez_writes_total Characteristic writes",
+            "# TYPE easy_bluez_writes_total counter",
+            &format!("easy_bluez_writes_total{{endpoint=\"{}\"}} 6", ep),
+            &format!("easy_bluez_writes_total{{endpoint=\"{}\"}} 0", odd),
+            "# HELP easy_bluez_notifications_total Notifications received",
+            "# TYPE easy_bluez_notifications_total counter",
+            &format!("easy_bluez_notifications_total{{endpoint=\"{}\"}} 7", ep),
+            &format!("easy_bluez_notifications_total{{endpoint=\"{}\"}} 0", odd),
+            "# HELP easy_bluez_read_bytes_total Bytes read",
+            "# TYPE easy_bluez_read_bytes_total counter",
+            &format!("easy_bluez_read_bytes_total{{endpoint=\"{}\"}} 8", ep),
+            &format!("easy_bluez_read_bytes_total{{endpoint=\"{}\"}} 0", odd),
+            "# HELP easy_bluez_written_bytes_total Bytes written",
+            "# TYPE easy_bluez_written_bytes_total counter",
+            &format!("easy_bluez_written_bytes_total{{endpoint=\"{}\"}} 9", ep),
+            &format!("easy_bluez_written_bytes_total{{endpoint=\"{}\"}} 0", odd),
+            "# HELP easy_bluez_notified_bytes_total Bytes received in notifications",
+            "# TYPE easy_bluez_notified_bytes_total counter",
+            &format!("easy_bluez_notified_bytes_total{{endpoint=\"{}\"}} 10", ep),
+            &format!("easy_bluez_notified_bytes_total{{endpoint=\"{}\"}} 0", odd),
+            "# HELP easy_bluez_errors_total Failed operations",
+            "# TYPE easy_bluez_errors_total counter",
+            "easy_bluez_errors_total{kind=\"read\"} 11",
+            "# HELP easy_bluez_task_tick_seconds Time spent per task tick",
+            "# TYPE easy_bluez_task_tick_seconds summary",
+            "easy_bluez_task_tick_seconds_count{task=\"data_poll\"} 12",
+            "easy_bluez_task_tick_seconds_sum{task=\"data_poll\"} 0.5",
+            "# HELP easy_bluez_task_tick_max_seconds Longest task tick",
+            "# TYPE easy_bluez_task_tick_max_seconds gauge",
+            "easy_bluez_task_tick_max_seconds{task=\"data_poll\"} 0.25",
+        ];
+
+        assert_eq!(snapshot.to_prometheus(), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn escapes_labels() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a\"b"), r#"a\"b"#);
+        assert_eq!(escape("a\\b"), r#"a\\b"#);
+        assert_eq!(escape("a\nb"), r#"a\nb"#);
+    }
+
+    #[test]
+    fn timers_record_ticks() {
+        let metrics = Metrics::enabled();
+
+        drop(metrics.timer("endpoints"));
+        {
+            let _timer = metrics.timer("endpoints");
+            thread::sleep(OldDuration::from_millis(20));
+        }
+
+        let snapshot = metrics.snapshot().unwrap();
+        let tick = &snapshot.ticks["endpoints"];
+        assert_eq!(tick.count, 2);
+        assert!(tick.max_seconds >= 0.02);
+        assert!(tick.total_seconds >= tick.max_seconds);
+        assert!(tick.total_seconds < tick.max_seconds * 2.0);
+    }
+
+    #[test]
+    fn disabled_metrics_record_nothing() {
+        let metrics = Metrics::default();
+        drop(metrics.timer("endpoints"));
+        metrics.error("read");
+        assert_eq!(metrics.snapshot(), None);
+    }
+}
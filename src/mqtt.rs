@@ -0,0 +1,357 @@
+//! A gateway between devices and an MQTT broker, enabled with the `mqtt`
+//! feature. Values are published to `<prefix>/<mac>/<service>/<chrc>`,
+//! messages published to `<prefix>/<mac>/<service>/<chrc>/set` are written
+//! to the device, and each device's connection state is kept in a retained
+//! `<prefix>/<mac>/status` message.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration as OldDuration;
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use uuid::Uuid;
+
+use BtMacAddress;
+use EasyBluezHandle;
+use connection::ConnectionEvent;
+use errors::*;
+
+/// How long to wait before reconnecting to the broker
+const RECONNECT_DELAY_MS: u64 = 5000;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointMode {
+    /// Read the characteristic regularly and publish each value
+    Poll,
+    /// Publish every notification
+    Notify,
+    /// Write whatever is published to the `/set` topic
+    Write,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MqttEndpoint {
+    pub mac: String,
+    pub service: String,
+    pub characteristic: String,
+    pub mode: EndpointMode,
+}
+
+/// Which endpoints to bridge, and which broker to bridge them to. Can be
+/// built in code, or deserialized from a configuration file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MqttGateway {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) client_id: String,
+    pub(crate) prefix: String,
+    pub(crate) endpoints: Vec<MqttEndpoint>,
+}
+
+impl Default for MqttGateway {
+    fn default() -> Self {
+        MqttGateway {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "easy-bluez".to_string(),
+            prefix: "easy-bluez".to_string(),
+            endpoints: vec![],
+        }
+    }
+}
+
+impl MqttGateway {
+    pub fn new(host: &str, port: u16) -> Self {
+        MqttGateway {
+            host: host.to_string(),
+            port,
+            ..Self::default()
+        }
+    }
+
+    pub fn client_id(mut self, id: &str) -> Self {
+        self.client_id = id.to_string();
+        self
+    }
+
+    /// First level of every topic, `easy-bluez` by default
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    pub fn endpoint(mut self, mac: &str, svc: &str, chrc: &str, mode: EndpointMode) -> Self {
+        self.endpoints.push(MqttEndpoint {
+            mac: mac.to_string(),
+            service: svc.to_string(),
+            characteristic: chrc.to_string(),
+            mode,
+        });
+        self
+    }
+
+    /// Bridge the configured endpoints, blocking for as long as the handle
+    /// runs. Connection to the broker is retried whenever it drops
+    pub fn run(&self, ez: &EasyBluezHandle) -> Result<()> {
+        let status_topic = format!("{}/status", self.prefix);
+
+        let mut opts = MqttOptions::new(self.client_id.clone(), self.host.clone(), self.port);
+        opts.set_keep_alive(OldDuration::from_secs(30));
+        opts.set_last_will(LastWill::new(status_topic.clone(), "offline", QoS::AtLeastOnce, true));
+
+        let (mut client, mut connection) = Client::new(opts, 64);
+        let mut writes = HashMap::new();
+
+        for ep in &self.endpoints {
+            let topic = self.topic(ep)?;
+
+            match ep.mode {
+                EndpointMode::Poll => {
+                    let rx = ez.poll(&ep.mac, &ep.service, &ep.characteristic)?;
+                    publish_values(rx, client.clone(), topic);
+                }
+                EndpointMode::Notify => {
+                    let rx = ez.notify(&ep.mac, &ep.service, &ep.characteristic)?;
+                    publish_values(rx, client.clone(), topic);
+                }
+                EndpointMode::Write => {
+                    let tx = ez.writeable(&ep.mac, &ep.service, &ep.characteristic)?;
+                    writes.insert(format!("{}/set", topic), tx);
+                }
+            }
+        }
+
+        publish_status(ez.connection_events()?, client.clone(), self.prefix.clone());
+
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}:{}", self.host, self.port);
+
+                    // Subscriptions don't outlive the session
+                    let topics = writes.keys().cloned().collect();
+                    announce(client.clone(), topics, status_topic.clone());
+                }
+                Ok(Event::Incoming(Packet::Publish(msg))) => {
+                    let sent = match writes.get(&msg.topic) {
+                        Some(tx) => tx.send(msg.payload.to_vec().into_boxed_slice()).is_ok(),
+                        None => {
+                            debug!("Ignoring message on {}", msg.topic);
+                            continue;
+                        }
+                    };
+
+                    if !sent {
+                        error!("Writes to {} have stopped, unsubscribing", msg.topic);
+                        writes.remove(&msg.topic);
+                        if let Err(e) = client.try_unsubscribe(msg.topic.clone()) {
+                            warn!("Failed to unsubscribe from {}, {:?}", msg.topic, e);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection failed, {:?}", e);
+                    thread::sleep(OldDuration::from_millis(RECONNECT_DELAY_MS));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Topic of an endpoint, with the address and UUIDs in a canonical form
+    fn topic(&self, ep: &MqttEndpoint) -> Result<String> {
+        let mac = BtMacAddress::from_str(&ep.mac)?;
+        let svc = Uuid::from_str(&ep.service).chain_err(|| "not a UUID!")?;
+        let chrc = Uuid::from_str(&ep.characteristic).chain_err(|| "not a UUID!")?;
+
+        Ok(format!("{}/{}/{}/{}", self.prefix, mac, svc.hyphenated(), chrc.hyphenated()))
+    }
+}
+
+/// Subscribe to the `/set` topics and publish the gateway's status. Done on
+/// its own thread, as the requests queue behind anything published while
+/// the broker was away, and only drain while the event loop runs
+fn announce(mut client: Client, topics: Vec<String>, status_topic: String) {
+    thread::spawn(move || {
+        for topic in topics {
+            if let Err(e) = client.subscribe(topic.clone(), QoS::AtLeastOnce) {
+                error!("Failed to subscribe to {}, {:?}", topic, e);
+            }
+        }
+        if let Err(e) = client.publish(status_topic, QoS::AtLeastOnce, true, "online") {
+            error!("Failed to publish status, {:?}", e);
+        }
+    });
+}
+
+fn publish_values(rx: Receiver<Box<[u8]>>, mut client: Client, topic: String) {
+    thread::spawn(move || {
+        // Publishing fails while the broker is away, later values are
+        // still worth publishing once it is back
+        for data in rx {
+            if let Err(e) = client.publish(topic.clone(), QoS::AtLeastOnce, false, data.to_vec()) {
+                error!("Failed to publish to {}, {:?}", topic, e);
+            }
+        }
+    });
+}
+
+fn publish_status(rx: Receiver<ConnectionEvent>, mut client: Client, prefix: String) {
+    thread::spawn(move || {
+        for event in rx {
+            let (topic, status) = status_message(&prefix, event);
+            if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, status) {
+                error!("Failed to publish status, {:?}", e);
+            }
+        }
+    });
+}
+
+/// Topic and payload of a device's retained status message
+fn status_message(prefix: &str, event: ConnectionEvent) -> (String, &'static str) {
+    let (mac, status) = match event {
+        ConnectionEvent::Connected(mac) => (mac, "connected"),
+        ConnectionEvent::Disconnected(mac) => (mac, "disconnected"),
+        ConnectionEvent::Missing(mac) => (mac, "missing"),
+        ConnectionEvent::GaveUp(mac) => (mac, "gave_up"),
+    };
+
+    (format!("{}/{}/status", prefix, mac), status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+    use std::sync::mpsc::channel;
+
+    use EasyBluez;
+
+    const MAC: &str = "CF:75:CE:86:6D:02";
+    const BATTERY: &str = "0000180f-0000-1000-8000-00805f9b34fb";
+    const LEVEL: &str = "00002a19-0000-1000-8000-00805f9b34fb";
+
+    fn endpoint(mac: &str, svc: &str, chrc: &str) -> MqttEndpoint {
+        MqttEndpoint {
+            mac: mac.to_string(),
+            service: svc.to_string(),
+            characteristic: chrc.to_string(),
+            mode: EndpointMode::Poll,
+        }
+    }
+
+    #[test]
+    fn topics_are_canonical() {
+        let gw = MqttGateway::new("localhost", 1883).prefix("sensors");
+
+        // Lower case address and short-form UUIDs are accepted too
+        let ep = endpoint("cf:75:ce:86:6d:02", "0000180F00001000800000805F9B34FB", LEVEL);
+        assert_eq!(
+            gw.topic(&ep).unwrap(),
+            "sensors/CF:75:CE:86:6D:02/0000180f-0000-1000-8000-00805f9b34fb/00002a19-0000-1000-8000-00805f9b34fb"
+        );
+
+        assert!(gw.topic(&endpoint("not a mac", BATTERY, LEVEL)).is_err());
+        assert!(gw.topic(&endpoint(MAC, "not a uuid", LEVEL)).is_err());
+        assert!(gw.topic(&endpoint(MAC, BATTERY, "")).is_err());
+    }
+
+    #[test]
+    fn status_messages() {
+        let mac = BtMacAddress::from_str(MAC).unwrap();
+
+        assert_eq!(
+            status_message("sensors", ConnectionEvent::Connected(mac.clone())),
+            ("sensors/CF:75:CE:86:6D:02/status".to_string(), "connected")
+        );
+        assert_eq!(status_message("p", ConnectionEvent::Disconnected(mac.clone())).1, "disconnected");
+        assert_eq!(status_message("p", ConnectionEvent::Missing(mac.clone())).1, "missing");
+        assert_eq!(status_message("p", ConnectionEvent::GaveUp(mac)).1, "gave_up");
+    }
+
+    #[test]
+    fn configuration_decodes() {
+        let gw: MqttGateway = ::serde_json::from_str(&format!(
+            r#"{{"host": "broker", "prefix": "sensors", "endpoints": [
+                {{"mac": "{0}", "service": "{1}", "characteristic": "{2}", "mode": "notify"}},
+                {{"mac": "{0}", "service": "{1}", "characteristic": "{2}", "mode": "write"}}
+            ]}}"#,
+            MAC, BATTERY, LEVEL
+        )).unwrap();
+
+        assert_eq!(gw.host, "broker");
+        // Missing fields take their defaults
+        assert_eq!(gw.port, 1883);
+        assert_eq!(gw.client_id, "easy-bluez");
+        assert_eq!(gw.prefix, "sensors");
+        assert_eq!(
+            gw.endpoints.iter().map(|ep| ep.mode).collect::<Vec<_>>(),
+            [EndpointMode::Notify, EndpointMode::Write]
+        );
+
+        let bad = ::serde_json::from_str::<MqttGateway>(
+            r#"{"endpoints": [{"mac": "", "service": "", "characteristic": "", "mode": "sometimes"}]}"#,
+        );
+        assert!(bad.is_err());
+    }
+
+    /// Needs a broker, such as `mosquitto`, listening on localhost:1883
+    #[test]
+    #[ignore]
+    fn publishes_to_mosquitto() {
+        let path = env::temp_dir().join(format!("easy-bluez-mqtt-{}.jsonl", process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        for i in 1..100 {
+            writeln!(
+                file,
+                r#"{{"at":{},"kind":"poll","mac":"{}","service":"{}","characteristic":"{}","data":[{}]}}"#,
+                f64::from(i) * 0.1,
+                MAC,
+                BATTERY,
+                LEVEL,
+                i
+            ).unwrap();
+        }
+        drop(file);
+        let ez = EasyBluez::new().replay(&path, 1.0).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let prefix = format!("easy-bluez-test-{}", process::id());
+        let gw = MqttGateway::new("localhost", 1883)
+            .client_id(&format!("{}-gateway", prefix))
+            .prefix(&prefix)
+            .endpoint(MAC, BATTERY, LEVEL, EndpointMode::Poll);
+        let topic = gw.topic(&gw.endpoints[0]).unwrap();
+        thread::spawn(move || gw.run(&ez));
+
+        let opts = MqttOptions::new(format!("{}-listener", prefix), "localhost", 1883);
+        let (mut client, mut connection) = Client::new(opts, 16);
+        client.subscribe(topic.clone(), QoS::AtLeastOnce).unwrap();
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                if let Ok(Event::Incoming(Packet::Publish(msg))) = notification {
+                    if tx.send((msg.topic, msg.payload.to_vec())).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        let (first_topic, first) = rx.recv_timeout(OldDuration::from_secs(10)).unwrap();
+        let (_, second) = rx.recv_timeout(OldDuration::from_secs(10)).unwrap();
+        assert_eq!(first_topic, topic);
+        assert_eq!(first.len(), 1);
+        assert!(second[0] > first[0]);
+    }
+}
@@ -0,0 +1,301 @@
+//! Presence of nearby devices, as seen while scanning. Each device's last
+//! sighting and signal strength are tracked, and `Appeared` and `Lost`
+//! events are reported as devices come and go.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use Duration;
+use BtMacAddress;
+
+/// How many RSSI samples are kept per device
+const RSSI_HISTORY: usize = 20;
+
+/// A device coming into or going out of range
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum PresenceEvent {
+    /// Seen for the first time, or again after being lost
+    Appeared(BtMacAddress),
+    /// Not seen for longer than the presence timeout
+    Lost(BtMacAddress),
+}
+
+/// How RSSI samples are smoothed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    /// Mean of the last `n` samples
+    MovingAverage(usize),
+    /// A one dimensional Kalman filter. Lower process noise trusts the
+    /// estimate more, lower measurement noise trusts each sample more
+    Kalman {
+        process_noise: f64,
+        measurement_noise: f64,
+    },
+}
+
+impl Default for Smoothing {
+    fn default() -> Self {
+        Smoothing::MovingAverage(5)
+    }
+}
+
+/// A snapshot of a device seen while scanning
+#[derive(Clone, Debug, PartialEq)]
+pub struct DevicePresence {
+    pub mac: BtMacAddress,
+    /// Seen within the presence timeout
+    pub present: bool,
+    /// Time since the device was last seen
+    pub seen_ago: Duration,
+    /// Latest signal strength, in dBm
+    pub rssi: Option<i16>,
+    /// Signal strength with the configured smoothing applied
+    pub smoothed_rssi: Option<f64>,
+    /// Recent signal strengths, oldest first
+    pub rssi_history: Vec<i16>,
+}
+
+/// Kalman filter state
+#[derive(Clone, Copy)]
+struct Estimate {
+    value: f64,
+    error: f64,
+}
+
+struct Tracked {
+    present: bool,
+    last_seen: Instant,
+    rssi: Vec<i16>,
+    estimate: Option<Estimate>,
+}
+
+impl Tracked {
+    fn smoothed(&self, smoothing: Smoothing) -> Option<f64> {
+        match smoothing {
+            Smoothing::MovingAverage(n) => {
+                let recent = &self.rssi[self.rssi.len().saturating_sub(n.max(1))..];
+                if recent.is_empty() {
+                    return None;
+                }
+                Some(recent.iter().map(|&r| f64::from(r)).sum::<f64>() / recent.len() as f64)
+            }
+            Smoothing::Kalman { .. } => self.estimate.map(|e| e.value),
+        }
+    }
+
+    fn sample(&mut self, rssi: i16, smoothing: Smoothing) {
+        if self.rssi.len() == RSSI_HISTORY {
+            self.rssi.remove(0);
+        }
+        self.rssi.push(rssi);
+
+        if let Smoothing::Kalman {
+            process_noise,
+            measurement_noise,
+        } = smoothing
+        {
+            let measured = f64::from(rssi);
+            self.estimate = Some(match self.estimate {
+                None => Estimate {
+                    value: measured,
+                    error: measurement_noise,
+                },
+                Some(e) => {
+                    let error = e.error + process_noise;
+                    let gain = error / (error + measurement_noise);
+                    Estimate {
+                        value: e.value + gain * (measured - e.value),
+                        error: (1.0 - gain) * error,
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Every device seen while scanning, shared between discovery and the
+/// handle
+pub(crate) type SharedPresence = Arc<RwLock<PresenceTracker>>;
+
+pub(crate) struct PresenceTracker {
+    timeout: Duration,
+    smoothing: Smoothing,
+    devices: HashMap<BtMacAddress, Tracked>,
+}
+
+impl PresenceTracker {
+    pub fn new(timeout: Duration, smoothing: Smoothing) -> Self {
+        PresenceTracker {
+            timeout,
+            smoothing,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Note a sighting of a device at `now`, returns `Appeared` if it was
+    /// not present before
+    pub fn saw(&mut self, mac: &BtMacAddress, rssi: Option<i16>, now: Instant) -> Option<PresenceEvent> {
+        let smoothing = self.smoothing;
+        let tracked = self.devices.entry(mac.clone()).or_insert_with(|| Tracked {
+            present: false,
+            last_seen: now,
+            rssi: vec![],
+            estimate: None,
+        });
+
+        tracked.last_seen = now;
+        if let Some(rssi) = rssi {
+            tracked.sample(rssi, smoothing);
+        }
+
+        if tracked.present {
+            None
+        } else {
+            tracked.present = true;
+            Some(PresenceEvent::Appeared(mac.clone()))
+        }
+    }
+
+    /// Mark devices not seen within the timeout before `now` as lost
+    pub fn expire(&mut self, now: Instant) -> Vec<PresenceEvent> {
+        let timeout = match self.timeout.to_std() {
+            Ok(t) => t,
+            Err(_) => return vec![],
+        };
+
+        self.devices
+            .iter_mut()
+            .filter(|(_, t)| t.present && now.saturating_duration_since(t.last_seen) > timeout)
+            .map(|(mac, t)| {
+                t.present = false;
+                PresenceEvent::Lost(mac.clone())
+            })
+            .collect()
+    }
+
+    pub fn devices(&self) -> Vec<DevicePresence> {
+        let mut devices: Vec<DevicePresence> = self.devices
+            .iter()
+            .map(|(mac, t)| DevicePresence {
+                mac: mac.clone(),
+                present: t.present,
+                seen_ago: Duration::from_std(t.last_seen.elapsed()).unwrap_or_else(|_| Duration::zero()),
+                rssi: t.rssi.last().cloned(),
+                smoothed_rssi: t.smoothed(self.smoothing),
+                rssi_history: t.rssi.clone(),
+            })
+            .collect();
+
+        devices.sort_by_key(|d| d.mac.to_string());
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::time::Duration as OldDuration;
+
+    fn mac() -> BtMacAddress {
+        BtMacAddress::from_str("CF:75:CE:86:6D:02").unwrap()
+    }
+
+    fn tracked() -> Tracked {
+        Tracked {
+            present: true,
+            last_seen: Instant::now(),
+            rssi: vec![],
+            estimate: None,
+        }
+    }
+
+    #[test]
+    fn moving_average_of_recent_samples() {
+        let smoothing = Smoothing::MovingAverage(3);
+        let mut t = tracked();
+        assert_eq!(t.smoothed(smoothing), None);
+
+        for &rssi in &[-90, -60, -70, -80] {
+            t.sample(rssi, smoothing);
+        }
+        assert_eq!(t.smoothed(smoothing), Some(-70.0));
+        assert_eq!(t.smoothed(Smoothing::MovingAverage(10)), Some(-75.0));
+        // A zero window still averages the latest sample
+        assert_eq!(t.smoothed(Smoothing::MovingAverage(0)), Some(-80.0));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut t = tracked();
+        for i in 0..(RSSI_HISTORY as i16 + 5) {
+            t.sample(-i, Smoothing::default());
+        }
+
+        assert_eq!(t.rssi.len(), RSSI_HISTORY);
+        assert_eq!(t.rssi[0], -5);
+        assert_eq!(*t.rssi.last().unwrap(), -(RSSI_HISTORY as i16 + 4));
+    }
+
+    #[test]
+    fn kalman_starts_at_first_sample_and_converges() {
+        let smoothing = Smoothing::Kalman {
+            process_noise: 0.01,
+            measurement_noise: 4.0,
+        };
+        let mut t = tracked();
+
+        t.sample(-60, smoothing);
+        assert_eq!(t.smoothed(smoothing), Some(-60.0));
+
+        // First update: error 4.01, gain 4.01 / 8.01
+        t.sample(-70, smoothing);
+        let expected = -60.0 + (4.01 / 8.01) * -10.0;
+        assert!((t.smoothed(smoothing).unwrap() - expected).abs() < 1e-9);
+
+        for _ in 0..200 {
+            t.sample(-80, smoothing);
+        }
+        assert!((t.smoothed(smoothing).unwrap() + 80.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn kalman_ignores_a_single_outlier() {
+        let smoothing = Smoothing::Kalman {
+            process_noise: 0.01,
+            measurement_noise: 4.0,
+        };
+        let mut t = tracked();
+        for _ in 0..50 {
+            t.sample(-60, smoothing);
+        }
+
+        t.sample(-100, smoothing);
+        assert!(t.smoothed(smoothing).unwrap() > -62.0);
+    }
+
+    #[test]
+    fn appears_once_and_is_lost_after_timeout() {
+        let mut p = PresenceTracker::new(Duration::seconds(10), Smoothing::default());
+        let start = Instant::now();
+
+        assert_eq!(p.saw(&mac(), Some(-60), start), Some(PresenceEvent::Appeared(mac())));
+        assert_eq!(p.saw(&mac(), Some(-61), start + OldDuration::from_secs(5)), None);
+
+        // Measured from the latest sighting
+        assert!(p.expire(start + OldDuration::from_secs(14)).is_empty());
+        assert_eq!(p.expire(start + OldDuration::from_secs(16)), vec![PresenceEvent::Lost(mac())]);
+        assert!(p.expire(start + OldDuration::from_secs(30)).is_empty());
+
+        let devices = p.devices();
+        assert_eq!(devices.len(), 1);
+        assert!(!devices[0].present);
+        assert_eq!(devices[0].rssi_history, vec![-60, -61]);
+
+        // Seen again after being lost
+        let later = start + OldDuration::from_secs(40);
+        assert_eq!(p.saw(&mac(), None, later), Some(PresenceEvent::Appeared(mac())));
+        assert!(p.devices()[0].present);
+    }
+}
@@ -0,0 +1,466 @@
+//! Recording and replay of device sessions. With `EasyBluez::record`, polled
+//! values, writes, notifications and connection events are appended to a
+//! file as JSON lines. `EasyBluez::replay` plays such a file back through
+//! the channels of a handle, so code consuming them can be exercised
+//! without the devices. Addresses are written in the canonical form of
+//! `eui48`, but read in any form `BtMacAddress` parses.
+//!
+//! ```text
+//! {"at":0.52,"kind":"connection","event":{"Connected":"cf-75-ce-86-6d-02"}}
+//! {"at":1.04,"kind":"poll","mac":"cf-75-ce-86-6d-02","service":"...","characteristic":"...","data":[1,2]}
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration as OldDuration, Instant};
+
+use serde_json;
+use uuid::Uuid;
+
+use BtMacAddress;
+use bt_manager::{NotifyRequest, ReadRequest, SomethingItem, WriteRequest};
+use bt_manager::signals::SharedCache;
+use connection::ConnectionEvent;
+use errors::*;
+
+/// How often replay picks up new subscriptions while waiting for the next
+/// record
+const REPLAY_TICK_MS: u64 = 50;
+
+/// One recorded event
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Record {
+    /// Seconds since the recording started
+    pub at: f64,
+    #[serde(flatten)]
+    pub event: RecordedEvent,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    /// A value read by `EasyBluezHandle::poll`
+    Poll(RecordedValue),
+    /// A value written to the device
+    Write(RecordedValue),
+    Notification(RecordedValue),
+    Connection { event: ConnectionEvent },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RecordedValue {
+    pub mac: BtMacAddress,
+    pub service: Uuid,
+    pub characteristic: Uuid,
+    pub data: Vec<u8>,
+}
+
+impl RecordedValue {
+    fn is_for(&self, si: &SomethingItem) -> bool {
+        self.mac == si.mac && self.service == si.svc && self.characteristic == si.chrc
+    }
+
+    fn same_endpoint(&self, other: &RecordedValue) -> bool {
+        self.mac == other.mac && self.service == other.service
+            && self.characteristic == other.characteristic
+    }
+}
+
+/// Read every record of a recording
+pub fn read_recording<P: AsRef<Path>>(path: P) -> Result<Vec<Record>> {
+    let file = File::open(path).chain_err(|| "failed to open recording")?;
+    let mut records = vec![];
+
+    for line in BufReader::new(file).lines() {
+        let line = line.chain_err(|| "failed to read recording")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).chain_err(|| "bad record")?);
+    }
+
+    Ok(records)
+}
+
+struct RecordingFile {
+    out: BufWriter<File>,
+    started: Instant,
+}
+
+/// Where the background tasks record a session. Recording does nothing
+/// unless it is enabled
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Option<(Arc<Mutex<RecordingFile>>, SharedCache)>);
+
+impl Recorder {
+    /// Open the file at `path` for appending to, ready for `Recorder::new`
+    pub fn open(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .chain_err(|| "failed to open recording")
+    }
+
+    /// Record to `file`, resolving endpoints through `cache`
+    pub fn new(file: File, cache: SharedCache) -> Self {
+        let recording = RecordingFile {
+            out: BufWriter::new(file),
+            started: Instant::now(),
+        };
+
+        Recorder(Some((Arc::new(Mutex::new(recording)), cache)))
+    }
+
+    pub fn poll(&self, chrc_path: &str, data: &[u8]) {
+        self.value(chrc_path, data, RecordedEvent::Poll);
+    }
+
+    pub fn write(&self, chrc_path: &str, data: &[u8]) {
+        self.value(chrc_path, data, RecordedEvent::Write);
+    }
+
+    pub fn notification(&self, chrc_path: &str, data: &[u8]) {
+        self.value(chrc_path, data, RecordedEvent::Notification);
+    }
+
+    pub fn connection(&self, event: &ConnectionEvent) {
+        self.record(RecordedEvent::Connection {
+            event: event.clone(),
+        });
+    }
+
+    fn value<F>(&self, chrc_path: &str, data: &[u8], kind: F)
+    where
+        F: FnOnce(RecordedValue) -> RecordedEvent,
+    {
+        let (_, ref cache) = match self.0 {
+            Some(ref r) => r,
+            None => return,
+        };
+
+        let ep = match cache.read().unwrap().endpoint(chrc_path) {
+            Some(ep) => ep,
+            None => {
+                debug!("Not recording {}, its device is unknown", chrc_path);
+                return;
+            }
+        };
+
+        self.record(kind(RecordedValue {
+            mac: ep.mac,
+            service: ep.svc,
+            characteristic: ep.chrc,
+            data: data.to_vec(),
+        }));
+    }
+
+    fn record(&self, event: RecordedEvent) {
+        let (ref file, _) = match self.0 {
+            Some(ref r) => r,
+            None => return,
+        };
+        let mut file = file.lock().unwrap();
+
+        let elapsed = file.started.elapsed();
+        let record = Record {
+            at: elapsed.as_secs_f64(),
+            event,
+        };
+        let line = serde_json::to_string(&record).expect("records always serialize");
+
+        // Flushed per record, so a recording survives the process being killed
+        if let Err(e) = writeln!(file.out, "{}", line).and_then(|_| file.out.flush()) {
+            error!("Failed to record, {:?}", e);
+        }
+    }
+}
+
+/// Everything a handle sends to the background tasks, answered from a
+/// recording instead
+pub(crate) struct Replayer {
+    pub records: Vec<Record>,
+    pub speed: f64,
+
+    pub rx_macs: Receiver<BtMacAddress>,
+    pub rx_polls: Receiver<(SomethingItem, Sender<Box<[u8]>>)>,
+    pub rx_notifies: Receiver<NotifyRequest>,
+    pub rx_reads: Receiver<ReadRequest>,
+    pub rx_writes: Receiver<WriteRequest>,
+    pub rx_event_subs: Receiver<Sender<ConnectionEvent>>,
+
+    pub polls: Vec<(SomethingItem, Sender<Box<[u8]>>)>,
+    pub notifies: Vec<(SomethingItem, Sender<Box<[u8]>>)>,
+    pub writes: Vec<Receiver<Box<[u8]>>>,
+    pub event_subs: Vec<Sender<ConnectionEvent>>,
+    /// Latest value of each endpoint, to answer one-shot reads
+    pub latest: Vec<RecordedValue>,
+}
+
+impl Replayer {
+    /// Deliver every record at its time, scaled by the speed. Subscribers
+    /// see their channels close once the recording ends
+    pub fn run(mut self) {
+        let started = Instant::now();
+        let records = ::std::mem::take(&mut self.records);
+
+        for record in records {
+            let due = started + OldDuration::from_secs_f64(record.at.max(0.0) / self.speed);
+
+            loop {
+                self.take_requests();
+
+                let now = Instant::now();
+                if now >= due {
+                    break;
+                }
+                thread::sleep((due - now).min(OldDuration::from_millis(REPLAY_TICK_MS)));
+            }
+
+            self.deliver(record.event);
+        }
+
+        info!("Replay finished");
+    }
+
+    fn take_requests(&mut self) {
+        while self.rx_macs.try_recv().is_ok() {}
+
+        while let Ok(poll) = self.rx_polls.try_recv() {
+            self.polls.push(poll);
+        }
+        while let Ok((si, tx, _)) = self.rx_notifies.try_recv() {
+            self.notifies.push((si, tx));
+        }
+        while let Ok((_, rx, _)) = self.rx_writes.try_recv() {
+            self.writes.push(rx);
+        }
+        while let Ok(sub) = self.rx_event_subs.try_recv() {
+            self.event_subs.push(sub);
+        }
+
+        while let Ok((si, tx)) = self.rx_reads.try_recv() {
+            let value = self.latest.iter().find(|v| v.is_for(&si));
+            let _ = tx.send(value.map(|v| v.data.clone().into_boxed_slice()));
+        }
+
+        // Writes go nowhere, but are accepted as long as the writer exists
+        for rx in &self.writes {
+            while rx.try_recv().is_ok() {}
+        }
+    }
+
+    fn deliver(&mut self, event: RecordedEvent) {
+        let (value, subs) = match event {
+            RecordedEvent::Poll(value) => (value, &mut self.polls),
+            RecordedEvent::Notification(value) => (value, &mut self.notifies),
+            RecordedEvent::Write(_) => return,
+            RecordedEvent::Connection { event } => {
+                self.event_subs.retain(|tx| tx.send(event.clone()).is_ok());
+                return;
+            }
+        };
+
+        subs.retain(|(si, tx)| {
+            !value.is_for(si) || tx.send(value.data.clone().into_boxed_slice()).is_ok()
+        });
+
+        self.latest.retain(|v| !v.same_endpoint(&value));
+        self.latest.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+
+    const MAC: &str = "CF:75:CE:86:6D:02";
+    const BATTERY: &str = "0000180f-0000-1000-8000-00805f9b34fb";
+    const LEVEL: &str = "00002a19-0000-1000-8000-00805f9b34fb";
+    const NUS: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
+    const NUS_TX: &str = "6e400003-b5a3-f393-e0a9-e50e24dcca9e";
+
+    fn value(svc: &str, chrc: &str, data: &[u8]) -> RecordedValue {
+        RecordedValue {
+            mac: BtMacAddress::from_str(MAC).unwrap(),
+            service: Uuid::from_str(svc).unwrap(),
+            characteristic: Uuid::from_str(chrc).unwrap(),
+            data: data.to_vec(),
+        }
+    }
+
+    fn endpoint(svc: &str, chrc: &str) -> SomethingItem {
+        SomethingItem {
+            mac: BtMacAddress::from_str(MAC).unwrap(),
+            svc: Uuid::from_str(svc).unwrap(),
+            chrc: Uuid::from_str(chrc).unwrap(),
+        }
+    }
+
+    /// Where a handle would send its requests
+    struct Requests {
+        polls: Sender<(SomethingItem, Sender<Box<[u8]>>)>,
+        notifies: Sender<NotifyRequest>,
+        reads: Sender<ReadRequest>,
+        event_subs: Sender<Sender<ConnectionEvent>>,
+    }
+
+    fn replayer(records: Vec<Record>) -> (Replayer, Requests) {
+        let (tx_polls, rx_polls) = channel();
+        let (tx_notifies, rx_notifies) = channel();
+        let (tx_reads, rx_reads) = channel();
+        let (tx_event_subs, rx_event_subs) = channel();
+
+        let replayer = Replayer {
+            records,
+            speed: 100.0,
+            rx_macs: channel().1,
+            rx_polls,
+            rx_notifies,
+            rx_reads,
+            rx_writes: channel().1,
+            rx_event_subs,
+            polls: vec![],
+            notifies: vec![],
+            writes: vec![],
+            event_subs: vec![],
+            latest: vec![],
+        };
+        let requests = Requests {
+            polls: tx_polls,
+            notifies: tx_notifies,
+            reads: tx_reads,
+            event_subs: tx_event_subs,
+        };
+        (replayer, requests)
+    }
+
+    #[test]
+    fn records_match_the_documented_format() {
+        let mac = BtMacAddress::from_str(MAC).unwrap();
+        let lines = [
+            r#"{"at":0.52,"kind":"connection","event":{"Connected":"cf-75-ce-86-6d-02"}}"#,
+            r#"{"at":1.04,"kind":"poll","mac":"cf-75-ce-86-6d-02","service":"0000180f-0000-1000-8000-00805f9b34fb","characteristic":"00002a19-0000-1000-8000-00805f9b34fb","data":[1,2]}"#,
+            r#"{"at":1.5,"kind":"write","mac":"cf-75-ce-86-6d-02","service":"0000180f-0000-1000-8000-00805f9b34fb","characteristic":"00002a19-0000-1000-8000-00805f9b34fb","data":[]}"#,
+            r#"{"at":2.0,"kind":"notification","mac":"cf-75-ce-86-6d-02","service":"6e400001-b5a3-f393-e0a9-e50e24dcca9e","characteristic":"6e400003-b5a3-f393-e0a9-e50e24dcca9e","data":[255]}"#,
+        ];
+        let records = [
+            Record {
+                at: 0.52,
+                event: RecordedEvent::Connection {
+                    event: ConnectionEvent::Connected(mac),
+                },
+            },
+            Record {
+                at: 1.04,
+                event: RecordedEvent::Poll(value(BATTERY, LEVEL, &[1, 2])),
+            },
+            Record {
+                at: 1.5,
+                event: RecordedEvent::Write(value(BATTERY, LEVEL, &[])),
+            },
+            Record {
+                at: 2.0,
+                event: RecordedEvent::Notification(value(NUS, NUS_TX, &[255])),
+            },
+        ];
+
+        for (line, record) in lines.iter().zip(records.iter()) {
+            assert_eq!(&serde_json::from_str::<Record>(line).unwrap(), record);
+            assert_eq!(&serde_json::to_string(record).unwrap(), line);
+        }
+
+        // As written by hand
+        let colons = r#"{"at":0.52,"kind":"connection","event":{"Connected":"CF:75:CE:86:6D:02"}}"#;
+        assert_eq!(serde_json::from_str::<Record>(colons).unwrap(), records[0]);
+
+        assert!(serde_json::from_str::<Record>(r#"{"at":1.0,"kind":"scan"}"#).is_err());
+        assert!(serde_json::from_str::<Record>(r#"{"kind":"connection","event":{"Connected":"CF:75:CE:86:6D:02"}}"#).is_err());
+    }
+
+    #[test]
+    fn replays_to_subscribers_in_order() {
+        let mac = BtMacAddress::from_str(MAC).unwrap();
+        let records = vec![
+            Record {
+                at: 0.0,
+                event: RecordedEvent::Connection {
+                    event: ConnectionEvent::Connected(mac.clone()),
+                },
+            },
+            Record {
+                at: 0.5,
+                event: RecordedEvent::Poll(value(BATTERY, LEVEL, &[90])),
+            },
+            Record {
+                at: 1.0,
+                event: RecordedEvent::Notification(value(NUS, NUS_TX, b"hi")),
+            },
+            Record {
+                at: 1.5,
+                event: RecordedEvent::Poll(value(BATTERY, LEVEL, &[89])),
+            },
+            // Polled values only go to polls, notifications to notifies
+            Record {
+                at: 2.0,
+                event: RecordedEvent::Notification(value(BATTERY, LEVEL, &[0])),
+            },
+            Record {
+                at: 2.5,
+                event: RecordedEvent::Connection {
+                    event: ConnectionEvent::Disconnected(mac.clone()),
+                },
+            },
+        ];
+        let (replayer, requests) = replayer(records);
+
+        let (tx, polled) = channel();
+        requests.polls.send((endpoint(BATTERY, LEVEL), tx)).unwrap();
+        let (tx, notified) = channel();
+        requests.notifies.send((endpoint(NUS, NUS_TX), tx, false)).unwrap();
+        let (tx, events) = channel();
+        requests.event_subs.send(tx).unwrap();
+
+        let started = Instant::now();
+        replayer.run();
+        // 2.5 seconds of recording, at 100 times the speed
+        assert!(started.elapsed() >= OldDuration::from_millis(25));
+
+        assert_eq!(polled.iter().collect::<Vec<_>>(), [Box::from(&[90][..]), Box::from(&[89][..])]);
+        assert_eq!(notified.iter().collect::<Vec<_>>(), [Box::from(&b"hi"[..])]);
+        assert_eq!(
+            events.iter().collect::<Vec<_>>(),
+            [ConnectionEvent::Connected(mac.clone()), ConnectionEvent::Disconnected(mac)]
+        );
+    }
+
+    #[test]
+    fn reads_are_answered_from_the_latest_value() {
+        let (mut replayer, requests) = replayer(vec![]);
+
+        replayer.deliver(RecordedEvent::Poll(value(BATTERY, LEVEL, &[90])));
+        replayer.deliver(RecordedEvent::Notification(value(NUS, NUS_TX, b"one")));
+        replayer.deliver(RecordedEvent::Poll(value(BATTERY, LEVEL, &[89])));
+        assert_eq!(replayer.latest.len(), 2);
+
+        let read = |si| {
+            let (tx, rx) = channel();
+            requests.reads.send((si, tx)).unwrap();
+            rx
+        };
+        let level = read(endpoint(BATTERY, LEVEL));
+        let uart = read(endpoint(NUS, NUS_TX));
+        let unknown = read(endpoint(NUS, LEVEL));
+        replayer.take_requests();
+
+        assert_eq!(level.try_recv().unwrap().as_deref(), Some(&[89][..]));
+        assert_eq!(uart.try_recv().unwrap().as_deref(), Some(&b"one"[..]));
+        assert_eq!(unknown.try_recv().unwrap(), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Well known GATT services, and types for the data they expose
+
+use uuid::Uuid;
+
+/// Device Information service
+pub const DEVICE_INFORMATION: u16 = 0x180A;
+/// Battery service
+pub const BATTERY: u16 = 0x180F;
+
+pub const SYSTEM_ID: u16 = 0x2A23;
+pub const MODEL_NUMBER: u16 = 0x2A24;
+pub const SERIAL_NUMBER: u16 = 0x2A25;
+pub const FIRMWARE_REVISION: u16 = 0x2A26;
+pub const HARDWARE_REVISION: u16 = 0x2A27;
+pub const SOFTWARE_REVISION: u16 = 0x2A28;
+pub const MANUFACTURER_NAME: u16 = 0x2A29;
+pub const PNP_ID: u16 = 0x2A50;
+pub const BATTERY_LEVEL: u16 = 0x2A19;
+
+/// Expand a 16 bit assigned number into a full UUID, using the
+/// Bluetooth Base UUID (`0000xxxx-0000-1000-8000-00805f9b34fb`)
+pub fn uuid_from_u16(short: u16) -> Uuid {
+    Uuid::from_fields(
+        short as u32,
+        0x0000,
+        0x1000,
+        &[0x80, 0x00, 0x00, 0x80, 0x5f, 0x9b, 0x34, 0xfb],
+    ).unwrap()
+}
+
+/// Contents of the Device Information service. Any characteristic
+/// the device does not implement is left as `None`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware_rev: Option<String>,
+    pub hardware_rev: Option<String>,
+    pub software_rev: Option<String>,
+    pub system_id: Option<SystemId>,
+    pub pnp_id: Option<PnpId>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SystemId {
+    pub manufacturer_id: u64,
+    pub organizationally_unique_id: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PnpId {
+    pub vendor_id_source: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub product_version: u16,
+}
+
+/// Parse a UTF-8 string characteristic, dropping any trailing NULs
+/// that some devices include
+pub fn parse_string(raw: &[u8]) -> Option<String> {
+    let s = String::from_utf8_lossy(raw);
+    let s = s.trim_end_matches('\0');
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+/// Parse the System ID characteristic: a 40 bit manufacturer defined
+/// identifier followed by a 24 bit OUI, both little endian
+pub fn parse_system_id(raw: &[u8]) -> Option<SystemId> {
+    if raw.len() < 8 {
+        return None;
+    }
+
+    let manufacturer_id = raw[..5]
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    let organizationally_unique_id = raw[5..8]
+        .iter()
+        .rev()
+        .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+
+    Some(SystemId {
+        manufacturer_id,
+        organizationally_unique_id,
+    })
+}
+
+/// Parse the PnP ID characteristic
+pub fn parse_pnp_id(raw: &[u8]) -> Option<PnpId> {
+    if raw.len() < 7 {
+        return None;
+    }
+
+    Some(PnpId {
+        vendor_id_source: raw[0],
+        vendor_id: le_u16(&raw[1..3]),
+        product_id: le_u16(&raw[3..5]),
+        product_version: le_u16(&raw[5..7]),
+    })
+}
+
+/// Parse the Battery Level characteristic, a percentage from 0-100
+pub fn parse_battery_level(raw: &[u8]) -> Option<u8> {
+    match raw.first() {
+        Some(&lvl) if lvl <= 100 => Some(lvl),
+        _ => None,
+    }
+}
+
+fn le_u16(raw: &[u8]) -> u16 {
+    (raw[0] as u16) | ((raw[1] as u16) << 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_uuids() {
+        assert_eq!(
+            uuid_from_u16(BATTERY).hyphenated().to_string(),
+            "0000180f-0000-1000-8000-00805f9b34fb"
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(parse_string(b"nRF52"), Some("nRF52".to_string()));
+        assert_eq!(parse_string(b"1.0\0\0"), Some("1.0".to_string()));
+        assert_eq!(parse_string(b"\0"), None);
+        assert_eq!(parse_string(b""), None);
+        // Invalid UTF-8 is replaced rather than rejected
+        assert_eq!(parse_string(b"ok\xff"), Some("ok\u{fffd}".to_string()));
+    }
+
+    #[test]
+    fn system_ids() {
+        assert_eq!(
+            parse_system_id(&[0x01, 0x02, 0x03, 0x04, 0x05, 0xaa, 0xbb, 0xcc]),
+            Some(SystemId {
+                manufacturer_id: 0x05_0403_0201,
+                organizationally_unique_id: 0xccbbaa,
+            })
+        );
+        // Anything past the 8 bytes is ignored
+        assert!(parse_system_id(&[0xff; 9]).is_some());
+        assert_eq!(parse_system_id(&[0xff; 7]), None);
+        assert_eq!(parse_system_id(&[]), None);
+    }
+
+    #[test]
+    fn pnp_ids() {
+        assert_eq!(
+            parse_pnp_id(&[0x01, 0x59, 0x00, 0x34, 0x12, 0x00, 0x01]),
+            Some(PnpId {
+                vendor_id_source: 1,
+                vendor_id: 0x0059,
+                product_id: 0x1234,
+                product_version: 0x0100,
+            })
+        );
+        assert_eq!(parse_pnp_id(&[0x01, 0x59, 0x00, 0x34, 0x12, 0x00]), None);
+        assert_eq!(parse_pnp_id(&[]), None);
+    }
+
+    #[test]
+    fn battery_levels() {
+        assert_eq!(parse_battery_level(&[0]), Some(0));
+        assert_eq!(parse_battery_level(&[100]), Some(100));
+        assert_eq!(parse_battery_level(&[87, 0xff]), Some(87));
+        assert_eq!(parse_battery_level(&[101]), None);
+        assert_eq!(parse_battery_level(&[]), None);
+    }
+}
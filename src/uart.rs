@@ -0,0 +1,212 @@
+//! The Nordic UART Service, as a byte stream. Streams are opened with
+//! `EasyBluezHandle::uart`.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+
+use {BtMacAddress, Duration};
+use bt_manager::signals::SharedCache;
+use writes::{ATT_WRITE_OVERHEAD, DEFAULT_MTU};
+
+pub const NUS_SERVICE: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
+/// Written by us
+pub const NUS_RX: &str = "6e400002-b5a3-f393-e0a9-e50e24dcca9e";
+/// Notified by the device
+pub const NUS_TX: &str = "6e400003-b5a3-f393-e0a9-e50e24dcca9e";
+
+/// A connection to a device's Nordic UART Service. Reads block until the
+/// device sends something. Writes are buffered until they fill at least a
+/// packet, or until `flush`, and the write task splits what is sent into
+/// packets.
+pub struct UartStream {
+    pub(crate) tx: Sender<Box<[u8]>>,
+    pub(crate) rx: Receiver<Box<[u8]>>,
+    /// Received, but not yet read
+    pub(crate) pending: Vec<u8>,
+    /// Written, but not yet sent
+    pub(crate) unsent: Vec<u8>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) mac: BtMacAddress,
+    pub(crate) cache: SharedCache,
+}
+
+impl UartStream {
+    /// How long a read may block, `None` to wait forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Payload bytes that fit in one write at the negotiated MTU
+    fn packet_size(&self) -> usize {
+        let cache = self.cache.read().unwrap();
+        let mtu = cache.device_path(&self.mac).and_then(|p| cache.mtu(&p));
+        mtu.unwrap_or(DEFAULT_MTU).saturating_sub(ATT_WRITE_OVERHEAD).max(1) as usize
+    }
+
+    fn send(&self, data: Vec<u8>) -> io::Result<()> {
+        self.tx
+            .send(data.into_boxed_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "easy-bluez has shut down"))
+    }
+}
+
+impl Read for UartStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.pending.is_empty() {
+            let received = match self.read_timeout {
+                Some(t) => {
+                    let t = t.to_std()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad timeout"))?;
+                    self.rx.recv_timeout(t)
+                }
+                None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                Ok(data) => self.pending.extend_from_slice(&data),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+                }
+                // Nothing more will arrive
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let n = cmp::min(buf.len(), self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for UartStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.unsent.extend_from_slice(buf);
+
+        // Whole packets go out together, the rest waits for more writes
+        let size = self.packet_size();
+        let full = self.unsent.len() / size * size;
+        if full > 0 {
+            let packets = self.unsent.drain(..full).collect();
+            self.send(packets)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Send whatever is buffered, as a single message for the write task to
+    /// split into packets
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.unsent.is_empty() {
+            let data = ::std::mem::take(&mut self.unsent);
+            self.send(data)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UartStream {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+    use ByteStream;
+
+    /// A stream, and the device end of it
+    fn stream() -> (UartStream, ByteStream) {
+        let (tx, sent) = channel();
+        let (received, rx) = channel();
+
+        let stream = UartStream {
+            tx,
+            rx,
+            pending: vec![],
+            unsent: vec![],
+            read_timeout: None,
+            mac: BtMacAddress::from_str("CF:75:CE:86:6D:02").unwrap(),
+            cache: SharedCache::default(),
+        };
+        (stream, (received, sent))
+    }
+
+    #[test]
+    fn whole_packets_are_sent_together() {
+        let (mut stream, (_received, sent)) = stream();
+        // 20 bytes per packet at the default MTU
+        let data: Vec<u8> = (0..45).collect();
+
+        stream.write_all(&data[..5]).unwrap();
+        assert!(sent.try_recv().is_err());
+
+        stream.write_all(&data[5..]).unwrap();
+        assert_eq!(&*sent.try_recv().unwrap(), &data[..40]);
+        assert!(sent.try_recv().is_err());
+
+        stream.flush().unwrap();
+        assert_eq!(&*sent.try_recv().unwrap(), &data[40..]);
+
+        // Nothing left to flush
+        stream.flush().unwrap();
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[test]
+    fn flush_sends_one_message() {
+        let (mut stream, (_received, sent)) = stream();
+
+        for _ in 0..3 {
+            stream.write_all(b"abcdef").unwrap();
+        }
+        stream.flush().unwrap();
+        assert_eq!(&*sent.try_recv().unwrap(), &b"abcdefabcdefabcdef"[..]);
+
+        // However many packets it takes
+        stream.write_all(&[7; 1010]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(sent.try_iter().map(|m| m.len()).collect::<Vec<_>>(), [1000, 10]);
+    }
+
+    #[test]
+    fn dropping_sends_the_rest() {
+        let (mut stream, (_received, sent)) = stream();
+
+        stream.write_all(b"bye").unwrap();
+        drop(stream);
+        assert_eq!(&*sent.try_recv().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn empty_read_does_not_block() {
+        let (mut stream, _device) = stream();
+        assert_eq!(stream.read(&mut []).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_split_notifications() {
+        let (mut stream, (received, _sent)) = stream();
+        received.send(b"hello".to_vec().into_boxed_slice()).unwrap();
+
+        let mut buf = [0u8; 3];
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+
+        stream.set_read_timeout(Some(Duration::milliseconds(10)));
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        drop(received);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+//! Options for writeable endpoints, used with
+//! `EasyBluezHandle::writeable_with`.
+
+/// Smallest ATT MTU, used when the negotiated one is not known
+pub const DEFAULT_MTU: u16 = 23;
+
+/// ATT header bytes taken from every write
+pub(crate) const ATT_WRITE_OVERHEAD: u16 = 3;
+
+/// Set on the framing header of the last chunk of a payload
+pub const FINAL_CHUNK: u8 = 0x80;
+
+/// Which ATT procedure BlueZ uses for a write
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteKind {
+    /// Let BlueZ pick, based on the characteristic flags
+    #[default]
+    Default,
+    /// Write with response. BlueZ uses a prepared long write when the
+    /// payload does not fit in one packet
+    Request,
+    /// Write without response
+    Command,
+    /// Reliable write, where the peer echoes each prepared chunk back
+    Reliable,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteOptions {
+    pub(crate) kind: WriteKind,
+    pub(crate) chunked: bool,
+    pub(crate) sequence_header: bool,
+    pub(crate) streaming: bool,
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn kind(mut self, kind: WriteKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Split payloads larger than the negotiated MTU into separate writes
+    pub fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
+    /// Prefix every chunk with a one byte header: a 7 bit sequence number,
+    /// with `FINAL_CHUNK` set on the last chunk of each payload. Implies
+    /// `chunked`
+    pub fn sequence_header(mut self) -> Self {
+        self.chunked = true;
+        self.sequence_header = true;
+        self
+    }
+
+    /// Write through a socket from BlueZ's `AcquireWrite` rather than one
+    /// D-Bus call per write, for high rate write-without-response streams.
+    /// Falls back to `WriteValue` when the characteristic doesn't support it
+    pub fn streaming(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    /// Split a payload into the writes needed to send it
+    pub(crate) fn fragment(&self, payload: &[u8], mtu: u16) -> Vec<Vec<u8>> {
+        if !self.chunked {
+            return vec![payload.to_vec()];
+        }
+
+        let header = if self.sequence_header { 1 } else { 0 };
+        let size = mtu.saturating_sub(ATT_WRITE_OVERHEAD + header).max(1) as usize;
+
+        if payload.is_empty() {
+            return vec![if self.sequence_header { vec![FINAL_CHUNK] } else { vec![] }];
+        }
+
+        let count = payload.chunks(size).count();
+        payload
+            .chunks(size)
+            .enumerate()
+            .map(|(seq, chunk)| {
+                let mut out = Vec::with_capacity(chunk.len() + header as usize);
+                if self.sequence_header {
+                    let last = if seq + 1 == count { FINAL_CHUNK } else { 0 };
+                    out.push((seq as u8 & !FINAL_CHUNK) | last);
+                }
+                out.extend_from_slice(chunk);
+                out
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn unchunked_is_sent_whole() {
+        let data = payload(100);
+        assert_eq!(WriteOptions::new().fragment(&data, DEFAULT_MTU), vec![data]);
+    }
+
+    #[test]
+    fn empty_payload() {
+        let empty: Vec<u8> = vec![];
+        assert_eq!(WriteOptions::new().fragment(&empty, DEFAULT_MTU), vec![empty.clone()]);
+        assert_eq!(WriteOptions::new().chunked().fragment(&empty, DEFAULT_MTU), vec![empty.clone()]);
+        assert_eq!(
+            WriteOptions::new().sequence_header().fragment(&empty, DEFAULT_MTU),
+            vec![vec![FINAL_CHUNK]]
+        );
+    }
+
+    #[test]
+    fn payload_of_exactly_one_packet() {
+        let data = payload((DEFAULT_MTU - ATT_WRITE_OVERHEAD) as usize);
+        assert_eq!(WriteOptions::new().chunked().fragment(&data, DEFAULT_MTU), vec![data.clone()]);
+
+        // One byte more needs a second packet
+        let mut longer = data.clone();
+        longer.push(0xff);
+        assert_eq!(
+            WriteOptions::new().chunked().fragment(&longer, DEFAULT_MTU),
+            vec![data, vec![0xff]]
+        );
+    }
+
+    #[test]
+    fn sequence_headers() {
+        // 19 bytes of payload fit in each packet after the header
+        let data = payload(40);
+        let chunks = WriteOptions::new().sequence_header().fragment(&data, DEFAULT_MTU);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0][0], 0);
+        assert_eq!(chunks[1][0], 1);
+        assert_eq!(chunks[2][0], 2 | FINAL_CHUNK);
+        assert_eq!(&chunks[0][1..], &data[..19]);
+        assert_eq!(&chunks[2][1..], &data[38..]);
+    }
+
+    #[test]
+    fn reliable_writes_leave_splitting_to_bluez() {
+        // BlueZ prepares a long reliable write itself, unless asked to chunk
+        let data = payload(100);
+        let reliable = WriteOptions::new().kind(WriteKind::Reliable);
+        assert_eq!(reliable.fragment(&data, DEFAULT_MTU), vec![data.clone()]);
+
+        let chunks = reliable.chunked().fragment(&data, DEFAULT_MTU);
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn tiny_mtu_still_makes_progress() {
+        let data = payload(3);
+        let chunks = WriteOptions::new().sequence_header().fragment(&data, 3);
+        assert_eq!(chunks, vec![vec![0, 0], vec![1, 1], vec![2 | FINAL_CHUNK, 2]]);
+    }
+}